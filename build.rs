@@ -1,5 +1,5 @@
 use std::{
-    env,
+    env, fs,
     path::{Path, PathBuf},
     process::Command,
 };
@@ -34,8 +34,183 @@ fn sdk_include_path() -> Option<String> {
     }
 }
 
-fn compile_lwip() {
+/// An lwIP size left to the build: the macro, the type and doc of the exported constant.
+struct Tunable {
+    name: &'static str,
+    ty: &'static str,
+    doc: &'static str,
+}
+
+const TUNABLES: [Tunable; 7] = [
+    Tunable {
+        name: "MEM_SIZE",
+        ty: "usize",
+        doc: "Bytes of the lwIP heap, which holds the data queued on TCP connections.",
+    },
+    Tunable {
+        name: "MEMP_NUM_TCP_PCB",
+        ty: "usize",
+        doc: "Maximum number of TCP connections open at once.",
+    },
+    Tunable {
+        name: "MEMP_NUM_TCP_SEG",
+        ty: "usize",
+        doc: "Maximum number of TCP segments queued at once, over all connections.",
+    },
+    Tunable {
+        name: "PBUF_POOL_SIZE",
+        ty: "usize",
+        doc: "Number of buffers in the pool received packets are copied into.",
+    },
+    Tunable {
+        name: "TCP_MSS",
+        ty: "u16",
+        doc: "TCP maximum segment size, in bytes.",
+    },
+    Tunable {
+        name: "TCP_WND",
+        ty: "u32",
+        doc: "TCP receive window, in bytes.",
+    },
+    Tunable {
+        name: "TCP_SND_BUF",
+        ty: "u32",
+        doc: "TCP send buffer per connection, in bytes.",
+    },
+];
+
+/// The `profile-*` feature the values start from, `default` if none. The server one wins
+/// if both are enabled, e.g. with `--all-features`.
+fn lwip_profile() -> &'static str {
+    if env::var("CARGO_FEATURE_PROFILE_SERVER").is_ok() {
+        "server"
+    } else if env::var("CARGO_FEATURE_PROFILE_MOBILE").is_ok() {
+        "mobile"
+    } else {
+        "default"
+    }
+}
+
+/// The values of `TUNABLES`: the preset of the profile, the iOS one by default on iOS,
+/// overridden by the `LWIP_<name>` environment variables. The windows default to a
+/// multiple of the MSS, capped to what lwIP takes without window scaling.
+fn lwip_tuning() -> Vec<u32> {
+    let ios = env::var("CARGO_CFG_TARGET_OS").map_or(false, |os| os == "ios");
+    let (mem_size, tcp_pcb, tcp_seg, pbuf_pool, wnd_mss, snd_buf_mss) = match lwip_profile() {
+        "mobile" => (512 * 1024, 256, 1024, 128, 16, 8),
+        "server" => (16 * 1024 * 1024, 10240, 32768, 2048, 32, 16),
+        _ if ios => (512 * 1024, 256, 4096, 512, 32, 16),
+        _ => (2 * 1024 * 1024, 1024, 4096, 512, 32, 16),
+    };
+    let var = |name: &str, default: u32| {
+        let key = format!("LWIP_{}", name);
+        println!("cargo:rerun-if-env-changed={}", key);
+        match env::var(&key) {
+            Ok(value) => value
+                .trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("{}={:?} is not a number", key, value)),
+            Err(_) => default,
+        }
+    };
+    let mss = var("TCP_MSS", 1460);
+    let max = u16::MAX as u32;
+    // The checks of lwIP's init.c, which would otherwise fail the C build less readably.
+    if !(1..=max).contains(&mss) {
+        panic!("LWIP_TCP_MSS must be between 1 and 65535, got {}", mss);
+    }
+    // lwIP keeps TCP_SNDLOWAT, half the send buffer, 4 * TCP_MSS below the u16 overflow.
+    let snd_buf_max = (2 * max.saturating_sub(4 * mss + 1)).min(max);
+    let values = vec![
+        var("MEM_SIZE", mem_size),
+        var("MEMP_NUM_TCP_PCB", tcp_pcb),
+        var("MEMP_NUM_TCP_SEG", tcp_seg),
+        var("PBUF_POOL_SIZE", pbuf_pool),
+        mss,
+        var("TCP_WND", (wnd_mss * mss).min(max)),
+        var("TCP_SND_BUF", (snd_buf_mss * mss).min(snd_buf_max)),
+    ];
+    let (tcp_seg, wnd, snd_buf) = (values[2], values[5], values[6]);
+    if wnd > max || wnd < 2 * mss {
+        panic!(
+            "LWIP_TCP_WND must be between 2 * TCP_MSS = {} and 65535, got {}",
+            2 * mss,
+            wnd
+        );
+    }
+    if snd_buf > max || snd_buf < 2 * mss {
+        panic!(
+            "LWIP_TCP_SND_BUF must be between 2 * TCP_MSS = {} and 65535, got {}",
+            2 * mss,
+            snd_buf
+        );
+    }
+    // lwIP's default TCP_SNDLOWAT.
+    let snd_lowat = (snd_buf / 2).max(2 * mss + 1).min(snd_buf - 1);
+    if snd_lowat + 4 * mss >= max {
+        panic!(
+            "LWIP_TCP_SND_BUF must keep TCP_SNDLOWAT = {} 4 * TCP_MSS below 65535, got {}",
+            snd_lowat, snd_buf
+        );
+    }
+    // lwIP's default TCP_SND_QUEUELEN.
+    let snd_queuelen = (4 * snd_buf + mss - 1) / mss;
+    if tcp_seg < snd_queuelen {
+        panic!(
+            "LWIP_MEMP_NUM_TCP_SEG must be at least 4 * TCP_SND_BUF / TCP_MSS = {}, got {}",
+            snd_queuelen, tcp_seg
+        );
+    }
+    values
+}
+
+/// Exports the effective tuning as the constants of `lwip::tuning`.
+fn write_tuning(tuning: &[u32]) {
+    let mut out = format!(
+        "/// The `profile-*` feature the values start from, `default` if none.\n\
+         pub const PROFILE: &str = {:?};\n",
+        lwip_profile()
+    );
+    for (tunable, value) in TUNABLES.iter().zip(tuning) {
+        out += &format!(
+            "/// {} `{}` in lwIP.\npub const {}: {} = {};\n",
+            tunable.doc, tunable.name, tunable.name, tunable.ty, value
+        );
+    }
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_path.join("tuning.rs"), out).expect("Couldn't write tuning!");
+}
+
+/// Preprocessor definitions derived from the enabled cargo features, shared by the C build
+/// and bindgen so that both see the same lwIP configuration.
+fn lwip_defines(tuning: &[u32]) -> Vec<(&'static str, String)> {
+    let ipv4 = env::var("CARGO_FEATURE_IPV4").is_ok();
+    let ipv6 = env::var("CARGO_FEATURE_IPV6").is_ok();
+    if !ipv4 && !ipv6 {
+        panic!("at least one of the `ipv4` and `ipv6` features must be enabled");
+    }
+    let mut defines = Vec::new();
+    if !ipv4 {
+        defines.push(("LWIP_IPV4", "0".into()));
+    }
+    if !ipv6 {
+        defines.push(("LWIP_IPV6", "0".into()));
+    }
+    if env::var("CARGO_FEATURE_STATS").is_ok() {
+        defines.push(("LWIP_STATS", "1".into()));
+    }
+    if env::var("CARGO_FEATURE_LWIP_DEBUG").is_ok() {
+        defines.push(("LWIP_RUST_LOG", "1".into()));
+    }
+    for (tunable, value) in TUNABLES.iter().zip(tuning) {
+        defines.push((tunable.name, value.to_string()));
+    }
+    defines
+}
+
+fn compile_lwip(tuning: &[u32]) {
     println!("cargo:rerun-if-changed=old-src/core");
+    println!("cargo:rerun-if-changed=old-src/custom");
     let mut build = cc::Build::new();
     build
         .file("old-src/core/init.c")
@@ -48,7 +223,7 @@ fn compile_lwip() {
         .file("old-src/core/netif.c")
         .file("old-src/core/pbuf.c")
         .file("old-src/core/raw.c")
-        // .file("old-src/core/stats.c")
+        .file("old-src/core/stats.c")
         // .file("old-src/core/sys.c")
         .file("old-src/core/tcp.c")
         .file("old-src/core/tcp_in.c")
@@ -57,14 +232,14 @@ fn compile_lwip() {
         .file("old-src/core/udp.c")
         // .file("old-src/core/ipv4/autoip.c")
         // .file("old-src/core/ipv4/dhcp.c")
-        // .file("old-src/core/ipv4/etharp.c")
+        .file("old-src/core/ipv4/etharp.c")
         .file("old-src/core/ipv4/icmp.c")
         // .file("old-src/core/ipv4/igmp.c")
         .file("old-src/core/ipv4/ip4_frag.c")
         .file("old-src/core/ipv4/ip4.c")
         .file("old-src/core/ipv4/ip4_addr.c")
         // .file("old-src/core/ipv6/dhcp6.c")
-        // .file("old-src/core/ipv6/ethip6.c")
+        .file("old-src/core/ipv6/ethip6.c")
         .file("old-src/core/ipv6/icmp6.c")
         // .file("old-src/core/ipv6/inet6.c")
         .file("old-src/core/ipv6/ip6.c")
@@ -74,10 +249,14 @@ fn compile_lwip() {
         .file("old-src/core/ipv6/nd6.c")
         .file("old-src/custom/sys_arch.c")
         .file("src/api/err.c")
+        .file("src/netif/ethernet.c")
         .include("old-src/custom")
         .include("old-src/include")
         .warnings(false)
         .flag_if_supported("-Wno-everything");
+    for (name, value) in lwip_defines(tuning) {
+        build.define(name, value.as_str());
+    }
     if let Some(sdk_include_path) = sdk_include_path() {
         build.include(sdk_include_path);
     }
@@ -85,7 +264,7 @@ fn compile_lwip() {
     build.compile("liblwip.a");
 }
 
-fn generate_lwip_bindings() {
+fn generate_lwip_bindings(tuning: &[u32]) {
     println!("cargo:rustc-link-lib=lwip");
     // println!("cargo:rerun-if-changed=old-src/custom/wrapper.h");
     println!("cargo:include=old-src/include");
@@ -108,6 +287,9 @@ fn generate_lwip_bindings() {
     if let Some(sdk_include_path) = sdk_include_path {
         builder = builder.clang_arg(format!("-I{}", sdk_include_path));
     }
+    for (name, value) in lwip_defines(tuning) {
+        builder = builder.clang_arg(format!("-D{}={}", name, value));
+    }
 
     if os == "windows" {
         builder = builder.size_t_is_usize(false);
@@ -124,7 +306,9 @@ fn generate_lwip_bindings() {
 fn main() {
     let os = env::var("CARGO_CFG_TARGET_OS").unwrap();
     println!("cargo:warning=host os {}", os);
-    compile_lwip();
-    generate_lwip_bindings();
+    let tuning = lwip_tuning();
+    compile_lwip(&tuning);
+    generate_lwip_bindings(&tuning);
+    write_tuning(&tuning);
     println!("cargo:rerun-if-changed=build.rs");
 }
@@ -63,6 +63,10 @@
 /** Global data for both IPv4 and IPv6 */
 struct ip_globals ip_data;
 
+#if TUN2SOCKS
+u8_t ip_forward_enabled;
+#endif /* TUN2SOCKS */
+
 #if LWIP_IPV4 && LWIP_IPV6
 
 const ip_addr_t ip_addr_any_type = IPADDR_ANY_TYPE_INIT;
@@ -67,6 +67,10 @@
 
 static void icmp_send_response(struct pbuf *p, u8_t type, u8_t code);
 
+#if TUN2SOCKS
+u8_t icmp_reply_from_dest;
+#endif /* TUN2SOCKS */
+
 /**
  * Processes ICMP input packets, called from ip_input().
  *
@@ -396,6 +400,16 @@ icmp_send_response(struct pbuf *p, u8_t type, u8_t code)
     }
 #endif
     ICMP_STATS_INC(icmp.xmit);
+#if TUN2SOCKS
+    if (icmp_reply_from_dest) {
+      // go-tun2socks logic
+      // The netif owns none of the addresses behind the tun, answer unclaimed
+      // packets from the address they were sent to.
+      ip4_addr_t iphdr_dst;
+      ip4_addr_copy(iphdr_dst, iphdr->dest);
+      ip4_output_if(q, &iphdr_dst, &iphdr_src, ICMP_TTL, 0, IP_PROTO_ICMP, netif);
+    } else
+#endif /* TUN2SOCKS */
     ip4_output_if(q, NULL, &iphdr_src, ICMP_TTL, 0, IP_PROTO_ICMP, netif);
   }
   pbuf_free(q);
@@ -153,9 +153,18 @@ ip4_route(const ip4_addr_t *dest)
 {
 #if TUN2SOCKS
   // go-tun2socks logic
-  // no routing and just use the default netif, netif_list[0], i.e., the loopif
-  // enable loopif by setting LWIP_HAVE_LOOPIF = 1 in lwipopts.h
-  return netif_list;
+  // no routing table, the routes of the Rust side come first, then the netif the packet
+  // being processed came in on, so that replies go back to it, then the default netif
+  {
+    struct netif *netif = LWIP_HOOK_IP4_ROUTE_SRC(NULL, dest);
+    if (netif != NULL) {
+      return netif;
+    }
+    if (ip_current_input_netif() != NULL) {
+      return ip_current_input_netif();
+    }
+  }
+  return netif_default;
 #endif /* TUN2SOCKS */
 
 #if !LWIP_SINGLE_NETIF
@@ -293,6 +302,15 @@ ip4_forward(struct pbuf *p, struct ip_hdr *iphdr, struct netif *inp)
   LWIP_UNUSED_ARG(inp);
 
   if (!ip4_canforward(p)) {
+#if TUN2SOCKS && LWIP_ICMP
+    // go-tun2socks logic
+    // tell the sender, unless the packet is ICMP, broadcast or multicast
+    if (IPH_PROTO(iphdr) != IP_PROTO_ICMP && !(p->flags & (PBUF_FLAG_LLBCAST | PBUF_FLAG_LLMCAST)) &&
+        !ip4_addr_ismulticast(ip4_current_dest_addr())) {
+      /* ICMP Destination Unreachable code 13: "Communication administratively prohibited" */
+      icmp_dest_unreach(p, (enum icmp_dur_type)13);
+    }
+#endif /* TUN2SOCKS && LWIP_ICMP */
     goto return_noroute;
   }
 
@@ -310,7 +328,12 @@ ip4_forward(struct pbuf *p, struct ip_hdr *iphdr, struct netif *inp)
     LWIP_DEBUGF(IP_DEBUG, ("ip4_forward: no forwarding route for %"U16_F".%"U16_F".%"U16_F".%"U16_F" found\n",
                            ip4_addr1_16(ip4_current_dest_addr()), ip4_addr2_16(ip4_current_dest_addr()),
                            ip4_addr3_16(ip4_current_dest_addr()), ip4_addr4_16(ip4_current_dest_addr())));
-    /* @todo: send ICMP_DUR_NET? */
+#if TUN2SOCKS && LWIP_ICMP
+    // go-tun2socks logic
+    if (IPH_PROTO(iphdr) != IP_PROTO_ICMP) {
+      icmp_dest_unreach(p, ICMP_DUR_NET);
+    }
+#endif /* TUN2SOCKS && LWIP_ICMP */
     goto return_noroute;
   }
 #if !IP_FORWARD_ALLOW_TX_ON_RX_NETIF
@@ -343,6 +366,15 @@ ip4_forward(struct pbuf *p, struct ip_hdr *iphdr, struct netif *inp)
     IPH_CHKSUM_SET(iphdr, (u16_t)(IPH_CHKSUM(iphdr) + PP_HTONS(0x100)));
   }
 
+#if TUN2SOCKS && defined(LWIP_HOOK_IP4_FORWARD_OUTPUT)
+  // go-tun2socks logic
+  // e.g. source NAT, the header is rewritten in place
+  if (!LWIP_HOOK_IP4_FORWARD_OUTPUT(p, inp, netif)) {
+    IP_STATS_INC(ip.drop);
+    return;
+  }
+#endif /* TUN2SOCKS && LWIP_HOOK_IP4_FORWARD_OUTPUT */
+
   LWIP_DEBUGF(IP_DEBUG, ("ip4_forward: forwarding packet to %"U16_F".%"U16_F".%"U16_F".%"U16_F"\n",
                          ip4_addr1_16(ip4_current_dest_addr()), ip4_addr2_16(ip4_current_dest_addr()),
                          ip4_addr3_16(ip4_current_dest_addr()), ip4_addr4_16(ip4_current_dest_addr())));
@@ -376,13 +408,36 @@ return_noroute:
 }
 #endif /* IP_FORWARD */
 
+#if TUN2SOCKS && IP_FORWARD
+/** Return true if the stack forwards the current input packet, i.e. it's routed to
+ * another netif than the one it came in on, and isn't sent to the address of that netif */
+int
+ip4_input_forwarded(void)
+{
+  struct netif *netif;
+
+  if (!ip_forward_enabled) {
+    return 0;
+  }
+  netif = LWIP_HOOK_IP4_ROUTE_SRC(NULL, ip4_current_dest_addr());
+  return netif != NULL && netif != ip_current_input_netif() &&
+         !ip4_addr_cmp(ip4_current_dest_addr(), netif_ip4_addr(netif));
+}
+#endif /* TUN2SOCKS && IP_FORWARD */
+
 /** Return true if the current input packet should be accepted on this netif */
 static int
 ip4_input_accept(struct netif *netif)
 {
 #if TUN2SOCKS
       // go-tun2socks logic
-      // all packets are accepted by the first one, and it should be the loopif
+      // all packets are accepted by the input netif, i.e., the netif added by the Rust side,
+      // unless they are forwarded
+#if IP_FORWARD
+      if (ip4_input_forwarded()) {
+        return 0;
+      }
+#endif /* IP_FORWARD */
       return 1;
 #endif /* TUN2SOCKS */
 
@@ -528,6 +583,12 @@ ip4_input(struct pbuf *p, struct netif *inp)
   ip_addr_copy_from_ip4(ip_data.current_iphdr_dest, iphdr->dest);
   ip_addr_copy_from_ip4(ip_data.current_iphdr_src, iphdr->src);
 
+#if TUN2SOCKS
+  // go-tun2socks logic
+  // whether the packet is forwarded depends on the netif it came in on
+  ip_data.current_input_netif = inp;
+#endif /* TUN2SOCKS */
+
   /* match packet against an interface, i.e. is this packet for us? */
   if (ip4_addr_ismulticast(ip4_current_dest_addr())) {
 #if LWIP_IGMP
@@ -579,6 +640,10 @@ ip4_input(struct pbuf *p, struct netif *inp)
     }
   }
 
+#if TUN2SOCKS
+  ip_data.current_input_netif = NULL;
+#endif /* TUN2SOCKS */
+
 #if IP_ACCEPT_LINK_LAYER_ADDRESSING
   /* Pass DHCP messages regardless of destination address. DHCP traffic is addressed
    * using link layer addressing (such as Ethernet MAC) so we must not filter on IP.
@@ -635,7 +700,15 @@ ip4_input(struct pbuf *p, struct netif *inp)
     /* non-broadcast packet? */
     if (!ip4_addr_isbroadcast(ip4_current_dest_addr(), inp)) {
       /* try to forward IP packet on (other) interfaces */
+#if TUN2SOCKS
+      // go-tun2socks logic
+      // ICMP errors go back to the netif the packet came in on, see ip4_route
+      ip_data.current_input_netif = inp;
+      ip4_forward(p, (struct ip_hdr *)p->payload, inp);
+      ip_data.current_input_netif = NULL;
+#else
       ip4_forward(p, (struct ip_hdr *)p->payload, inp);
+#endif /* TUN2SOCKS */
     } else
 #endif /* IP_FORWARD */
     {
@@ -54,6 +54,9 @@
 #include "lwip/mld6.h"
 #include "lwip/ip.h"
 #include "lwip/stats.h"
+#if TUN2SOCKS
+#include "lwip/icmp.h"
+#endif /* TUN2SOCKS */
 
 #include <string.h>
 
@@ -69,6 +72,9 @@ static void icmp6_send_response_with_addrs(struct pbuf *p, u8_t code, u32_t data
 static void icmp6_send_response_with_addrs_and_netif(struct pbuf *p, u8_t code, u32_t data,
     u8_t type, const ip6_addr_t *src_addr, const ip6_addr_t *dest_addr, struct netif *netif);
 
+#if TUN2SOCKS
+u8_t icmp6_reply_from_dest;
+#endif /* TUN2SOCKS */
 
 /**
  * Process an input ICMPv6 message. Called by ip6_input.
@@ -314,8 +320,18 @@ icmp6_send_response(struct pbuf *p, u8_t code, u32_t data, u8_t type)
   LWIP_ASSERT("icmpv6 packet not a direct response", netif != NULL);
   reply_dest = ip6_current_src_addr();
 
-  /* Select an address to use as source. */
-  reply_src = ip_2_ip6(ip6_select_source_address(netif, reply_dest));
+#if TUN2SOCKS
+  if (icmp6_reply_from_dest) {
+    // go-tun2socks logic
+    // The netif owns none of the addresses behind the tun, answer unclaimed
+    // packets from the address they were sent to.
+    reply_src = ip6_current_dest_addr();
+  } else
+#endif /* TUN2SOCKS */
+  {
+    /* Select an address to use as source. */
+    reply_src = ip_2_ip6(ip6_select_source_address(netif, reply_dest));
+  }
   if (reply_src == NULL) {
     ICMP6_STATS_INC(icmp6.rterr);
     return;
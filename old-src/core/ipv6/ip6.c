@@ -87,9 +87,18 @@ ip6_route(const ip6_addr_t *src, const ip6_addr_t *dest)
 {
 #if TUN2SOCKS
   // go-tun2socks logic
-  // no routing and just use the default netif, netif_list[0], i.e., the loopif
-  // enable loopif by setting LWIP_HAVE_LOOPIF = 1 in lwipopts.h
-  return netif_list;
+  // no routing table, the routes of the Rust side come first, then the netif the packet
+  // being processed came in on, so that replies go back to it, then the default netif
+  {
+    struct netif *netif = LWIP_HOOK_IP6_ROUTE(src, dest);
+    if (netif != NULL) {
+      return netif;
+    }
+    if (ip_current_input_netif() != NULL) {
+      return ip_current_input_netif();
+    }
+  }
+  return netif_default;
 #endif /* TUN2SOCKS */
 
 #if LWIP_SINGLE_NETIF
@@ -374,6 +383,20 @@ ip6_forward(struct pbuf *p, struct ip6_hdr *iphdr, struct netif *inp)
 {
   struct netif *netif;
 
+#if TUN2SOCKS && defined(LWIP_HOOK_IP6_CANFORWARD)
+  // go-tun2socks logic
+  if (LWIP_HOOK_IP6_CANFORWARD(p) == 0) {
+#if LWIP_ICMP6
+    /* Don't send ICMP messages in response to ICMP messages */
+    if (IP6H_NEXTH(iphdr) != IP6_NEXTH_ICMP6) {
+      icmp6_dest_unreach(p, ICMP6_DUR_PROHIBITED);
+    }
+#endif /* LWIP_ICMP6 */
+    IP6_STATS_INC(ip6.drop);
+    return;
+  }
+#endif /* TUN2SOCKS && LWIP_HOOK_IP6_CANFORWARD */
+
   /* do not forward link-local or loopback addresses */
   if (ip6_addr_islinklocal(ip6_current_dest_addr()) ||
       ip6_addr_isloopback(ip6_current_dest_addr())) {
@@ -471,13 +494,36 @@ ip6_forward(struct pbuf *p, struct ip6_hdr *iphdr, struct netif *inp)
 }
 #endif /* LWIP_IPV6_FORWARD */
 
+#if TUN2SOCKS && LWIP_IPV6_FORWARD
+/** Return true if the stack forwards the current input packet, i.e. it's routed to
+ * another netif than the one it came in on, and isn't sent to an address of that netif */
+int
+ip6_input_forwarded(void)
+{
+  struct netif *netif;
+
+  if (!ip_forward_enabled) {
+    return 0;
+  }
+  netif = LWIP_HOOK_IP6_ROUTE(ip6_current_src_addr(), ip6_current_dest_addr());
+  return netif != NULL && netif != ip_current_input_netif() &&
+         netif_get_ip6_addr_match(netif, ip6_current_dest_addr()) < 0;
+}
+#endif /* TUN2SOCKS && LWIP_IPV6_FORWARD */
+
 /** Return true if the current input packet should be accepted on this netif */
 static int
 ip6_input_accept(struct netif *netif)
 {
 #if TUN2SOCKS
       // go-tun2socks logic
-      // all packets are accepted by the first one, and it should be the loopif
+      // all packets are accepted by the input netif, i.e., the netif added by the Rust side,
+      // unless they are forwarded
+#if LWIP_IPV6_FORWARD
+      if (ip6_input_forwarded()) {
+        return 0;
+      }
+#endif /* LWIP_IPV6_FORWARD */
       return 1;
 #endif /* TUN2SOCKS */
 
@@ -58,6 +58,9 @@
 #if LWIP_ND6_TCP_REACHABILITY_HINTS
 #include "lwip/nd6.h"
 #endif /* LWIP_ND6_TCP_REACHABILITY_HINTS */
+#if TUN2SOCKS
+#include "lwip/icmp6.h"
+#endif /* TUN2SOCKS */
 
 #include <string.h>
 
@@ -87,6 +90,10 @@ static struct pbuf *recv_data;
 
 struct tcp_pcb *tcp_input_pcb;
 
+#if TUN2SOCKS
+struct unclaimed_reply tcp_unclaimed_reply = { UNCLAIMED_REPLY_RST, ICMP_DUR_PORT, ICMP6_DUR_PORT };
+#endif /* TUN2SOCKS */
+
 /* Forward declarations. */
 static err_t tcp_process(struct tcp_pcb *pcb);
 static void tcp_receive(struct tcp_pcb *pcb);
@@ -96,6 +103,9 @@ static void tcp_listen_input(struct tcp_pcb_listen *pcb);
 static void tcp_timewait_input(struct tcp_pcb *pcb);
 
 static int tcp_input_delayed_close(struct tcp_pcb *pcb);
+#if TUN2SOCKS
+static void tcp_input_unclaimed(struct pbuf *p);
+#endif /* TUN2SOCKS */
 
 #if LWIP_TCP_SACK_OUT
 static void tcp_add_sack(struct tcp_pcb *pcb, u32_t left, u32_t right);
@@ -578,6 +588,9 @@ aborted:
   } else {
     /* If no matching PCB was found, send a TCP RST (reset) to the
        sender. */
+#if TUN2SOCKS
+    tcp_input_unclaimed(p);
+#else
     LWIP_DEBUGF(TCP_RST_DEBUG, ("tcp_input: no PCB match found, resetting.\n"));
     if (!(TCPH_FLAGS(tcphdr) & TCP_RST)) {
       TCP_STATS_INC(tcp.proterr);
@@ -585,6 +598,7 @@ aborted:
       tcp_rst(NULL, ackno, seqno + tcplen, ip_current_dest_addr(),
               ip_current_src_addr(), tcphdr->dest, tcphdr->src);
     }
+#endif /* TUN2SOCKS */
     pbuf_free(p);
   }
 
@@ -597,6 +611,45 @@ dropped:
   pbuf_free(p);
 }
 
+#if TUN2SOCKS
+/**
+ * Called from tcp_input when no pcb matches the segment, answers it according
+ * to tcp_unclaimed_reply. The caller still owns p.
+ */
+static void
+tcp_input_unclaimed(struct pbuf *p)
+{
+  if (TCPH_FLAGS(tcphdr) & TCP_RST) {
+    /* never answer a reset */
+    return;
+  }
+  TCP_STATS_INC(tcp.proterr);
+  TCP_STATS_INC(tcp.drop);
+  switch (tcp_unclaimed_reply.action) {
+    case UNCLAIMED_REPLY_RST:
+      LWIP_DEBUGF(TCP_RST_DEBUG, ("tcp_input: no PCB match found, resetting.\n"));
+      tcp_rst(NULL, ackno, seqno + tcplen, ip_current_dest_addr(),
+              ip_current_src_addr(), tcphdr->dest, tcphdr->src);
+      break;
+    case UNCLAIMED_REPLY_ICMP:
+      LWIP_DEBUGF(TCP_RST_DEBUG, ("tcp_input: no PCB match found, sending ICMP unreachable.\n"));
+      /* the ICMP message quotes the ports and sequence number, restore them
+         to network byte order */
+      tcphdr->src = lwip_htons(tcphdr->src);
+      tcphdr->dest = lwip_htons(tcphdr->dest);
+      tcphdr->seqno = lwip_htonl(tcphdr->seqno);
+      /* move payload pointer back to ip header */
+      if (pbuf_header_force(p, (s16_t)(ip_current_header_tot_len() + TCP_HLEN + tcphdr_opt1len)) == 0) {
+        icmp_unclaimed_unreach(ip_current_is_v6(), p, &tcp_unclaimed_reply);
+      }
+      break;
+    default:
+      LWIP_DEBUGF(TCP_RST_DEBUG, ("tcp_input: no PCB match found, dropping.\n"));
+      break;
+  }
+}
+#endif /* TUN2SOCKS */
+
 /** Called from tcp_input to check for TF_CLOSED flag. This results in closing
  * and deallocating a pcb at the correct place to ensure noone references it
  * any more.
@@ -709,6 +762,11 @@ tcp_listen_input(struct tcp_pcb_listen *pcb)
     /* inherit socket options */
     npcb->so_options = pcb->so_options & SOF_INHERITED;
     npcb->netif_idx = pcb->netif_idx;
+#if TUN2SOCKS
+    // go-tun2socks logic
+    // pin the connection to the netif it came in on, so that it's answered on it
+    npcb->netif_idx = netif_get_index(ip_current_input_netif());
+#endif /* TUN2SOCKS */
     /* Register the new PCB so that we can begin receiving segments
        for it. */
     TCP_REG_ACTIVE(npcb);
@@ -80,6 +80,10 @@ static u16_t udp_port = UDP_LOCAL_PORT_RANGE_START;
 /* exported in udp.h (was static) */
 struct udp_pcb *udp_pcbs;
 
+#if TUN2SOCKS
+struct unclaimed_reply udp_unclaimed_reply = { UNCLAIMED_REPLY_DROP, ICMP_DUR_PORT, ICMP6_DUR_PORT };
+#endif /* TUN2SOCKS */
+
 /**
  * Initialize this module.
  */
@@ -196,6 +200,9 @@ udp_input(struct pbuf *p, struct netif *inp)
   struct udp_hdr *udphdr;
   struct udp_pcb *pcb, *prev;
   struct udp_pcb *uncon_pcb;
+#if TUN2SOCKS
+  struct udp_pcb *any_pcb;
+#endif /* TUN2SOCKS */
   u16_t src, dest;
   u8_t broadcast;
   u8_t for_us = 0;
@@ -246,6 +253,9 @@ udp_input(struct pbuf *p, struct netif *inp)
   pcb = NULL;
   prev = NULL;
   uncon_pcb = NULL;
+#if TUN2SOCKS
+  any_pcb = NULL;
+#endif /* TUN2SOCKS */
   /* Iterate through the UDP pcb list for a matching pcb.
    * 'Perfect match' pcbs (connected to the remote port & ip address) are
    * preferred. If no perfect match is found, the first unconnected pcb that
@@ -254,8 +264,15 @@ udp_input(struct pbuf *p, struct netif *inp)
 
 #if TUN2SOCKS
 	// go-tun2socks logic
-	// take the first one, library users are responsible for creating that pcb
-	break;
+	// the first catch-all pcb gets everything no bound pcb matches, library
+	// users are responsible for creating that pcb
+	if (pcb->flags & UDP_FLAGS_TUN2SOCKS_ANY) {
+	  if (any_pcb == NULL) {
+	    any_pcb = pcb;
+	  }
+	  prev = pcb;
+	  continue;
+	}
 #endif /* TUN2SOCKS */
 
     /* print the PCB local and remote address */
@@ -316,11 +333,22 @@ udp_input(struct pbuf *p, struct netif *inp)
   if (pcb == NULL) {
     pcb = uncon_pcb;
   }
+#if TUN2SOCKS
+  if (pcb == NULL) {
+    pcb = any_pcb;
+  }
+#endif /* TUN2SOCKS */
 
   /* Check checksum if this is a match or if it was directed at us. */
   if (pcb != NULL) {
     for_us = 1;
   } else {
+#if TUN2SOCKS
+    // go-tun2socks logic
+    // Every datagram coming from the tun is addressed to us, whatever its
+    // destination, so unclaimed ones are answered by udp_unclaimed_reply.
+    for_us = 1;
+#else
 #if LWIP_IPV6
     if (ip_current_is_v6()) {
       for_us = netif_get_ip6_addr_match(inp, ip6_current_dest_addr()) >= 0;
@@ -331,6 +359,7 @@ udp_input(struct pbuf *p, struct netif *inp)
       for_us = ip4_addr_cmp(netif_ip4_addr(inp), ip4_current_dest_addr());
     }
 #endif /* LWIP_IPV4 */
+#endif /* TUN2SOCKS */
   }
 
   if (for_us) {
@@ -427,7 +456,14 @@ udp_input(struct pbuf *p, struct netif *inp)
     } else {
       LWIP_DEBUGF(UDP_DEBUG | LWIP_DBG_TRACE, ("udp_input: not for us.\n"));
 
-#if LWIP_ICMP || LWIP_ICMP6
+#if TUN2SOCKS
+      if (udp_unclaimed_reply.action == UNCLAIMED_REPLY_ICMP &&
+          !broadcast && !ip_addr_ismulticast(ip_current_dest_addr())) {
+        /* move payload pointer back to ip header */
+        pbuf_header_force(p, (s16_t)(ip_current_header_tot_len() + UDP_HLEN));
+        icmp_unclaimed_unreach(ip_current_is_v6(), p, &udp_unclaimed_reply);
+      }
+#elif LWIP_ICMP || LWIP_ICMP6
       /* No match was found, send ICMP destination port unreachable unless
          destination address was broadcast/multicast. */
       if (!broadcast && !ip_addr_ismulticast(ip_current_dest_addr())) {
@@ -435,7 +471,7 @@ udp_input(struct pbuf *p, struct netif *inp)
         pbuf_header_force(p, (s16_t)(ip_current_header_tot_len() + UDP_HLEN));
         icmp_port_unreach(ip_current_is_v6(), p);
       }
-#endif /* LWIP_ICMP || LWIP_ICMP6 */
+#endif /* TUN2SOCKS */
       UDP_STATS_INC(udp.proterr);
       UDP_STATS_INC(udp.drop);
       MIB2_STATS_INC(mib2.udpnoports);
@@ -1,3 +1,40 @@
+#if LWIP_RUST_LOG
+/* Debug output is formatted in sys_arch.c and handed to the Rust side, which
+ * installs these callbacks before lwip_init(). */
+typedef int (*lwip_log_enabled_fn_t)(const char *file, unsigned char flags);
+typedef void (*lwip_log_fn_t)(const char *file, unsigned char flags, const char *msg);
+extern lwip_log_enabled_fn_t lwip_log_enabled_fn;
+extern lwip_log_fn_t lwip_log_fn;
+void lwip_platform_debugf(const char *file, unsigned char flags, const char *format, ...);
+
+#define LWIP_PLATFORM_UNPAREN(...) __VA_ARGS__
+#define LWIP_PLATFORM_DEBUGF(debug, message) \
+  lwip_platform_debugf(__FILE__, (unsigned char)(debug), LWIP_PLATFORM_UNPAREN message)
+#define LWIP_PLATFORM_DIAG(message) LWIP_PLATFORM_DEBUGF(0x80U, message)
+#endif /* LWIP_RUST_LOG */
+
+/* Routes of the Rust side, which installs these callbacks before lwip_init(), see
+ * LWIP_HOOK_IP4_ROUTE_SRC and LWIP_HOOK_IP6_ROUTE in lwipopts.h. */
+struct netif;
+struct ip4_addr;
+struct ip6_addr;
+typedef struct netif *(*lwip_ip4_route_fn_t)(const struct ip4_addr *src, const struct ip4_addr *dest);
+typedef struct netif *(*lwip_ip6_route_fn_t)(const struct ip6_addr *src, const struct ip6_addr *dest);
+extern lwip_ip4_route_fn_t lwip_ip4_route_fn;
+extern lwip_ip6_route_fn_t lwip_ip6_route_fn;
+
+/* Forwarding predicate of the Rust side, see LWIP_HOOK_IP4_CANFORWARD and
+ * LWIP_HOOK_IP6_CANFORWARD in lwipopts.h. p starts at the IP header, returns 1 to
+ * forward, 0 to discard and -1 to leave it to lwIP. */
+struct pbuf;
+typedef int (*lwip_canforward_fn_t)(struct pbuf *p);
+extern lwip_canforward_fn_t lwip_canforward_fn;
+
+/* Source NAT of the Rust side, see LWIP_HOOK_IP4_FORWARD_OUTPUT in lwipopts.h. p starts
+ * at the IP header and may be rewritten in place, returns 0 to drop it. */
+typedef int (*lwip_ip4_forward_output_fn_t)(struct pbuf *p, struct netif *inp, struct netif *netif);
+extern lwip_ip4_forward_output_fn_t lwip_ip4_forward_output_fn;
+
 #ifdef _WIN32
   // both win32 and win64 are defined here
   #include "cc_windows.h"
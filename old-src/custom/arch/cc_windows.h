@@ -89,7 +89,7 @@ typedef int sys_prot_t;
  * C standard lwIP targets does not support this in macros, we have extra brackets
  * around the arguments, which are left out in the following macro definition:
  */
-#if !defined(LWIP_TESTMODE) || !LWIP_TESTMODE
+#if (!defined(LWIP_TESTMODE) || !LWIP_TESTMODE) && !defined(LWIP_PLATFORM_DIAG)
 void lwip_win32_platform_diag(const char *format, ...);
 #define LWIP_PLATFORM_DIAG(x) lwip_win32_platform_diag x
 #endif
@@ -37,9 +37,26 @@
 #define LWIP_TIMERS 1
 
 #define IP_DEFAULT_TTL 64
-#define LWIP_ARP 0
+// ARP and Ethernet for the Ethernet link mode, unused by the IP netif
+#define LWIP_ARP 1
+#define LWIP_ETHERNET 1
 #define ARP_QUEUEING 0
-#define IP_FORWARD 0
+// router mode, enabled at runtime by ip_forward_enabled
+#define IP_FORWARD 1
+#define LWIP_IPV6_FORWARD 1
+// routes of the Rust side, consulted before falling back to the default netif
+#define LWIP_HOOK_IP4_ROUTE_SRC(src, dest) \
+  (lwip_ip4_route_fn != NULL ? lwip_ip4_route_fn(src, dest) : NULL)
+#define LWIP_HOOK_IP6_ROUTE(src, dest) \
+  (lwip_ip6_route_fn != NULL ? lwip_ip6_route_fn(src, dest) : NULL)
+// forwarding predicate of the Rust side, LWIP_HOOK_IP6_CANFORWARD is a tun2socks addition
+#define LWIP_HOOK_IP4_CANFORWARD(p, dest) \
+  (lwip_canforward_fn != NULL ? lwip_canforward_fn(p) : -1)
+#define LWIP_HOOK_IP6_CANFORWARD(p) \
+  (lwip_canforward_fn != NULL ? lwip_canforward_fn(p) : -1)
+// rewrites the forwarded packets before they're sent, a tun2socks addition
+#define LWIP_HOOK_IP4_FORWARD_OUTPUT(p, inp, netif) \
+  (lwip_ip4_forward_output_fn != NULL ? lwip_ip4_forward_output_fn(p, inp, netif) : 1)
 #define LWIP_ICMP 1
 #define LWIP_RAW 1
 #define LWIP_DHCP 0
@@ -58,18 +75,21 @@
 #define LWIP_NETCONN 0
 #define LWIP_SOCKET 0
 #define PPP_SUPPORT 0
+// Both families unless the build leaves one out, see the `ipv4` and `ipv6` features.
+#ifndef LWIP_IPV4
+#define LWIP_IPV4 1
+#endif
+#ifndef LWIP_IPV6
 #define LWIP_IPV6 1
+#endif
 #define LWIP_IPV6_MLD 0
-#define LWIP_IPV6_AUTOCONFIG 1
+#define LWIP_IPV6_AUTOCONFIG LWIP_IPV6
 
 #if defined __APPLE__
 #include <TargetConditionals.h>
 
 #if TARGET_OS_IPHONE
 #define LWIP_TCP_KEEPALIVE 1
-#define MEMP_NUM_TCP_PCB 256
-#else
-#define MEMP_NUM_TCP_PCB 1024
 #endif
 #elif defined __linux__
 #include <endian.h>
@@ -81,10 +101,6 @@
 #define BYTE_ORDER BIG_ENDIAN
 #endif
 #endif
-
-#define MEMP_NUM_TCP_PCB 1024
-#else
-#define MEMP_NUM_TCP_PCB 1024
 #endif
 
 // disable checksum checks
@@ -97,23 +113,41 @@
 #define LWIP_CHECKSUM_ON_COPY 1
 #define LWIP_CHKSUM_ALGORITHM 3
 
+// Sizes build.rs defines from the `profile-*` features and the `LWIP_*` environment
+// variables, the defaults below only apply when the header is used without it.
+#ifndef TCP_MSS
 #define TCP_MSS 1460
+#endif
+#ifndef TCP_WND
 #define TCP_WND (32 * TCP_MSS)
+#endif
+#ifndef TCP_SND_BUF
 #define TCP_SND_BUF (16 * TCP_MSS)
+#endif
 
-#if defined __APPLE__
-#include <TargetConditionals.h>
-#if TARGET_OS_IPHONE
+#if defined __APPLE__ && TARGET_OS_IPHONE
+#ifndef MEM_SIZE
 #define MEM_SIZE (512 * 1024)
-#else
-#define MEM_SIZE (2 * 1024 * 1024)
 #endif
-#else
+#ifndef MEMP_NUM_TCP_PCB
+#define MEMP_NUM_TCP_PCB 256
+#endif
+#endif
+#ifndef MEM_SIZE
 #define MEM_SIZE (2 * 1024 * 1024)
 #endif
+#ifndef MEMP_NUM_TCP_PCB
+#define MEMP_NUM_TCP_PCB 1024
+#endif
 
+#ifndef MEMP_NUM_TCP_SEG
 #define MEMP_NUM_TCP_SEG 4096
+#endif
+#ifndef PBUF_POOL_SIZE
 #define PBUF_POOL_SIZE 512
+#endif
+// the catch-all socket and the ones of UdpSocket::bind
+#define MEMP_NUM_UDP_PCB 128
 
 // #define TCP_MSS 1460
 // #define TCP_WND (16 * TCP_MSS)
@@ -128,8 +162,42 @@
 // is used regardless of the platform
 #define IPV6_FRAG_COPYHEADER 1
 
+// LWIP_RUST_LOG is set by build.rs when the `lwip-debug` feature is enabled,
+// messages then go to the `log` crate and are filtered there
+#ifndef LWIP_RUST_LOG
+#define LWIP_RUST_LOG 0
+#endif
+
 #define LWIP_DEBUG 0
+// LWIP_DEBUG is defined, if only to 0, so timeouts.h would tie the timer names to
+// SYS_DEBUG and `lwip-debug` would change the timer structs and turn sys_timeout()
+// into a macro, keep them the same whatever the features
+#define LWIP_DEBUG_TIMERNAMES 0
 #define LWIP_DBG_MIN_LEVEL LWIP_DBG_LEVEL_ALL
+#if LWIP_RUST_LOG
+#define LWIP_DBG_TYPES_ON LWIP_DBG_ON
+#define NETIF_DEBUG LWIP_DBG_ON
+#define PBUF_DEBUG LWIP_DBG_ON
+#define INET_DEBUG LWIP_DBG_ON
+#define IP_DEBUG LWIP_DBG_ON
+#define IP_REASS_DEBUG LWIP_DBG_ON
+#define RAW_DEBUG LWIP_DBG_ON
+#define MEM_DEBUG LWIP_DBG_ON
+#define MEMP_DEBUG LWIP_DBG_ON
+#define SYS_DEBUG LWIP_DBG_ON
+#define TIMERS_DEBUG LWIP_DBG_ON
+#define TCP_DEBUG LWIP_DBG_ON
+#define TCP_INPUT_DEBUG LWIP_DBG_ON
+#define TCP_RTO_DEBUG LWIP_DBG_ON
+#define TCP_CWND_DEBUG LWIP_DBG_ON
+#define TCP_WND_DEBUG LWIP_DBG_ON
+#define TCP_RST_DEBUG LWIP_DBG_ON
+#define TCP_QLEN_DEBUG LWIP_DBG_ON
+#define TCP_OUTPUT_DEBUG LWIP_DBG_ON
+#define UDP_DEBUG LWIP_DBG_ON
+#define TCPIP_DEBUG LWIP_DBG_ON
+#define IP6_DEBUG LWIP_DBG_ON
+#else
 #define LWIP_DBG_TYPES_ON LWIP_DBG_OFF
 #define NETIF_DEBUG LWIP_DBG_OFF
 #define PBUF_DEBUG LWIP_DBG_OFF
@@ -152,8 +220,13 @@
 #define UDP_DEBUG LWIP_DBG_OFF
 #define TCPIP_DEBUG LWIP_DBG_OFF
 #define IP6_DEBUG LWIP_DBG_OFF
+#endif
 
+// LWIP_STATS is set by build.rs when the `stats` feature is enabled
+#ifndef LWIP_STATS
 #define LWIP_STATS 0
+#endif
+#define LWIP_STATS_LARGE 1
 #define LWIP_STATS_DISPLAY 0
 #define LWIP_PERF 0
 
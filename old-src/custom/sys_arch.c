@@ -209,3 +209,29 @@
 #elif __posix
     // POSIX
 #endif
+
+lwip_ip4_route_fn_t lwip_ip4_route_fn;
+lwip_ip6_route_fn_t lwip_ip6_route_fn;
+lwip_canforward_fn_t lwip_canforward_fn;
+lwip_ip4_forward_output_fn_t lwip_ip4_forward_output_fn;
+
+#if LWIP_RUST_LOG
+    #include <stdarg.h>
+    #include <stdio.h>
+
+    lwip_log_enabled_fn_t lwip_log_enabled_fn;
+    lwip_log_fn_t lwip_log_fn;
+
+    void lwip_platform_debugf(const char *file, unsigned char flags, const char *format, ...)
+    {
+        char msg[256];
+        va_list ap;
+        if (lwip_log_fn == NULL || (lwip_log_enabled_fn != NULL && !lwip_log_enabled_fn(file, flags))) {
+            return;
+        }
+        va_start(ap, format);
+        vsnprintf(msg, sizeof(msg), format, ap);
+        va_end(ap);
+        lwip_log_fn(file, flags, msg);
+    }
+#endif /* LWIP_RUST_LOG */
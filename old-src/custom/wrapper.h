@@ -4,3 +4,8 @@
 #include "../include/lwip/tcp.h"
 #include "../include/lwip/udp.h"
 #include "../include/lwip/ip_addr.h"
+#include "../include/lwip/stats.h"
+#include "../include/lwip/raw.h"
+#include "../include/lwip/etharp.h"
+#include "../include/lwip/ethip6.h"
+#include "../include/netif/ethernet.h"
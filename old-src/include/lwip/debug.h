@@ -142,6 +142,17 @@
 #endif
 
 #ifdef LWIP_DEBUG
+#if TUN2SOCKS && defined(LWIP_PLATFORM_DEBUGF)
+/* the port wants the debug flags along with the message to map them to log levels */
+#define LWIP_DEBUGF(debug, message) do { \
+                               if ( \
+                                   ((debug) & LWIP_DBG_ON) && \
+                                   ((debug) & LWIP_DBG_TYPES_ON) && \
+                                   ((s16_t)((debug) & LWIP_DBG_MASK_LEVEL) >= LWIP_DBG_MIN_LEVEL)) { \
+                                 LWIP_PLATFORM_DEBUGF(debug, message); \
+                               } \
+                             } while(0)
+#else /* TUN2SOCKS && LWIP_PLATFORM_DEBUGF */
 #define LWIP_DEBUGF(debug, message) do { \
                                if ( \
                                    ((debug) & LWIP_DBG_ON) && \
@@ -153,6 +164,7 @@
                                  } \
                                } \
                              } while(0)
+#endif /* TUN2SOCKS && LWIP_PLATFORM_DEBUGF */
 
 #else  /* LWIP_DEBUG */
 #define LWIP_DEBUGF(debug, message)
@@ -103,6 +103,51 @@ void icmp_time_exceeded(struct pbuf *p, enum icmp_te_type t);
 #define icmp_port_unreach(isipv6, pbuf)
 #endif /* (LWIP_IPV6 && LWIP_ICMP6) || (LWIP_IPV4 && LWIP_ICMP) LWIP_IPV4*/
 
+#if TUN2SOCKS
+/* go-tun2socks logic
+ * There is no host behind the tun to answer for traffic that no pcb claims,
+ * so the application decides whether it is dropped, reset or answered with
+ * an ICMP/ICMPv6 destination unreachable. */
+#define UNCLAIMED_REPLY_DROP  0
+#define UNCLAIMED_REPLY_RST   1
+#define UNCLAIMED_REPLY_ICMP  2
+
+struct unclaimed_reply {
+  /* one of UNCLAIMED_REPLY_* */
+  u8_t action;
+  /* destination unreachable codes used with UNCLAIMED_REPLY_ICMP */
+  u8_t icmp_code;
+  u8_t icmp6_code;
+};
+
+/* Set while icmp_unclaimed_unreach answers. The netif owns none of the addresses
+ * behind the tun, so the unreachable comes from the address the unclaimed packet
+ * was sent to, other ICMP errors from the address of the netif. */
+#if LWIP_IPV4 && LWIP_ICMP
+extern u8_t icmp_reply_from_dest;
+#define icmp_unclaimed_unreach4(pbuf, reply) do{ icmp_reply_from_dest = 1; \
+                                                 icmp_dest_unreach(pbuf, (enum icmp_dur_type)(reply)->icmp_code); \
+                                                 icmp_reply_from_dest = 0; }while(0)
+#endif
+#if LWIP_IPV6 && LWIP_ICMP6
+extern u8_t icmp6_reply_from_dest;
+#define icmp_unclaimed_unreach6(pbuf, reply) do{ icmp6_reply_from_dest = 1; \
+                                                 icmp6_dest_unreach(pbuf, (enum icmp6_dur_code)(reply)->icmp6_code); \
+                                                 icmp6_reply_from_dest = 0; }while(0)
+#endif
+
+#if LWIP_IPV4 && LWIP_IPV6 && LWIP_ICMP && LWIP_ICMP6
+#define icmp_unclaimed_unreach(isipv6, pbuf, reply) do{ if(isipv6) { icmp_unclaimed_unreach6(pbuf, reply); } \
+                                                        else { icmp_unclaimed_unreach4(pbuf, reply); }}while(0)
+#elif LWIP_IPV4 && LWIP_ICMP
+#define icmp_unclaimed_unreach(isipv6, pbuf, reply) do{ if(!(isipv6)) { icmp_unclaimed_unreach4(pbuf, reply);}}while(0)
+#elif LWIP_IPV6 && LWIP_ICMP6
+#define icmp_unclaimed_unreach(isipv6, pbuf, reply) do{ if(isipv6) { icmp_unclaimed_unreach6(pbuf, reply);}}while(0)
+#else
+#define icmp_unclaimed_unreach(isipv6, pbuf, reply)
+#endif
+#endif /* TUN2SOCKS */
+
 #ifdef __cplusplus
 }
 #endif
@@ -127,6 +127,11 @@ struct ip_globals
 };
 extern struct ip_globals ip_data;
 
+#if TUN2SOCKS
+/* forward the packets routed to another netif instead of accepting them, off by default */
+extern u8_t ip_forward_enabled;
+#endif /* TUN2SOCKS */
+
 
 /** Get the interface that accepted the current packet.
  * This may or may not be the receiving netif, depending on your netif/network setup.
@@ -60,6 +60,9 @@ extern "C" {
 struct netif *ip6_route(const ip6_addr_t *src, const ip6_addr_t *dest);
 const ip_addr_t *ip6_select_source_address(struct netif *netif, const ip6_addr_t * dest);
 err_t         ip6_input(struct pbuf *p, struct netif *inp);
+#if TUN2SOCKS && LWIP_IPV6_FORWARD
+int           ip6_input_forwarded(void);
+#endif /* TUN2SOCKS && LWIP_IPV6_FORWARD */
 err_t         ip6_output(struct pbuf *p, const ip6_addr_t *src, const ip6_addr_t *dest,
                          u8_t hl, u8_t tc, u8_t nexth);
 err_t         ip6_output_if(struct pbuf *p, const ip6_addr_t *src, const ip6_addr_t *dest,
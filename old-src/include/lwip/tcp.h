@@ -407,6 +407,11 @@ err_t lwip_tcp_event(void *arg, struct tcp_pcb *pcb,
 
 #endif /* LWIP_EVENT_API */
 
+#if TUN2SOCKS
+/* answer to segments no pcb claims, reset by default */
+extern struct unclaimed_reply tcp_unclaimed_reply;
+#endif /* TUN2SOCKS */
+
 /* Application program's interface: */
 struct tcp_pcb * tcp_new     (void);
 struct tcp_pcb * tcp_new_ip_type (u8_t type);
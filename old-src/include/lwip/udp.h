@@ -48,6 +48,9 @@
 #include "lwip/ip.h"
 #include "lwip/ip6_addr.h"
 #include "lwip/prot/udp.h"
+#if TUN2SOCKS
+#include "lwip/icmp.h"
+#endif /* TUN2SOCKS */
 
 #ifdef __cplusplus
 extern "C" {
@@ -57,6 +60,10 @@ extern "C" {
 #define UDP_FLAGS_UDPLITE        0x02U
 #define UDP_FLAGS_CONNECTED      0x04U
 #define UDP_FLAGS_MULTICAST_LOOP 0x08U
+#if TUN2SOCKS
+/* catch-all pcb receiving the datagrams no other pcb is bound to */
+#define UDP_FLAGS_TUN2SOCKS_ANY  0x10U
+#endif /* TUN2SOCKS */
 
 struct udp_pcb;
 
@@ -118,6 +125,10 @@ struct udp_pcb {
 };
 /* udp_pcbs export for external reference (e.g. SNMP agent) */
 extern struct udp_pcb *udp_pcbs;
+#if TUN2SOCKS
+/* answer to datagrams no pcb claims, dropped by default */
+extern struct unclaimed_reply udp_unclaimed_reply;
+#endif /* TUN2SOCKS */
 
 /* The following functions is the application layer interface to the
    UDP code. */
@@ -0,0 +1,297 @@
+use std::{
+    fmt, io,
+    net::IpAddr,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{Arc, RwLock, Weak},
+};
+
+use super::filter::PacketView;
+use super::interface::Interface;
+use crate::Error;
+
+/// An IPv4 or IPv6 network, e.g. `10.0.0.0/8` or `fc00::/7`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    /// Returns `None` if `prefix_len` is longer than the address, host bits are cleared.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let addr = match addr {
+            IpAddr::V4(ip) if prefix_len <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V4((u32::from(ip) & mask).into())
+            }
+            IpAddr::V6(ip) if prefix_len <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V6((u128::from(ip) & mask).into())
+            }
+            _ => return None,
+        };
+        Some(IpCidr { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match IpCidr::new(*ip, self.prefix_len) {
+            Some(net) => net.addr == self.addr,
+            None => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = Error;
+
+    /// Parses `addr/prefix_len`, a bare address is a host route.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidCidr(s.to_string());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len = prefix_len.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        IpCidr::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Where `NetStack::classify_and_input` sends a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputAction {
+    /// Input the packet into lwIP.
+    Stack,
+    /// Hand the packet back to the caller untouched.
+    Bypass,
+}
+
+/// A rule of `InputRules`, the fields left `None` match any packet. Packets without
+/// ports, e.g. ICMP, don't match a rule with ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputRule {
+    pub src: Option<IpCidr>,
+    pub dst: Option<IpCidr>,
+    /// The IPv4 protocol or the IPv6 next header, e.g. 6 for TCP and 17 for UDP.
+    pub protocol: Option<u8>,
+    pub src_ports: Option<RangeInclusive<u16>>,
+    pub dst_ports: Option<RangeInclusive<u16>>,
+    pub action: InputAction,
+}
+
+impl InputRule {
+    /// A rule matching every packet, to be narrowed down by setting its fields.
+    pub fn new(action: InputAction) -> Self {
+        InputRule {
+            src: None,
+            dst: None,
+            protocol: None,
+            src_ports: None,
+            dst_ports: None,
+            action,
+        }
+    }
+
+    fn matches(&self, view: &PacketView) -> bool {
+        let port_matches = |ports: &Option<RangeInclusive<u16>>, port: Option<u16>| match ports {
+            Some(ports) => port.map_or(false, |port| ports.contains(&port)),
+            None => true,
+        };
+        self.src.map_or(true, |net| net.contains(&view.src_addr))
+            && self.dst.map_or(true, |net| net.contains(&view.dst_addr))
+            && self.protocol.map_or(true, |proto| proto == view.protocol)
+            && port_matches(&self.src_ports, view.src_port())
+            && port_matches(&self.dst_ports, view.dst_port())
+    }
+}
+
+/// Rule table deciding which packets `NetStack::classify_and_input` inputs into lwIP.
+/// The first matching rule wins, packets matching none get the default action and
+/// packets which can't be parsed go to the stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputRules {
+    rules: Vec<InputRule>,
+    default: InputAction,
+}
+
+impl InputRules {
+    pub fn new(default: InputAction) -> Self {
+        InputRules {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    pub fn push(&mut self, rule: InputRule) {
+        self.rules.push(rule);
+    }
+
+    /// The action for `pkt`.
+    pub fn classify(&self, pkt: &[u8]) -> InputAction {
+        let view = match PacketView::parse(pkt) {
+            Some(view) => view,
+            None => return InputAction::Stack,
+        };
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&view))
+            .map_or(self.default, |rule| rule.action)
+    }
+}
+
+/// Inputs packets into the interface of the stack according to its `InputRules`, see
+/// `NetStack::input_handle`. Unlike the sink of the stack it takes `&self`, so that it can
+/// be cloned and used once the stack is split.
+#[derive(Clone)]
+pub struct InputHandle {
+    interface: Weak<Interface>,
+    rules: Arc<RwLock<Option<InputRules>>>,
+}
+
+impl InputHandle {
+    pub(crate) fn new(interface: Weak<Interface>) -> Self {
+        InputHandle {
+            interface,
+            rules: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    pub(crate) fn set_rules(&self, rules: Option<InputRules>) {
+        *self.rules.write().unwrap_or_else(|e| e.into_inner()) = rules;
+    }
+
+    /// Inputs `pkt` into lwIP like the sink does, unless the input rules bypass it, in
+    /// which case it's handed back untouched. Fails once the stack is dropped.
+    pub fn classify_and_input(&self, pkt: Vec<u8>) -> io::Result<InputOutcome> {
+        let interface = self
+            .interface
+            .upgrade()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "netstack dropped"))?;
+        let bypass = {
+            let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+            match (rules.as_ref(), interface.ip_packet(&pkt)) {
+                (Some(rules), Some(ip)) => rules.classify(ip) == InputAction::Bypass,
+                _ => false,
+            }
+        };
+        if bypass {
+            return Ok(InputOutcome::Bypassed(pkt));
+        }
+        interface.input(pkt)?;
+        Ok(InputOutcome::Consumed)
+    }
+}
+
+/// What `NetStack::classify_and_input` did with a packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputOutcome {
+    /// The packet was input into lwIP.
+    Consumed,
+    /// The packet matched a bypass rule, to be sent by the caller, e.g. through the OS route.
+    Bypassed(Vec<u8>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ip_cidr() {
+        let net: IpCidr = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains(&"10.255.0.1".parse().unwrap()));
+        assert!(!net.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!net.contains(&"::a00:1".parse().unwrap()));
+        let net: IpCidr = "fc00::/7".parse().unwrap();
+        assert!(net.contains(&"fd12::1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<IpCidr>()
+            .unwrap()
+            .contains(&"1.2.3.4".parse().unwrap()));
+        assert_eq!("::1".parse::<IpCidr>().unwrap().prefix_len(), 128);
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("10.0.0/8".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn test_classify() {
+        let mut rules = InputRules::new(InputAction::Bypass);
+        rules.push(InputRule {
+            dst: Some("1.2.3.0/24".parse().unwrap()),
+            protocol: Some(17),
+            dst_ports: Some(53..=53),
+            ..InputRule::new(InputAction::Stack)
+        });
+        let mut pkt = vec![
+            0x45, 0, 0, 28, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 1, 2, 3, 4,
+        ];
+        pkt.extend_from_slice(&[0x30, 0x39, 0, 53, 0, 8, 0, 0]);
+        assert_eq!(rules.classify(&pkt), InputAction::Stack);
+        pkt[23] = 54;
+        assert_eq!(rules.classify(&pkt), InputAction::Bypass);
+        assert_eq!(rules.classify(&[0xff]), InputAction::Stack);
+    }
+
+    #[cfg(feature = "ipv4")]
+    #[test]
+    fn test_input_handle() {
+        use super::super::stack::NetStack;
+        use crate::testing::{self, timeout};
+        use futures::StreamExt;
+
+        let _stack = testing::lock_stack();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (stack, _tcp_listener, mut udp_socket) = NetStack::new().unwrap();
+            let input = stack.input_handle();
+            let mut rules = InputRules::new(InputAction::Stack);
+            rules.push(InputRule {
+                dst: Some("1.2.3.0/24".parse().unwrap()),
+                ..InputRule::new(InputAction::Bypass)
+            });
+            stack.set_input_rules(Some(rules));
+            let mut pkt = vec![
+                0x45, 0, 0, 30, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 1, 2, 3, 4,
+            ];
+            pkt.extend_from_slice(&[0x30, 0x39, 0, 53, 0, 10, 0, 0, b'h', b'i']);
+            assert_eq!(
+                input.classify_and_input(pkt.clone()).unwrap(),
+                InputOutcome::Bypassed(pkt.clone())
+            );
+
+            // Still usable once the stack is split.
+            let (sink, stream) = stack.split();
+            pkt[18] = 9;
+            assert_eq!(
+                input.classify_and_input(pkt.clone()).unwrap(),
+                InputOutcome::Consumed
+            );
+            let (data, _, dst_addr) = timeout(udp_socket.next()).await.unwrap();
+            assert_eq!(data, b"hi");
+            assert_eq!(dst_addr, "1.2.9.4:53".parse().unwrap());
+
+            drop((sink, stream));
+            let err = input.classify_and_input(pkt).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        });
+    }
+}
@@ -0,0 +1,124 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use super::classify::IpCidr;
+
+/// What the stack sink consumes and the stack stream produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Raw IPv4/IPv6 packets, e.g. for a TUN device.
+    Ip,
+    /// Ethernet frames, e.g. for a TAP device or a VM network backend. The stack has its
+    /// own MAC, answers ARP and NDP for its addresses and resolves the neighbors it
+    /// replies to.
+    Ethernet { mac: [u8; 6] },
+}
+
+/// NAT64 settings of an interface, see `InterfaceConfig::nat64`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nat64Config {
+    /// The /96 the IPv4 addresses are embedded in, `64:ff9b::` by default, see RFC 6052.
+    pub prefix: Ipv6Addr,
+    /// Translate the IPv6 packets to the prefix into IPv4 before lwIP sees them, and the
+    /// replies back, see RFC 7915. Only for `LinkMode::Ip` and with the `ipv4` feature,
+    /// IPv6 extension headers other than the fragment header aren't translated. Otherwise
+    /// lwIP handles them as IPv6.
+    pub translate: bool,
+    /// The IPv4 addresses the IPv6 sources are translated to, one per source, the least
+    /// recently used is reassigned once all are taken. `192.0.0.0/29` by default, see
+    /// RFC 7335.
+    pub client_pool: IpCidr,
+}
+
+impl Default for Nat64Config {
+    fn default() -> Self {
+        Nat64Config {
+            prefix: Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0),
+            translate: true,
+            client_pool: "192.0.0.0/29".parse().unwrap(),
+        }
+    }
+}
+
+/// Settings for `NetStack::with_config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetStackConfig {
+    pub stack_buffer_size: usize,
+    /// The number of datagrams queued by the catch-all `UdpSocket` and by the sockets of
+    /// `UdpSocket::bind`.
+    pub udp_buffer_size: usize,
+    pub link: LinkMode,
+    pub mtu: u16,
+    /// The address of the stack netif, unspecified by default. Connections to any address
+    /// are accepted regardless, it's the gateway of the devices in Ethernet mode and the
+    /// source of `NetStack::ping`. It must not be the address of a device. Ignored without
+    /// the `ipv4` feature, like `ipv6_addr` without the `ipv6` one.
+    pub ipv4_addr: Ipv4Addr,
+    pub ipv4_netmask: Ipv4Addr,
+    pub ipv4_gateway: Ipv4Addr,
+    /// A global IPv6 address of the stack netif, next to the link-local one derived from
+    /// the MAC in Ethernet mode.
+    pub ipv6_addr: Option<Ipv6Addr>,
+    /// Router mode, off by default. Packets routed by `NetStack::add_route` to another
+    /// interface than the one they came in on are forwarded there instead of being
+    /// terminated by the stack, with the TTL or hop limit decremented.
+    pub ip_forward: bool,
+    /// NAT64 for IPv6-only devices, off by default. The accepted `TcpStream`s to the
+    /// prefix report the embedded IPv4 destination, whether packets are translated or not.
+    pub nat64: Option<Nat64Config>,
+}
+
+impl NetStackConfig {
+    /// The settings of the interface of the stack.
+    pub(crate) fn interface(&self) -> InterfaceConfig {
+        InterfaceConfig {
+            stack_buffer_size: self.stack_buffer_size,
+            link: self.link,
+            mtu: self.mtu,
+            ipv4_addr: self.ipv4_addr,
+            ipv4_netmask: self.ipv4_netmask,
+            ipv4_gateway: self.ipv4_gateway,
+            ipv6_addr: self.ipv6_addr,
+            nat64: self.nat64.clone(),
+        }
+    }
+}
+
+impl Default for NetStackConfig {
+    fn default() -> Self {
+        NetStackConfig {
+            stack_buffer_size: 512,
+            udp_buffer_size: 64,
+            link: LinkMode::Ip,
+            mtu: 1500,
+            ipv4_addr: Ipv4Addr::UNSPECIFIED,
+            ipv4_netmask: Ipv4Addr::UNSPECIFIED,
+            ipv4_gateway: Ipv4Addr::UNSPECIFIED,
+            ipv6_addr: None,
+            ip_forward: false,
+            nat64: None,
+        }
+    }
+}
+
+/// Settings for `NetStack::add_interface`, the fields of the interface part of
+/// `NetStackConfig`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    /// The number of packets the stream of the interface holds.
+    pub stack_buffer_size: usize,
+    pub link: LinkMode,
+    pub mtu: u16,
+    /// See `NetStackConfig::ipv4_addr`.
+    pub ipv4_addr: Ipv4Addr,
+    pub ipv4_netmask: Ipv4Addr,
+    pub ipv4_gateway: Ipv4Addr,
+    pub ipv6_addr: Option<Ipv6Addr>,
+    /// See `NetStackConfig::nat64`.
+    pub nat64: Option<Nat64Config>,
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        NetStackConfig::default().interface()
+    }
+}
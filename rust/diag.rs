@@ -0,0 +1,44 @@
+use std::{ffi::CStr, os::raw, path::Path};
+
+use super::lwip::*;
+
+/// Hooks lwIP debug output into the `log` crate, must run before `lwip_init`.
+pub(crate) fn init() {
+    unsafe {
+        lwip_log_enabled_fn = Some(log_enabled_cb);
+        lwip_log_fn = Some(log_cb);
+    }
+}
+
+/// `lwip::<module>` where module is the stem of the lwIP source file.
+fn target(file: *const raw::c_char) -> String {
+    let file = unsafe { CStr::from_ptr(file) }.to_string_lossy();
+    let module = Path::new(file.as_ref())
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("unknown");
+    format!("lwip::{}", module)
+}
+
+fn level(flags: raw::c_uchar) -> log::Level {
+    let flags = flags as u32;
+    match flags & LWIP_DBG_MASK_LEVEL {
+        LWIP_DBG_LEVEL_SEVERE => log::Level::Error,
+        LWIP_DBG_LEVEL_SERIOUS => log::Level::Warn,
+        _ if flags & LWIP_DBG_TRACE != 0 => log::Level::Trace,
+        _ => log::Level::Debug,
+    }
+}
+
+extern "C" fn log_enabled_cb(file: *const raw::c_char, flags: raw::c_uchar) -> raw::c_int {
+    log::log_enabled!(target: &target(file), level(flags)) as raw::c_int
+}
+
+extern "C" fn log_cb(file: *const raw::c_char, flags: raw::c_uchar, msg: *const raw::c_char) {
+    let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy();
+    let msg = msg.trim_end();
+    if msg.is_empty() {
+        return;
+    }
+    log::log!(target: &target(file), level(flags), "{}", msg);
+}
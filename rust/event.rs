@@ -0,0 +1,96 @@
+use std::{net::SocketAddr, pin::Pin, sync::Mutex};
+
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::interface::InterfaceId;
+
+static SUBSCRIBERS: Mutex<Vec<UnboundedSender<StackEvent>>> = Mutex::new(Vec::new());
+
+/// Bytes transferred over a connection, seen from the netstack side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ByteCounts {
+    /// Bytes received from the device.
+    pub rx: u64,
+    /// Bytes sent to the device.
+    pub tx: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpCloseReason {
+    /// The stream was shut down before it was dropped.
+    Closed,
+    /// The stream was dropped without a shutdown, the connection is aborted.
+    Aborted,
+    /// lwIP reported a fatal error other than a reset on the connection.
+    Error(i8),
+}
+
+/// Flow lifecycle notifications. Addresses follow `TcpListener` and `UdpSocket`,
+/// `local_addr`/`src_addr` is the device side of the flow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackEvent {
+    TcpOpened {
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+    },
+    TcpClosed {
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        reason: TcpCloseReason,
+        bytes: ByteCounts,
+    },
+    TcpReset {
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        bytes: ByteCounts,
+    },
+    /// Datagrams on another interface with the same addresses are another flow.
+    UdpFlowStarted {
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        interface: Option<InterfaceId>,
+    },
+    UdpFlowExpired {
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        interface: Option<InterfaceId>,
+    },
+}
+
+fn subscribers() -> std::sync::MutexGuard<'static, Vec<UnboundedSender<StackEvent>>> {
+    SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn emit(event: StackEvent) {
+    let mut subscribers = subscribers();
+    if subscribers.is_empty() {
+        return;
+    }
+    subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+}
+
+pub(crate) fn subscribe() -> EventStream {
+    let (tx, rx) = unbounded_channel();
+    subscribers().push(tx);
+    EventStream { rx }
+}
+
+/// Ends all event streams, called when the netstack goes away.
+pub(crate) fn close() {
+    subscribers().clear();
+}
+
+/// Stream of `StackEvent`s returned by `NetStack::events`.
+pub struct EventStream {
+    rx: UnboundedReceiver<StackEvent>,
+}
+
+impl Stream for EventStream {
+    type Item = StackEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
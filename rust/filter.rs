@@ -0,0 +1,391 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use super::interface::Interface;
+use super::util;
+
+const DIVERT_QUEUE_SIZE: usize = 512;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_ICMPV6: u8 = 58;
+
+type Filter = dyn Fn(&PacketView) -> Verdict + Send + Sync;
+type Diverted = (Direction, Vec<u8>);
+
+static INPUT_FILTER: RwLock<Option<Arc<Filter>>> = RwLock::new(None);
+static OUTPUT_FILTER: RwLock<Option<Arc<Filter>>> = RwLock::new(None);
+static DIVERT_TX: Mutex<Option<Sender<Diverted>>> = Mutex::new(None);
+
+/// What happens to a packet once a filter has seen it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Let the packet through.
+    Accept,
+    /// Silently discard the packet.
+    Drop,
+    /// Discard the packet and answer the device with an ICMP/ICMPv6 administratively
+    /// prohibited. Outgoing packets, and packets an ICMP error must not answer, are
+    /// dropped.
+    Reject,
+    /// Take the packet out of the path and hand it to the `DivertStream`, dropped when
+    /// there is none.
+    Divert,
+    /// Let this IP packet through instead, e.g. the packet with another address or port.
+    /// The Ethernet header is kept in Ethernet mode, the checksums are up to the filter.
+    Rewrite(Vec<u8>),
+}
+
+/// Which way a packet was going when it was filtered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the device into lwIP.
+    Ingress,
+    /// From lwIP to the device.
+    Egress,
+}
+
+/// Transport header of a `PacketView`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportHeader {
+    Tcp {
+        src_port: u16,
+        dst_port: u16,
+        /// The flags byte, FIN is `0x01`, SYN `0x02`, RST `0x04` and ACK `0x10`.
+        flags: u8,
+    },
+    Udp {
+        src_port: u16,
+        dst_port: u16,
+    },
+    /// ICMP for IPv4, ICMPv6 for IPv6, the types differ between both.
+    Icmp {
+        icmp_type: u8,
+        code: u8,
+    },
+    /// Another protocol, a truncated header, or a non-first IPv4 fragment.
+    Other,
+}
+
+/// Parsed headers of an IPv4 or IPv6 packet, handed to the filters.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketView<'a> {
+    pub src_addr: IpAddr,
+    pub dst_addr: IpAddr,
+    /// The IPv4 protocol or the next header of the fixed IPv6 header.
+    pub protocol: u8,
+    pub transport: TransportHeader,
+    /// The whole IP packet, without the Ethernet header in Ethernet mode.
+    pub packet: &'a [u8],
+}
+
+impl<'a> PacketView<'a> {
+    /// Parses the IP and transport headers, IPv6 extension headers aren't walked.
+    pub fn parse(packet: &'a [u8]) -> Option<Self> {
+        let (src_addr, dst_addr, protocol, payload) = util::split_ip_packet(packet)?;
+        let fragment =
+            src_addr.is_ipv4() && u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0;
+        let port = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+        let transport = match protocol {
+            _ if fragment => TransportHeader::Other,
+            IPPROTO_TCP if payload.len() >= 20 => TransportHeader::Tcp {
+                src_port: port(0),
+                dst_port: port(2),
+                flags: payload[13],
+            },
+            IPPROTO_UDP if payload.len() >= 8 => TransportHeader::Udp {
+                src_port: port(0),
+                dst_port: port(2),
+            },
+            IPPROTO_ICMP | IPPROTO_ICMPV6 if payload.len() >= 4 => {
+                if (protocol == IPPROTO_ICMP) != src_addr.is_ipv4() {
+                    TransportHeader::Other
+                } else {
+                    TransportHeader::Icmp {
+                        icmp_type: payload[0],
+                        code: payload[1],
+                    }
+                }
+            }
+            _ => TransportHeader::Other,
+        };
+        Some(PacketView {
+            src_addr,
+            dst_addr,
+            protocol,
+            transport,
+            packet,
+        })
+    }
+
+    pub fn src_port(&self) -> Option<u16> {
+        match self.transport {
+            TransportHeader::Tcp { src_port, .. } | TransportHeader::Udp { src_port, .. } => {
+                Some(src_port)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn dst_port(&self) -> Option<u16> {
+        match self.transport {
+            TransportHeader::Tcp { dst_port, .. } | TransportHeader::Udp { dst_port, .. } => {
+                Some(dst_port)
+            }
+            _ => None,
+        }
+    }
+
+    /// The ICMP/ICMPv6 administratively prohibited answering this packet, `None` for
+    /// multicast or broadcast destinations and ICMP messages other than echo requests.
+    fn reject_message(&self) -> Option<Vec<u8>> {
+        match self.transport {
+            TransportHeader::Icmp { icmp_type, .. } if icmp_type != 8 && icmp_type != 128 => {
+                return None
+            }
+            _ => {}
+        }
+        match (self.src_addr, self.dst_addr) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                if dst.is_multicast() || dst.is_broadcast() || src.is_unspecified() {
+                    return None;
+                }
+                // RFC 792, the IP header and the first 8 bytes of its payload.
+                let hdr_len = ((self.packet[0] & 0x0f) as usize) * 4;
+                let quoted = &self.packet[..(hdr_len + 8).min(self.packet.len())];
+                Some(ip4_icmp_error(dst, src, 3, 13, quoted))
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                if dst.is_multicast() || src.is_unspecified() {
+                    return None;
+                }
+                // RFC 4443, as much of the packet as fits into the minimum MTU.
+                let quoted = &self.packet[..self.packet.len().min(1280 - 48)];
+                Some(ip6_icmp_error(dst, src, 1, 1, quoted))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn ip4_icmp_error(src: Ipv4Addr, dst: Ipv4Addr, icmp_type: u8, code: u8, quoted: &[u8]) -> Vec<u8> {
+    let tot_len = (20 + 8 + quoted.len()) as u16;
+    let mut pkt = Vec::with_capacity(tot_len as usize);
+    pkt.extend_from_slice(&[0x45, 0]);
+    pkt.extend_from_slice(&tot_len.to_be_bytes());
+    pkt.extend_from_slice(&[0, 0, 0, 0, 64, IPPROTO_ICMP, 0, 0]);
+    pkt.extend_from_slice(&src.octets());
+    pkt.extend_from_slice(&dst.octets());
+    let checksum = util::inet_checksum(&pkt, 0);
+    pkt[10..12].copy_from_slice(&checksum.to_be_bytes());
+    pkt.extend_from_slice(&[icmp_type, code, 0, 0, 0, 0, 0, 0]);
+    pkt.extend_from_slice(quoted);
+    let checksum = util::inet_checksum(&pkt[20..], 0);
+    pkt[22..24].copy_from_slice(&checksum.to_be_bytes());
+    pkt
+}
+
+fn ip6_icmp_error(src: Ipv6Addr, dst: Ipv6Addr, icmp_type: u8, code: u8, quoted: &[u8]) -> Vec<u8> {
+    let payload_len = (8 + quoted.len()) as u16;
+    let mut pkt = Vec::with_capacity(40 + payload_len as usize);
+    pkt.extend_from_slice(&[0x60, 0, 0, 0]);
+    pkt.extend_from_slice(&payload_len.to_be_bytes());
+    pkt.extend_from_slice(&[IPPROTO_ICMPV6, 255]);
+    pkt.extend_from_slice(&src.octets());
+    pkt.extend_from_slice(&dst.octets());
+    pkt.extend_from_slice(&[icmp_type, code, 0, 0, 0, 0, 0, 0]);
+    pkt.extend_from_slice(quoted);
+    let sum = util::ip6_pseudo_sum(&src, &dst, payload_len as u32, IPPROTO_ICMPV6);
+    let checksum = util::inet_checksum(&pkt[40..], sum);
+    pkt[42..44].copy_from_slice(&checksum.to_be_bytes());
+    pkt
+}
+
+fn filter_slot(direction: Direction) -> &'static RwLock<Option<Arc<Filter>>> {
+    match direction {
+        Direction::Ingress => &INPUT_FILTER,
+        Direction::Egress => &OUTPUT_FILTER,
+    }
+}
+
+pub(crate) fn set_filter(direction: Direction, filter: Option<Arc<Filter>>) {
+    *filter_slot(direction)
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = filter;
+}
+
+/// Runs the filter of `direction` over `pkt`, returns the packet if it goes on.
+/// Packets which can't be parsed, and Ethernet frames other than IP, are let through.
+fn run_filter(interface: &Interface, direction: Direction, pkt: Vec<u8>) -> Option<Vec<u8>> {
+    // Cloned out so that the filter may replace itself.
+    let filter = filter_slot(direction)
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Some(pkt),
+    };
+    let view = match interface.ip_packet(&pkt).and_then(PacketView::parse) {
+        Some(view) => view,
+        None => return Some(pkt),
+    };
+    match filter(&view) {
+        Verdict::Accept => Some(pkt),
+        Verdict::Drop => None,
+        Verdict::Reject => {
+            if direction == Direction::Ingress {
+                if let Some(mut reply) = view.reject_message() {
+                    if pkt.len() > view.packet.len() {
+                        // Ethernet, back to the sender from the address it was sent to.
+                        let mut frame = Vec::with_capacity(14 + reply.len());
+                        frame.extend_from_slice(&pkt[6..12]);
+                        frame.extend_from_slice(&pkt[0..6]);
+                        frame.extend_from_slice(&pkt[12..14]);
+                        frame.append(&mut reply);
+                        reply = frame;
+                    }
+                    interface.output(reply);
+                }
+            }
+            None
+        }
+        Verdict::Divert => {
+            if let Some(tx) = DIVERT_TX.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+                if tx.try_send((direction, pkt)).is_err() {
+                    log::trace!("divert stream full, packet dropped");
+                }
+            }
+            None
+        }
+        Verdict::Rewrite(packet) => {
+            let link_len = pkt.len() - view.packet.len();
+            Some(replace_ip_packet(pkt, link_len, packet))
+        }
+    }
+}
+
+/// `pkt` with the IP packet after its `link_len` bytes of link header replaced by `packet`.
+fn replace_ip_packet(mut pkt: Vec<u8>, link_len: usize, packet: Vec<u8>) -> Vec<u8> {
+    if link_len == 0 {
+        return packet;
+    }
+    pkt.truncate(link_len);
+    pkt.extend_from_slice(&packet);
+    pkt
+}
+
+/// Runs the input filter over a packet from the device, before it reaches lwIP.
+pub(crate) fn filter_input(interface: &Interface, pkt: Vec<u8>) -> Option<Vec<u8>> {
+    run_filter(interface, Direction::Ingress, pkt)
+}
+
+/// Runs the output filter over a packet from lwIP, lwip_mutex is locked.
+pub(crate) fn filter_output(interface: &Interface, pkt: Vec<u8>) -> Option<Vec<u8>> {
+    run_filter(interface, Direction::Egress, pkt)
+}
+
+pub(crate) fn divert() -> DivertStream {
+    let (tx, rx) = channel(DIVERT_QUEUE_SIZE);
+    DIVERT_TX
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .replace(tx);
+    DivertStream { rx }
+}
+
+/// Removes the filters and ends the divert stream, called when the netstack goes away.
+pub(crate) fn close() {
+    set_filter(Direction::Ingress, None);
+    set_filter(Direction::Egress, None);
+    DIVERT_TX.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// Stream of the packets a filter diverted, returned by `NetStack::divert_stream`.
+pub struct DivertStream {
+    rx: Receiver<Diverted>,
+}
+
+impl Stream for DivertStream {
+    type Item = Diverted;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_reject() {
+        let mut pkt = vec![
+            0x45,
+            0,
+            0,
+            28,
+            0,
+            1,
+            0,
+            0,
+            64,
+            IPPROTO_UDP,
+            0,
+            0,
+            10,
+            0,
+            0,
+            1,
+            1,
+            2,
+            3,
+            4,
+        ];
+        pkt.extend_from_slice(&[0x30, 0x39, 0, 53, 0, 8, 0, 0]);
+        let view = PacketView::parse(&pkt).unwrap();
+        assert_eq!(view.src_addr, IpAddr::from([10, 0, 0, 1]));
+        assert_eq!(view.dst_addr, IpAddr::from([1, 2, 3, 4]));
+        assert_eq!(
+            view.transport,
+            TransportHeader::Udp {
+                src_port: 12345,
+                dst_port: 53
+            }
+        );
+        assert_eq!(view.dst_port(), Some(53));
+
+        let reply = view.reject_message().unwrap();
+        assert_eq!(reply.len(), 20 + 8 + 28);
+        assert_eq!(&reply[12..16], &[1, 2, 3, 4]);
+        assert_eq!(&reply[16..20], &[10, 0, 0, 1]);
+        assert_eq!(&reply[20..22], &[3, 13]);
+        assert_eq!(&reply[28..], &pkt[..]);
+        assert_eq!(util::inet_checksum(&reply[..20], 0), 0);
+        assert_eq!(util::inet_checksum(&reply[20..], 0), 0);
+
+        // Non-first fragments carry no transport header.
+        pkt[7] = 1;
+        let view = PacketView::parse(&pkt).unwrap();
+        assert_eq!(view.transport, TransportHeader::Other);
+    }
+
+    #[test]
+    fn test_replace_ip_packet() {
+        let packet = vec![0x45, 0, 0, 20];
+        assert_eq!(replace_ip_packet(vec![0x45; 28], 0, packet.clone()), packet);
+        // The Ethernet header is kept.
+        let mut frame = vec![1u8; 14];
+        frame.extend_from_slice(&[0x45; 28]);
+        let rewritten = replace_ip_packet(frame, 14, packet.clone());
+        assert_eq!(&rewritten[..14], &[1u8; 14]);
+        assert_eq!(&rewritten[14..], &packet[..]);
+    }
+}
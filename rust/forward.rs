@@ -0,0 +1,79 @@
+use std::{
+    net::IpAddr,
+    os::raw,
+    sync::{Arc, RwLock},
+};
+
+use super::filter::PacketView;
+use super::interface::{Interface, InterfaceId};
+use super::lwip::*;
+use super::route;
+use super::LWIP_MUTEX;
+
+type ForwardFilter = dyn Fn(&PacketView, InterfaceId, InterfaceId) -> bool + Send + Sync;
+
+static FORWARD_FILTER: RwLock<Option<Arc<ForwardFilter>>> = RwLock::new(None);
+
+/// Installs the forwarding hook of lwIP, must run before `lwip_init`.
+pub(crate) fn init() {
+    unsafe { lwip_canforward_fn = Some(canforward_cb) };
+}
+
+/// Turns router mode on or off, see `NetStackConfig::ip_forward`.
+pub(crate) fn set_enabled(enabled: bool) {
+    let _g = LWIP_MUTEX.lock();
+    unsafe { ip_forward_enabled = enabled as u8_t };
+}
+
+pub(crate) fn set_filter(filter: Option<Arc<ForwardFilter>>) {
+    *FORWARD_FILTER.write().unwrap_or_else(|e| e.into_inner()) = filter;
+}
+
+/// Stops forwarding and removes the filter, called when the netstack goes away.
+pub(crate) fn close() {
+    set_enabled(false);
+    set_filter(None);
+}
+
+/// The interface lwIP forwards a packet from `src_addr` to `dst_addr` to, lwip_mutex is
+/// locked.
+fn route_interface(src_addr: &IpAddr, dst_addr: &IpAddr) -> Option<InterfaceId> {
+    let netif = route::find(src_addr, dst_addr);
+    if netif.is_null() {
+        return None;
+    }
+    unsafe { Interface::from_netif(netif) }.map(Interface::id)
+}
+
+/// Runs the forward filter over a packet lwIP is about to forward, `p` starts at the IP
+/// header. Returns 1 to forward, 0 to discard, -1 to leave it to lwIP.
+extern "C" fn canforward_cb(p: *mut pbuf) -> raw::c_int {
+    // Cloned out so that the filter may replace itself.
+    let filter = FORWARD_FILTER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return -1,
+    };
+    let buf = unsafe {
+        let tot_len = std::ptr::read_unaligned(p).tot_len;
+        let mut buf = Vec::with_capacity(tot_len as usize);
+        pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, tot_len, 0);
+        buf.set_len(tot_len as usize);
+        buf
+    };
+    let view = match PacketView::parse(&buf) {
+        Some(view) => view,
+        None => return -1,
+    };
+    let (from, to) = match (
+        InterfaceId::current_input(),
+        route_interface(&view.src_addr, &view.dst_addr),
+    ) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return -1,
+    };
+    filter(&view, from, to) as raw::c_int
+}
@@ -0,0 +1,587 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::raw,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future::poll_fn;
+use futures::stream::Stream;
+use futures::task::{Context, Poll, Waker};
+use log::warn;
+use tokio::sync::oneshot;
+
+use super::lwip::*;
+use super::raw_socket::poll_send_raw;
+use super::route;
+use super::stack::NetStack;
+use super::util;
+use super::LWIP_MUTEX;
+use crate::Error;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP6_ECHO_REQUEST: u8 = 128;
+const ICMP6_ECHO_REPLY: u8 = 129;
+const ICMP_QUEUE_SIZE: usize = 64;
+
+/// An ICMP or ICMPv6 echo request sent by the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IcmpEcho {
+    /// The device side.
+    pub src_addr: IpAddr,
+    /// The address being pinged.
+    pub dst_addr: IpAddr,
+    pub id: u16,
+    pub seq: u16,
+    pub payload: Vec<u8>,
+}
+
+impl IcmpEcho {
+    /// Parses an echo request out of an IPv4 or IPv6 packet.
+    fn parse_request(pkt: &[u8]) -> Option<Self> {
+        Self::parse(pkt, ICMP_ECHO_REQUEST, ICMP6_ECHO_REQUEST)
+    }
+
+    /// Parses an echo message of the given ICMP or ICMPv6 type.
+    fn parse(pkt: &[u8], icmp_type: u8, icmp6_type: u8) -> Option<Self> {
+        // Echo requests behind IPv6 extension headers are left to lwIP.
+        let (src_addr, dst_addr, proto, msg) = util::split_ip_packet(pkt)?;
+        let (kind, icmp_proto) = if src_addr.is_ipv4() {
+            (icmp_type, IP_PROTO_ICMP)
+        } else {
+            (icmp6_type, IP6_NEXTH_ICMP6)
+        };
+        if proto != icmp_proto as u8 || msg.len() < 8 || msg[0] != kind || msg[1] != 0 {
+            return None;
+        }
+        Some(IcmpEcho {
+            src_addr,
+            dst_addr,
+            id: u16::from_be_bytes([msg[4], msg[5]]),
+            seq: u16::from_be_bytes([msg[6], msg[7]]),
+            payload: msg[8..].to_vec(),
+        })
+    }
+
+    /// The ICMP message answering this request, checksum included.
+    fn reply_message(&self) -> Vec<u8> {
+        let kind = if self.src_addr.is_ipv4() {
+            ICMP_ECHO_REPLY
+        } else {
+            ICMP6_ECHO_REPLY
+        };
+        self.message(kind, self.dst_addr, self.src_addr)
+    }
+
+    /// The ICMP message carrying this request, checksum included.
+    fn request_message(&self) -> Vec<u8> {
+        let kind = if self.src_addr.is_ipv4() {
+            ICMP_ECHO_REQUEST
+        } else {
+            ICMP6_ECHO_REQUEST
+        };
+        self.message(kind, self.src_addr, self.dst_addr)
+    }
+
+    fn message(&self, kind: u8, src_addr: IpAddr, dst_addr: IpAddr) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(8 + self.payload.len());
+        msg.extend_from_slice(&[kind, 0, 0, 0]);
+        msg.extend_from_slice(&self.id.to_be_bytes());
+        msg.extend_from_slice(&self.seq.to_be_bytes());
+        msg.extend_from_slice(&self.payload);
+        let sum = match (src_addr, dst_addr) {
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                util::ip6_pseudo_sum(&src, &dst, msg.len() as u32, IP6_NEXTH_ICMP6 as u8)
+            }
+            _ => 0,
+        };
+        let checksum = util::inet_checksum(&msg, sum);
+        msg[2..4].copy_from_slice(&checksum.to_be_bytes());
+        msg
+    }
+}
+
+struct IcmpQueue {
+    echos: VecDeque<IcmpEcho>,
+    waker: Option<Waker>,
+}
+
+pub extern "C" fn icmp_recv_cb(
+    arg: *mut raw::c_void,
+    _pcb: *mut raw_pcb,
+    p: *mut pbuf,
+    _addr: *const ip_addr_t,
+) -> u8_t {
+    // SAFETY: the callback is removed, with lwip_mutex locked, before the socket is dropped.
+    let socket = unsafe { &*(arg as *const IcmpSocket) };
+    let tot_len = unsafe { std::ptr::read_unaligned(p).tot_len };
+    let mut buf = vec![0u8; tot_len as usize];
+    unsafe { pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, tot_len, 0) };
+    // Anything but echo requests is left to lwIP.
+    let echo = match IcmpEcho::parse_request(&buf) {
+        Some(echo) => echo,
+        None => return 0,
+    };
+    let mut queue = socket.queue();
+    if queue.echos.len() < ICMP_QUEUE_SIZE {
+        queue.echos.push_back(echo);
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+    } else {
+        warn!("icmp socket full, echo request dropped");
+    }
+    unsafe { pbuf_free(p) };
+    1
+}
+
+/// Surfaces the ICMP and ICMPv6 echo requests sent by the device, whatever their
+/// destination, instead of having lwIP answer them. Replies are injected with
+/// `send_reply`, e.g. once the application got one from upstream.
+pub struct IcmpSocket {
+    pcb4: usize,
+    pcb6: usize,
+    queue: Mutex<IcmpQueue>,
+}
+
+impl IcmpSocket {
+    pub fn new(_stack: &NetStack) -> Result<Box<Self>, Error> {
+        let _g = LWIP_MUTEX.lock();
+        unsafe {
+            // Only for the families the stack is built with, the other pcb stays null.
+            let new_pcb = |enabled: bool, ip_type: u32, proto: u32| {
+                if enabled {
+                    raw_new_ip_type(ip_type as u8_t, proto as u8_t)
+                } else {
+                    std::ptr::null_mut()
+                }
+            };
+            let pcb4 = new_pcb(
+                cfg!(feature = "ipv4"),
+                lwip_ip_addr_type_IPADDR_TYPE_V4,
+                IP_PROTO_ICMP,
+            );
+            let pcb6 = new_pcb(
+                cfg!(feature = "ipv6"),
+                lwip_ip_addr_type_IPADDR_TYPE_V6,
+                IP6_NEXTH_ICMP6,
+            );
+            if (cfg!(feature = "ipv4") && pcb4.is_null())
+                || (cfg!(feature = "ipv6") && pcb6.is_null())
+            {
+                for pcb in [pcb4, pcb6] {
+                    if !pcb.is_null() {
+                        raw_remove(pcb);
+                    }
+                }
+                return Err(Error::LwIP(err_enum_t_ERR_MEM as err_t));
+            }
+            let socket = Box::new(IcmpSocket {
+                pcb4: pcb4 as usize,
+                pcb6: pcb6 as usize,
+                queue: Mutex::new(IcmpQueue {
+                    echos: VecDeque::new(),
+                    waker: None,
+                }),
+            });
+            let arg = &*socket as *const IcmpSocket as *mut raw::c_void;
+            for pcb in [pcb4, pcb6] {
+                if !pcb.is_null() {
+                    raw_recv(pcb, Some(icmp_recv_cb), arg);
+                }
+            }
+            Ok(socket)
+        }
+    }
+
+    fn queue(&self) -> std::sync::MutexGuard<'_, IcmpQueue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sends the echo reply to `echo` back to the device, from the pinged address.
+    pub async fn send_reply(&self, echo: &IcmpEcho) -> io::Result<()> {
+        let pcb = match (echo.src_addr, echo.dst_addr) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => self.pcb4,
+            (IpAddr::V6(_), IpAddr::V6(_)) => self.pcb6,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "mixed address families",
+                ))
+            }
+        };
+        if pcb == 0 {
+            return Err(Error::UnsupportedFamily(echo.dst_addr).into());
+        }
+        let msg = echo.reply_message();
+        poll_fn(|cx| {
+            let _g = LWIP_MUTEX.lock();
+            poll_send_raw(cx, &echo.dst_addr, &echo.src_addr, pcb, &msg)
+        })
+        .await
+    }
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        let _g = LWIP_MUTEX.lock();
+        unsafe {
+            for pcb in [self.pcb4, self.pcb6] {
+                if pcb == 0 {
+                    continue;
+                }
+                raw_recv(pcb as *mut raw_pcb, None, std::ptr::null_mut());
+                raw_remove(pcb as *mut raw_pcb);
+            }
+        }
+    }
+}
+
+impl Stream for IcmpSocket {
+    type Item = IcmpEcho;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut queue = self.queue();
+        match queue.echos.pop_front() {
+            Some(echo) => Poll::Ready(Some(echo)),
+            None => {
+                queue.waker.replace(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Allocates the id and sequence number of the pings, the pair is unique among the
+/// pings in flight.
+static PING_SEQ: AtomicUsize = AtomicUsize::new(0);
+const PING_ID_BASE: u16 = 0x4c57;
+
+/// What a ping in flight waits for, the callback argument of its pcb.
+struct PingState {
+    dst_addr: IpAddr,
+    id: u16,
+    seq: u16,
+    tx: Mutex<Option<oneshot::Sender<Instant>>>,
+}
+
+pub extern "C" fn ping_recv_cb(
+    arg: *mut raw::c_void,
+    _pcb: *mut raw_pcb,
+    p: *mut pbuf,
+    _addr: *const ip_addr_t,
+) -> u8_t {
+    // SAFETY: the callback is removed, with lwip_mutex locked, before the ping is dropped.
+    let ping = unsafe { &*(arg as *const PingState) };
+    let tot_len = unsafe { std::ptr::read_unaligned(p).tot_len };
+    let mut buf = vec![0u8; tot_len as usize];
+    unsafe { pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, tot_len, 0) };
+    match IcmpEcho::parse(&buf, ICMP_ECHO_REPLY, ICMP6_ECHO_REPLY) {
+        Some(echo)
+            if echo.id == ping.id && echo.seq == ping.seq && echo.src_addr == ping.dst_addr =>
+        {
+            if let Some(tx) = ping.tx.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                let _ = tx.send(Instant::now());
+            }
+            unsafe { pbuf_free(p) };
+            1
+        }
+        _ => 0,
+    }
+}
+
+/// A ping in flight, its pcb is removed once the ping completes or is cancelled.
+struct Ping {
+    pcb: usize,
+    _state: Box<PingState>,
+}
+
+impl Ping {
+    /// lwip_mutex must be locked.
+    unsafe fn new(state: Box<PingState>) -> Result<Self, Error> {
+        let (ip_type, proto) = match state.dst_addr {
+            #[cfg(feature = "ipv4")]
+            IpAddr::V4(_) => (lwip_ip_addr_type_IPADDR_TYPE_V4, IP_PROTO_ICMP),
+            #[cfg(feature = "ipv6")]
+            IpAddr::V6(_) => (lwip_ip_addr_type_IPADDR_TYPE_V6, IP6_NEXTH_ICMP6),
+            #[allow(unreachable_patterns)]
+            dst_addr => return Err(Error::UnsupportedFamily(dst_addr)),
+        };
+        let pcb = raw_new_ip_type(ip_type as u8_t, proto as u8_t);
+        if pcb.is_null() {
+            return Err(Error::LwIP(err_enum_t_ERR_MEM as err_t));
+        }
+        let arg = &*state as *const PingState as *mut raw::c_void;
+        raw_recv(pcb, Some(ping_recv_cb), arg);
+        Ok(Ping {
+            pcb: pcb as usize,
+            _state: state,
+        })
+    }
+}
+
+impl Drop for Ping {
+    fn drop(&mut self) {
+        let _g = LWIP_MUTEX.lock();
+        unsafe {
+            raw_recv(self.pcb as *mut raw_pcb, None, std::ptr::null_mut());
+            raw_remove(self.pcb as *mut raw_pcb);
+        }
+    }
+}
+
+/// The address of the netif `dst_addr` is routed to, the first valid IPv6 address which
+/// isn't link-local unless `dst_addr` is, lwip_mutex must be locked.
+unsafe fn netif_addr(dst_addr: &IpAddr) -> IpAddr {
+    let unspecified: IpAddr = match dst_addr {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let netif = route::find(&unspecified, dst_addr);
+    if netif.is_null() {
+        return unspecified;
+    }
+    let netif = &*netif;
+    #[cfg(feature = "ipv6")]
+    let link_local =
+        |ip: &IpAddr| matches!(ip, IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80);
+    match dst_addr {
+        #[cfg(feature = "ipv4")]
+        IpAddr::V4(_) => util::to_socket_addr(&netif.ip_addr, 0).ip(),
+        #[cfg(feature = "ipv6")]
+        IpAddr::V6(_) => netif
+            .ip6_addr
+            .iter()
+            .zip(netif.ip6_addr_state.iter())
+            .filter(|(_, &state)| state as u32 & IP6_ADDR_VALID != 0)
+            .map(|(addr, _)| util::to_socket_addr(addr, 0).ip())
+            .find(|ip| link_local(ip) == link_local(dst_addr))
+            .unwrap_or(unspecified),
+        #[allow(unreachable_patterns)]
+        _ => unspecified,
+    }
+}
+
+/// Sends an echo request with `payload_len` bytes of payload to `dst_addr`, from
+/// `src_addr` or the netif address, and waits for the reply.
+pub(crate) async fn ping(
+    src_addr: Option<IpAddr>,
+    dst_addr: IpAddr,
+    payload_len: usize,
+    timeout: Duration,
+) -> io::Result<Duration> {
+    if payload_len > u16::MAX as usize - 48 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "ping payload too large",
+        ));
+    }
+    if matches!(src_addr, Some(src) if src.is_ipv4() != dst_addr.is_ipv4()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "mixed address families",
+        ));
+    }
+    // Before looking for a source address, which a missing family has none of.
+    util::to_ip_addr_t(dst_addr)?;
+    let n = PING_SEQ.fetch_add(1, Ordering::Relaxed);
+    let id = (n >> 16) as u16 ^ PING_ID_BASE;
+    let seq = n as u16;
+    let (tx, rx) = oneshot::channel();
+    let (ping, echo) = {
+        let _g = LWIP_MUTEX.lock();
+        let src_addr = src_addr.unwrap_or_else(|| unsafe { netif_addr(&dst_addr) });
+        if src_addr.is_unspecified() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no netif address to ping from",
+            ));
+        }
+        let echo = IcmpEcho {
+            src_addr,
+            dst_addr,
+            id,
+            seq,
+            payload: (0..payload_len).map(|i| i as u8).collect(),
+        };
+        let state = Box::new(PingState {
+            dst_addr,
+            id,
+            seq,
+            tx: Mutex::new(Some(tx)),
+        });
+        (unsafe { Ping::new(state)? }, echo)
+    };
+    let msg = echo.request_message();
+    let mut sent_at = Instant::now();
+    poll_fn(|cx| {
+        let _g = LWIP_MUTEX.lock();
+        sent_at = Instant::now();
+        poll_send_raw(cx, &echo.src_addr, &echo.dst_addr, ping.pcb, &msg)
+    })
+    .await?;
+    let result = tokio::time::timeout(timeout, rx).await;
+    drop(ping);
+    match result {
+        Ok(Ok(received_at)) => Ok(received_at.saturating_duration_since(sent_at)),
+        _ => Err(io::Error::new(io::ErrorKind::TimedOut, "ping timed out")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{self, timeout};
+    use crate::NetStackConfig;
+    use futures::{SinkExt, StreamExt};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// An address of the device and another one, for each family the stack is built with.
+    fn pairs(other: [u8; 4], other6: &str) -> Vec<(IpAddr, IpAddr)> {
+        let mut pairs = Vec::new();
+        if cfg!(feature = "ipv4") {
+            pairs.push(([10, 0, 0, 2].into(), other.into()));
+        }
+        if cfg!(feature = "ipv6") {
+            pairs.push(("fd00::2".parse().unwrap(), other6.parse().unwrap()));
+        }
+        pairs
+    }
+
+    /// The next echo request or reply out of the stack, skipping the neighbor discovery
+    /// and the like.
+    async fn next_echo(stack: &mut NetStack, request: bool) -> (Vec<u8>, IcmpEcho) {
+        loop {
+            let pkt = stack.next().await.unwrap().unwrap();
+            let echo = if request {
+                IcmpEcho::parse_request(&pkt)
+            } else {
+                IcmpEcho::parse(&pkt, ICMP_ECHO_REPLY, ICMP6_ECHO_REPLY)
+            };
+            if let Some(echo) = echo {
+                return (pkt, echo);
+            }
+        }
+    }
+
+    #[test]
+    fn test_icmp_socket() {
+        let _stack = testing::lock_stack();
+        runtime().block_on(async {
+            let (mut stack, _tcp_listener, _udp_socket) = NetStack::new().unwrap();
+            let mut socket = IcmpSocket::new(&stack).unwrap();
+            for (device, pinged) in pairs([8, 8, 8, 8], "2001:db8::1") {
+                let request = testing::echo(true, device, pinged, 7, 1, b"abc");
+                timeout(stack.send(request)).await.unwrap();
+                let echo = timeout(socket.next()).await.unwrap();
+                assert_eq!(
+                    echo,
+                    IcmpEcho {
+                        src_addr: device,
+                        dst_addr: pinged,
+                        id: 7,
+                        seq: 1,
+                        payload: b"abc".to_vec(),
+                    }
+                );
+
+                // Answered from the pinged address rather than the netif one.
+                timeout(socket.send_reply(&echo)).await.unwrap();
+                let (pkt, reply) = timeout(next_echo(&mut stack, false)).await;
+                testing::check(&pkt);
+                assert_eq!((reply.src_addr, reply.dst_addr), (pinged, device));
+                assert_eq!((reply.id, reply.seq), (7, 1));
+                assert_eq!(reply.payload, b"abc");
+            }
+        });
+    }
+
+    fn ping_config() -> NetStackConfig {
+        NetStackConfig {
+            ipv4_addr: [10, 0, 0, 1].into(),
+            ipv4_netmask: [255, 255, 255, 0].into(),
+            ipv6_addr: Some("fd00::1".parse().unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ping() {
+        let _stack = testing::lock_stack();
+        runtime().block_on(async {
+            let (mut stack, _tcp_listener, _udp_socket) =
+                NetStack::with_config(ping_config()).unwrap();
+            for (device, local) in pairs([10, 0, 0, 1], "fd00::1") {
+                let ping = tokio::spawn(stack.ping(device, 16, Duration::from_secs(5)));
+                let (pkt, request) = timeout(next_echo(&mut stack, true)).await;
+                testing::check(&pkt);
+                assert_eq!((request.src_addr, request.dst_addr), (local, device));
+                assert_eq!(request.payload.len(), 16);
+
+                // The device echoes the request back.
+                let (id, seq) = (request.id, request.seq);
+                let reply = testing::echo(false, device, local, id, seq, &request.payload);
+                timeout(stack.send(reply)).await.unwrap();
+                timeout(ping).await.unwrap().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_ping_other_replies() {
+        let _stack = testing::lock_stack();
+        runtime().block_on(async {
+            let (mut stack, _tcp_listener, _udp_socket) =
+                NetStack::with_config(ping_config()).unwrap();
+            #[cfg(feature = "stats")]
+            let raw_pcbs = |stack: &NetStack| {
+                let stats = stack.stats();
+                let pool = stats.memp.iter().find(|pool| pool.name == "RAW_PCB");
+                pool.unwrap().used
+            };
+            #[cfg(feature = "stats")]
+            let before = raw_pcbs(&stack);
+            let others = pairs([10, 0, 0, 3], "fd00::3");
+            for (device, local) in pairs([10, 0, 0, 1], "fd00::1") {
+                let other = others
+                    .iter()
+                    .find(|(_, ip)| ip.is_ipv4() == device.is_ipv4());
+                let other = other.unwrap().1;
+                let ping = tokio::spawn(stack.ping(device, 16, Duration::from_millis(300)));
+                let (_, request) = timeout(next_echo(&mut stack, true)).await;
+                #[cfg(feature = "stats")]
+                assert_eq!(raw_pcbs(&stack), before + 1);
+
+                // Replies of another id or seq, or from another address, aren't the one.
+                let (id, seq, payload) = (request.id, request.seq, &request.payload);
+                for (src, id, seq) in [
+                    (device, id ^ 1, seq),
+                    (device, id, seq ^ 1),
+                    (other, id, seq),
+                ] {
+                    let reply = testing::echo(false, src, local, id, seq, payload);
+                    timeout(stack.send(reply)).await.unwrap();
+                }
+                let err = timeout(ping).await.unwrap().unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                // The pcb of the ping is gone with it.
+                #[cfg(feature = "stats")]
+                assert_eq!(raw_pcbs(&stack), before);
+            }
+        });
+    }
+}
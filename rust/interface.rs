@@ -0,0 +1,402 @@
+use std::{
+    io,
+    os::raw,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc, Weak},
+};
+
+use futures::sink::Sink;
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use super::config::{InterfaceConfig, LinkMode};
+use super::filter;
+use super::lwip::*;
+// lwIP's input for IP netifs, `ip_input` only exists in dual-stack builds.
+#[cfg(all(feature = "ipv4", not(feature = "ipv6")))]
+use super::lwip::ip4_input as netif_ip_input;
+#[cfg(all(feature = "ipv6", not(feature = "ipv4")))]
+use super::lwip::ip6_input as netif_ip_input;
+#[cfg(all(feature = "ipv4", feature = "ipv6"))]
+use super::lwip::ip_input as netif_ip_input;
+use super::nat;
+use super::nat64::{Nat64, NAT64_OVERHEAD};
+#[cfg(feature = "ipv4")]
+use super::output::output_ip4;
+#[cfg(feature = "ipv6")]
+use super::output::output_ip6;
+use super::output::output_link;
+use super::route;
+use super::stack_impl::{wake_senders, INPUT_DROPPED, OUTPUT_DROPPED};
+use super::LWIP_MUTEX;
+use crate::Error;
+
+extern "C" fn ip_init(netif: *mut netif) -> err_t {
+    unsafe {
+        (*netif).name = [b't' as raw::c_char, b'n' as raw::c_char];
+        #[cfg(feature = "ipv4")]
+        {
+            (*netif).output = Some(output_ip4);
+        }
+        #[cfg(feature = "ipv6")]
+        {
+            (*netif).output_ip6 = Some(output_ip6);
+        }
+    }
+    err_enum_t_ERR_OK as err_t
+}
+
+extern "C" fn ethernet_init(netif: *mut netif) -> err_t {
+    unsafe {
+        (*netif).name = [b'e' as raw::c_char, b't' as raw::c_char];
+        #[cfg(feature = "ipv4")]
+        {
+            (*netif).output = Some(etharp_output);
+        }
+        #[cfg(feature = "ipv6")]
+        {
+            (*netif).output_ip6 = Some(ethip6_output);
+        }
+        (*netif).linkoutput = Some(output_link);
+        (*netif).flags |= (NETIF_FLAG_BROADCAST | NETIF_FLAG_ETHARP | NETIF_FLAG_ETHERNET) as u8_t;
+    }
+    err_enum_t_ERR_OK as err_t
+}
+
+/// Adds `netif` with the IPv4 addresses of `config`, which an IPv6-only lwIP has no room for.
+unsafe fn add_netif(
+    netif: *mut netif,
+    config: &InterfaceConfig,
+    state: *mut raw::c_void,
+    init: netif_init_fn,
+    input: netif_input_fn,
+) -> *mut netif {
+    #[cfg(feature = "ipv4")]
+    {
+        let ip: ip4_addr_t = config.ipv4_addr.into();
+        let netmask: ip4_addr_t = config.ipv4_netmask.into();
+        let gw: ip4_addr_t = config.ipv4_gateway.into();
+        netif_add(netif, &ip, &netmask, &gw, state, init, input)
+    }
+    #[cfg(not(feature = "ipv4"))]
+    {
+        let _ = config;
+        netif_add(netif, state, init, input)
+    }
+}
+
+/// Identifies an interface of the stack, see `NetStack::add_interface`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InterfaceId(u8);
+
+impl InterfaceId {
+    /// The id of the lwIP netif index `index`, `None` for `NETIF_NO_INDEX`.
+    pub(crate) fn from_index(index: u8) -> Option<Self> {
+        if index == NETIF_NO_INDEX as u8 {
+            None
+        } else {
+            Some(InterfaceId(index))
+        }
+    }
+
+    /// The interface the packet lwIP is processing came in on, lwip_mutex must be locked.
+    pub(crate) fn current_input() -> Option<Self> {
+        unsafe {
+            let netif = ip_data.current_input_netif;
+            if netif.is_null() {
+                None
+            } else {
+                Self::from_index((*netif).num + 1)
+            }
+        }
+    }
+
+    pub(crate) fn index(&self) -> u8 {
+        self.0
+    }
+}
+
+/// A netif of the stack and the channel its output goes to. The netif state points back
+/// to it, the netif is removed from lwIP when dropped. lwIP's own loopif is left alone.
+pub(crate) struct Interface {
+    netif: usize,
+    link: LinkMode,
+    tx: Sender<Vec<u8>>,
+    nat64: Option<Nat64>,
+}
+
+impl Interface {
+    pub fn new(config: &InterfaceConfig, tx: Sender<Vec<u8>>) -> Result<Arc<Self>, Error> {
+        let (init, input, mac): (netif_init_fn, netif_input_fn, _) = match config.link {
+            LinkMode::Ip => (Some(ip_init), Some(netif_ip_input), None),
+            LinkMode::Ethernet { mac } => (Some(ethernet_init), Some(ethernet_input), Some(mac)),
+        };
+        let nat64 = config.nat64.as_ref().map(Nat64::new).transpose()?;
+        let translate = nat64.as_ref().map_or(false, Nat64::translates);
+        // Translating needs the IPv4 side of the stack.
+        if translate && (config.link != LinkMode::Ip || cfg!(not(feature = "ipv4"))) {
+            return Err(Error::LwIP(err_enum_t_ERR_ARG as err_t));
+        }
+        let mut interface = Arc::new(Interface {
+            netif: 0,
+            link: config.link,
+            tx,
+            nat64,
+        });
+        let _g = LWIP_MUTEX.lock();
+        unsafe {
+            let netif: *mut netif = Box::into_raw(Box::new(std::mem::zeroed()));
+            if let Some(mac) = mac {
+                (*netif).hwaddr = mac;
+                (*netif).hwaddr_len = mac.len() as u8_t;
+            }
+            let state = Arc::as_ptr(&interface) as *mut raw::c_void;
+            let added = add_netif(netif, config, state, init, input);
+            if added.is_null() {
+                drop(Box::from_raw(netif));
+                return Err(Error::LwIP(err_enum_t_ERR_IF as err_t));
+            }
+            // Leaves room for the IPv6 header of translated packets.
+            (*netif).mtu = if translate {
+                config.mtu.saturating_sub(NAT64_OVERHEAD)
+            } else {
+                config.mtu
+            };
+            // Static addresses, duplicate address detection is skipped.
+            #[cfg(feature = "ipv6")]
+            {
+                let mut idx = 0;
+                if mac.is_some() {
+                    netif_create_ip6_linklocal_address(netif, 1);
+                    netif_ip6_addr_set_state(netif, idx, IP6_ADDR_PREFERRED as u8_t);
+                    idx += 1;
+                }
+                if let Some(ip6) = config.ipv6_addr {
+                    let ip6: ip6_addr_t = ip6.into();
+                    netif_ip6_addr_set(netif, idx, &ip6);
+                    netif_ip6_addr_set_state(netif, idx, IP6_ADDR_PREFERRED as u8_t);
+                }
+            }
+            netif_set_up(netif);
+            netif_set_link_up(netif);
+            // Not shared yet.
+            Arc::get_mut(&mut interface).unwrap().netif = netif as usize;
+        }
+        Ok(interface)
+    }
+
+    /// The interface whose netif is `netif`, `None` for netifs not added by the stack,
+    /// lwip_mutex must be locked.
+    pub unsafe fn from_netif<'a>(netif: *mut netif) -> Option<&'a Interface> {
+        ((*netif).state as *const Interface).as_ref()
+    }
+
+    /// The interface of `id`, `None` once it's removed, lwip_mutex must be locked.
+    pub unsafe fn from_id<'a>(id: InterfaceId) -> Option<&'a Interface> {
+        let netif = netif_get_by_index(id.index());
+        if netif.is_null() {
+            None
+        } else {
+            Self::from_netif(netif)
+        }
+    }
+
+    pub fn netif(&self) -> *mut netif {
+        self.netif as *mut netif
+    }
+
+    pub fn id(&self) -> InterfaceId {
+        InterfaceId(unsafe { (*self.netif()).num } + 1)
+    }
+
+    /// Makes this the netif of the traffic no route or flow claims.
+    pub fn set_default(&self) {
+        let _g = LWIP_MUTEX.lock();
+        unsafe { netif_set_default(self.netif()) };
+    }
+
+    /// The offset of the IP packet carried by `pkt`, `None` for Ethernet frames of other
+    /// protocols, e.g. ARP.
+    fn ip_offset(&self, pkt: &[u8]) -> Option<usize> {
+        match self.link {
+            LinkMode::Ip => Some(0),
+            LinkMode::Ethernet { .. } => match pkt.get(12..14)? {
+                [0x08, 0x00] | [0x86, 0xdd] => Some(14),
+                _ => None,
+            },
+        }
+    }
+
+    /// The IP packet carried by `pkt`, see `ip_offset`.
+    pub fn ip_packet<'a>(&self, pkt: &'a [u8]) -> Option<&'a [u8]> {
+        Some(&pkt[self.ip_offset(pkt)?..])
+    }
+
+    pub fn ip_packet_mut<'a>(&self, pkt: &'a mut [u8]) -> Option<&'a mut [u8]> {
+        let offset = self.ip_offset(pkt)?;
+        Some(&mut pkt[offset..])
+    }
+
+    pub fn nat64(&self) -> Option<&Nat64> {
+        self.nat64.as_ref()
+    }
+
+    /// Whether the stream of the interface can take another packet.
+    pub fn has_room(&self) -> bool {
+        self.tx.capacity() > 0
+    }
+
+    pub fn output(&self, pkt: Vec<u8>) {
+        if self.tx.try_send(pkt).is_err() {
+            // log::trace!("try send stack output pkt failed: {}", e);
+            OUTPUT_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Inputs a packet from the device into lwIP, after the filter and the translations.
+    /// Packets lwIP has no pbuf left for are dropped.
+    pub fn input(&self, item: Vec<u8>) -> io::Result<()> {
+        if item.is_empty() {
+            return Ok(());
+        }
+        let mut item = match filter::filter_input(self, item) {
+            Some(item) => item,
+            None => return Ok(()),
+        };
+        if let Some(nat64) = self.nat64() {
+            item = match nat64.translate_input(item) {
+                Some(item) => item,
+                None => return Ok(()),
+            };
+        }
+        nat::translate_input(self, &mut item);
+        unsafe {
+            let _g = LWIP_MUTEX.lock();
+
+            let pbuf = pbuf_alloc(pbuf_layer_PBUF_RAW, item.len() as u16_t, pbuf_type_PBUF_RAM);
+            if pbuf.is_null() {
+                // The packet was already filtered and translated, it can't be retried.
+                log::trace!("pbuf_alloc null alloc, packet dropped");
+                INPUT_DROPPED.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            pbuf_take(
+                pbuf,
+                item.as_ptr() as *const raw::c_void,
+                item.len() as u16_t,
+            );
+
+            let netif = self.netif();
+            if let Some(input_fn) = (*netif).input {
+                let err = input_fn(pbuf, netif);
+                wake_senders();
+                if err == err_enum_t_ERR_OK as err_t {
+                    Ok(())
+                } else {
+                    pbuf_free(pbuf);
+                    Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        format!("input error: {}", err),
+                    ))
+                }
+            } else {
+                pbuf_free(pbuf);
+                Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "input fn not set",
+                ))
+            }
+        }
+    }
+}
+
+impl Drop for Interface {
+    fn drop(&mut self) {
+        let _g = LWIP_MUTEX.lock();
+        route::remove_interface(self.id());
+        unsafe {
+            // Also resets netif_default.
+            netif_remove(self.netif());
+            drop(Box::from_raw(self.netif()));
+        }
+    }
+}
+
+/// An interface added by `NetStack::add_interface`, with its own packet stream and sink
+/// like the stack. The interface is removed from the stack when dropped.
+pub struct InterfaceHandle {
+    interface: Arc<Interface>,
+    rx: Receiver<Vec<u8>>,
+    sink_buf: Option<Vec<u8>>, // We're flushing per item, no need large buffer.
+}
+
+impl InterfaceHandle {
+    pub(crate) fn new(config: &InterfaceConfig) -> Result<Self, Error> {
+        let (tx, rx) = channel(config.stack_buffer_size);
+        Ok(InterfaceHandle {
+            interface: Interface::new(config, tx)?,
+            rx,
+            sink_buf: None,
+        })
+    }
+
+    pub fn id(&self) -> InterfaceId {
+        self.interface.id()
+    }
+
+    pub(crate) fn interface(&self) -> &Interface {
+        &self.interface
+    }
+
+    /// The interface, without keeping it in the stack once the handle is dropped.
+    pub(crate) fn downgrade(&self) -> Weak<Interface> {
+        Arc::downgrade(&self.interface)
+    }
+}
+
+impl Stream for InterfaceHandle {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(pkt)) => {
+                wake_senders();
+                Poll::Ready(Some(Ok(pkt)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for InterfaceHandle {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.sink_buf.is_none() {
+            Poll::Ready(Ok(()))
+        } else {
+            self.poll_flush(cx)
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.sink_buf.replace(item);
+        Ok(())
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        match self.sink_buf.take() {
+            Some(item) => Poll::Ready(self.interface.input(item)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,33 +1,97 @@
 #![doc = include_str!("../README.md")]
 
+mod classify;
+mod config;
+#[cfg(feature = "lwip-debug")]
+mod diag;
+mod event;
+mod filter;
+mod forward;
+mod icmp;
+mod interface;
 mod lwip;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod mutex;
+mod nat;
+mod nat64;
 mod output;
+mod raw_socket;
+mod route;
 mod stack;
 mod stack_impl;
+#[cfg(feature = "stats")]
+mod stats;
 mod tcp_listener;
 mod tcp_listener_impl;
 mod tcp_stream;
 mod tcp_stream_context;
 mod tcp_stream_impl;
+#[cfg(test)]
+mod testing;
+pub mod tuning;
 mod udp;
+mod udp_listener;
+mod unclaimed;
 mod util;
 
 pub(crate) static LWIP_MUTEX: mutex::AtomicMutex = mutex::AtomicMutex::new();
 pub(crate) use mutex::AtomicMutexGuard as LWIPMutexGuard;
 
+pub use classify::{InputAction, InputHandle, InputOutcome, InputRule, InputRules, IpCidr};
+pub use config::{InterfaceConfig, LinkMode, Nat64Config, NetStackConfig};
+pub use event::{ByteCounts, EventStream, StackEvent, TcpCloseReason};
+pub use filter::{Direction, DivertStream, PacketView, TransportHeader, Verdict};
+pub use icmp::{IcmpEcho, IcmpSocket};
+pub use interface::{InterfaceHandle, InterfaceId};
+#[cfg(feature = "metrics")]
+pub use metrics::render_metrics;
+pub use nat::NatMapping;
+pub use raw_socket::{RawPkt, RawSocket};
 pub use stack::NetStack;
+#[cfg(feature = "stats")]
+pub use stats::{ChannelDropStats, MemPoolStats, MemStats, ProtoStats, StackStats};
 pub use tcp_listener::TcpListener;
 pub use tcp_stream::TcpStream;
-pub use {udp::RecvHalf as UdpRecvHalf, udp::SendHalf as UdpSendHalf, udp::UdpPkt, udp::UdpSocket};
+pub use udp_listener::{UdpFlow, UdpListener};
+pub use unclaimed::{UnclaimedPolicy, UnreachableCode};
+pub use {
+    udp::RecvHalf as UdpRecvHalf, udp::SendHalf as UdpSendHalf, udp::UdpDropStats,
+    udp::UdpOverflowPolicy, udp::UdpPkt, udp::UdpSocket,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("LwIP error ({0})")]
     LwIP(i8),
 
+    #[error("invalid CIDR {0:?}")]
+    InvalidCidr(String),
+
+    #[error("unsupported lwIP address type {0}")]
+    InvalidAddrType(u8),
+
+    #[error("the stack is built without the address family of {0}")]
+    UnsupportedFamily(std::net::IpAddr),
+
+    #[error("another netstack is alive in this process")]
+    StackExists,
+
     #[error("AtomicMutexErr {0:?}")]
     AtomicMutexErr(#[from] mutex::AtomicMutexErr),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::LwIP(err) => util::to_io_error_kind(err),
+            Error::InvalidCidr(_) | Error::InvalidAddrType(_) => std::io::ErrorKind::InvalidInput,
+            Error::UnsupportedFamily(_) => std::io::ErrorKind::Unsupported,
+            Error::StackExists => std::io::ErrorKind::AlreadyExists,
+            _ => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e)
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt::Write;
+
+use super::stack::NetStack;
+use super::stats::{MemPoolStats, ProtoStats, StackStats};
+
+/// Renders the stack counters in the Prometheus text exposition format.
+pub fn render_metrics(stack: &NetStack) -> String {
+    render(&stack.stats())
+}
+
+type PoolGauge = fn(&MemPoolStats) -> usize;
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (key, val)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"{}\"", key, escape(val));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn protocols(stats: &StackStats) -> [(&'static str, &ProtoStats); 11] {
+    [
+        ("link", &stats.link),
+        ("etharp", &stats.etharp),
+        ("ip", &stats.ip),
+        ("ip_frag", &stats.ip_frag),
+        ("icmp", &stats.icmp),
+        ("ip6", &stats.ip6),
+        ("ip6_frag", &stats.ip6_frag),
+        ("icmp6", &stats.icmp6),
+        ("nd6", &stats.nd6),
+        ("udp", &stats.udp),
+        ("tcp", &stats.tcp),
+    ]
+}
+
+fn render(stats: &StackStats) -> String {
+    let mut out = String::new();
+    let protocols = protocols(stats);
+
+    let name = "lwip_packets_total";
+    header(&mut out, name, "counter", "Packets handled by lwIP.");
+    for (proto, s) in protocols.iter() {
+        for (direction, value) in [("xmit", s.xmit), ("recv", s.recv), ("fw", s.fw)] {
+            sample(
+                &mut out,
+                name,
+                &[("protocol", proto), ("direction", direction)],
+                value,
+            );
+        }
+    }
+
+    let name = "lwip_dropped_packets_total";
+    header(&mut out, name, "counter", "Packets dropped by lwIP.");
+    for (proto, s) in protocols.iter() {
+        sample(&mut out, name, &[("protocol", proto)], s.drop);
+    }
+
+    let name = "lwip_errors_total";
+    header(&mut out, name, "counter", "Packet errors reported by lwIP.");
+    for (proto, s) in protocols.iter() {
+        let errors = [
+            ("chkerr", s.chkerr),
+            ("lenerr", s.lenerr),
+            ("memerr", s.memerr),
+            ("rterr", s.rterr),
+            ("proterr", s.proterr),
+            ("opterr", s.opterr),
+            ("err", s.err),
+        ];
+        for (kind, value) in errors {
+            sample(
+                &mut out,
+                name,
+                &[("protocol", proto), ("kind", kind)],
+                value,
+            );
+        }
+    }
+
+    let name = "lwip_cache_hits_total";
+    header(&mut out, name, "counter", "Cache hits reported by lwIP.");
+    for (proto, s) in protocols.iter() {
+        sample(&mut out, name, &[("protocol", proto)], s.cachehit);
+    }
+
+    let mem = &stats.mem;
+    let gauges = [
+        ("lwip_mem_avail_bytes", "Size of the lwIP heap.", mem.avail),
+        (
+            "lwip_mem_used_bytes",
+            "Bytes in use on the lwIP heap.",
+            mem.used,
+        ),
+        (
+            "lwip_mem_max_used_bytes",
+            "High-water mark of the lwIP heap.",
+            mem.max,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, "gauge", help);
+        sample(&mut out, name, &[], value as u64);
+    }
+    let name = "lwip_mem_errors_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Failed allocations on the lwIP heap.",
+    );
+    sample(&mut out, name, &[], mem.err);
+    let name = "lwip_mem_illegal_total";
+    header(&mut out, name, "counter", "Illegal frees on the lwIP heap.");
+    sample(&mut out, name, &[], mem.illegal);
+
+    let gauges: [(&str, &str, PoolGauge); 3] = [
+        (
+            "lwip_memp_avail",
+            "Elements in the lwIP memory pool.",
+            |p| p.avail,
+        ),
+        (
+            "lwip_memp_used",
+            "Elements in use in the lwIP memory pool.",
+            |p| p.used,
+        ),
+        (
+            "lwip_memp_max_used",
+            "High-water mark of the lwIP memory pool.",
+            |p| p.max,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, "gauge", help);
+        for pool in stats.memp.iter() {
+            sample(&mut out, name, &[("pool", pool.name)], value(pool) as u64);
+        }
+    }
+    let name = "lwip_memp_errors_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Failed allocations in the lwIP memory pool.",
+    );
+    for pool in stats.memp.iter() {
+        sample(&mut out, name, &[("pool", pool.name)], pool.err);
+    }
+
+    let name = "lwip_netstack_dropped_packets_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Packets dropped because the receiving channel was full.",
+    );
+    sample(
+        &mut out,
+        name,
+        &[("channel", "output")],
+        stats.dropped.output,
+    );
+    sample(&mut out, name, &[("channel", "input")], stats.dropped.input);
+    sample(
+        &mut out,
+        name,
+        &[("channel", "udp_recv")],
+        stats.dropped.udp_recv,
+    );
+
+    let name = "lwip_netstack_dropped_bytes_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Payload bytes dropped because the receiving channel was full.",
+    );
+    sample(
+        &mut out,
+        name,
+        &[("channel", "udp_recv")],
+        stats.dropped.udp_recv_bytes,
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut stats = StackStats::default();
+        stats.tcp.recv = 42;
+        stats.udp.chkerr = 3;
+        stats.mem.max = 1024;
+        stats.memp.push(MemPoolStats {
+            name: "TCP_PCB",
+            avail: 1024,
+            used: 7,
+            ..Default::default()
+        });
+        stats.dropped.input = 2;
+        stats.dropped.udp_recv = 5;
+        stats.dropped.udp_recv_bytes = 512;
+
+        let text = render(&stats);
+        assert!(text.contains("# TYPE lwip_packets_total counter\n"));
+        assert!(text.contains("lwip_packets_total{protocol=\"tcp\",direction=\"recv\"} 42\n"));
+        assert!(text.contains("lwip_errors_total{protocol=\"udp\",kind=\"chkerr\"} 3\n"));
+        assert!(text.contains("lwip_mem_max_used_bytes 1024\n"));
+        assert!(text.contains("lwip_memp_used{pool=\"TCP_PCB\"} 7\n"));
+        assert!(text.contains("lwip_netstack_dropped_packets_total{channel=\"input\"} 2\n"));
+        assert!(text.contains("lwip_netstack_dropped_packets_total{channel=\"udp_recv\"} 5\n"));
+        assert!(text.contains("lwip_netstack_dropped_bytes_total{channel=\"udp_recv\"} 512\n"));
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            let value = line.rsplit(' ').next().unwrap();
+            assert!(value.parse::<u64>().is_ok(), "bad sample: {}", line);
+        }
+    }
+}
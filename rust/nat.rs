@@ -0,0 +1,782 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::raw,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::classify::IpCidr;
+use super::interface::{Interface, InterfaceId};
+use super::lwip::*;
+use super::util;
+use crate::Error;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_ICMP: u8 = 1;
+
+// RFC 5382, RFC 4787 and RFC 5508.
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(7440);
+const TCP_TRANSITORY_TIMEOUT: Duration = Duration::from_secs(240);
+const UDP_TIMEOUT: Duration = Duration::from_secs(300);
+const ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_MAPPINGS: usize = 65536;
+/// Public ports are allocated above the well-known ones.
+const PORT_MIN: u16 = 1024;
+
+/// `(protocol, source, destination)`, the ICMP echo identifier stands in for the port of
+/// the querier and 0 for the one of the responder.
+type FlowKey = (u8, SocketAddr, SocketAddr);
+
+static NAT: Mutex<Option<Nat>> = Mutex::new(None);
+
+/// An entry of the NAT table, see `NetStack::nat_mappings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatMapping {
+    /// 6 for TCP, 17 for UDP and 1 for ICMP echo, whose ports are the identifier.
+    pub protocol: u8,
+    /// The address of the host behind the inside interface.
+    pub inside: SocketAddr,
+    /// The address of the pool the host is seen from on the outside interface.
+    pub public: SocketAddr,
+    pub remote: SocketAddr,
+    /// The time left until the mapping is removed unless traffic refreshes it.
+    pub expires_in: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpState {
+    /// Only a SYN from the inside has been seen.
+    New,
+    Established,
+    /// A FIN or a RST has been seen.
+    Closing,
+}
+
+/// The public address of an inside endpoint, shared by all its flows.
+struct Endpoint {
+    public: SocketAddr,
+    /// The number of flows to each remote host, for address-dependent filtering.
+    remotes: HashMap<IpAddr, usize>,
+}
+
+struct Mapping {
+    public: SocketAddr,
+    expires: Instant,
+    tcp: TcpState,
+}
+
+impl Mapping {
+    /// Refreshes the mapping with a packet of `protocol` and TCP `flags`.
+    fn refresh(&mut self, protocol: u8, flags: u8, inbound: bool, now: Instant) {
+        let timeout = match protocol {
+            IPPROTO_TCP => {
+                if flags & 0x05 != 0 {
+                    self.tcp = TcpState::Closing;
+                } else if inbound && flags & 0x10 != 0 && self.tcp == TcpState::New {
+                    self.tcp = TcpState::Established;
+                }
+                match self.tcp {
+                    TcpState::Established => TCP_ESTABLISHED_TIMEOUT,
+                    _ => TCP_TRANSITORY_TIMEOUT,
+                }
+            }
+            IPPROTO_UDP => UDP_TIMEOUT,
+            _ => ICMP_TIMEOUT,
+        };
+        self.expires = now + timeout;
+    }
+}
+
+struct Nat {
+    inside: InterfaceId,
+    outside: InterfaceId,
+    pool_base: u32,
+    pool_size: u32,
+    next_port: u16,
+    next_sweep: Instant,
+    /// Keyed by the flow as sent by the inside host.
+    outbound: HashMap<FlowKey, Mapping>,
+    /// Keyed by `(protocol, inside)`, whatever the remote, i.e. endpoint-independent
+    /// mapping, see RFC 4787 section 4.1.
+    endpoints: HashMap<(u8, SocketAddr), Endpoint>,
+    /// `(protocol, public)` to the inside endpoint.
+    publics: HashMap<(u8, SocketAddr), SocketAddr>,
+}
+
+impl Nat {
+    /// The pool address of `inside`, a host always gets the same one.
+    fn public_addr(&self, inside: IpAddr) -> IpAddr {
+        let inside = match inside {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => 0,
+        };
+        Ipv4Addr::from(self.pool_base + inside % self.pool_size).into()
+    }
+
+    /// A public address for the inside endpoint of `key` which no other endpoint uses,
+    /// the port of the inside host is kept when possible.
+    fn allocate(&mut self, key: &FlowKey) -> Option<SocketAddr> {
+        let (protocol, inside, _) = *key;
+        let addr = self.public_addr(inside.ip());
+        let taken = |nat: &Self, port| {
+            nat.publics
+                .contains_key(&(protocol, SocketAddr::new(addr, port)))
+        };
+        if inside.port() >= PORT_MIN && !taken(self, inside.port()) {
+            return Some(SocketAddr::new(addr, inside.port()));
+        }
+        for _ in PORT_MIN..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = port.checked_add(1).unwrap_or(PORT_MIN);
+            if !taken(self, port) {
+                return Some(SocketAddr::new(addr, port));
+            }
+        }
+        None
+    }
+
+    /// Adds the flow `key`, from the public address of its inside endpoint, allocating
+    /// one for a new endpoint. Returns false if the table is full.
+    fn open(&mut self, key: FlowKey, now: Instant) -> bool {
+        if self.outbound.len() >= MAX_MAPPINGS {
+            log::trace!("nat table full, packet dropped");
+            return false;
+        }
+        if !self.endpoints.contains_key(&(key.0, key.1)) {
+            let public = match self.allocate(&key) {
+                Some(public) => public,
+                None => return false,
+            };
+            let remotes = HashMap::new();
+            self.endpoints
+                .insert((key.0, key.1), Endpoint { public, remotes });
+            self.publics.insert((key.0, public), key.1);
+        }
+        let endpoint = self.endpoints.get_mut(&(key.0, key.1)).unwrap();
+        *endpoint.remotes.entry(key.2.ip()).or_insert(0) += 1;
+        let public = endpoint.public;
+        self.outbound.insert(
+            key,
+            Mapping {
+                public,
+                expires: now,
+                tcp: TcpState::New,
+            },
+        );
+        true
+    }
+
+    /// Translates the source of a packet forwarded from the inside to the outside
+    /// interface, returns false to drop it.
+    fn translate_output(&mut self, pkt: &mut [u8]) -> bool {
+        if pkt.first().map(|b| b >> 4) != Some(4) || pkt.len() < 20 {
+            // Only IPv4 is translated, anything else would leak the inside addresses.
+            return false;
+        }
+        let now = Instant::now();
+        if let Some(inner_at) = icmp_error_inner(pkt) {
+            // About a packet the inside host received, quoted from remote to inside.
+            let inner = match flow_key(&pkt[inner_at..]) {
+                Some(inner) => inner,
+                None => return false,
+            };
+            let public = match self.endpoints.get(&(inner.0, inner.2)) {
+                Some(endpoint) => endpoint.public,
+                None => return false,
+            };
+            rewrite(pkt, true, public);
+            rewrite(&mut pkt[inner_at..], false, public);
+            fix_icmp_checksum(pkt);
+            return true;
+        }
+        if is_fragment(pkt) {
+            // Non-first fragments have no ports, the pool address is per host.
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(&pkt[12..16]).unwrap());
+            let public = self.public_addr(src.into());
+            rewrite(pkt, true, SocketAddr::new(public, 0));
+            return true;
+        }
+        let hdr_len = ((pkt[0] & 0x0f) as usize) * 4;
+        let key = match flow_key(pkt) {
+            Some(key) if key.0 != IPPROTO_ICMP || pkt[hdr_len] == 8 => key,
+            // Only echo requests of the inside hosts are tracked.
+            _ => return false,
+        };
+        if !self.outbound.contains_key(&key) && !self.open(key, now) {
+            return false;
+        }
+        let mapping = self.outbound.get_mut(&key).unwrap();
+        mapping.refresh(key.0, tcp_flags(pkt), false, now);
+        let public = mapping.public;
+        rewrite(pkt, true, public);
+        true
+    }
+
+    /// Translates the destination of a packet from the outside interface back to the
+    /// inside host, packets of no mapping are left alone. Any port of a remote host the
+    /// inside one sent to gets through, i.e. address-dependent filtering, see RFC 4787
+    /// section 5.
+    fn translate_input(&mut self, pkt: &mut [u8]) {
+        if pkt.first().map(|b| b >> 4) != Some(4) || pkt.len() < 20 {
+            return;
+        }
+        if let Some(inner_at) = icmp_error_inner(pkt) {
+            // About a packet the inside host sent, quoted from public to remote.
+            let inner = match flow_key(&pkt[inner_at..]) {
+                Some(inner) => inner,
+                None => return,
+            };
+            let inside = match self.publics.get(&(inner.0, inner.1)) {
+                Some(inside) => *inside,
+                None => return,
+            };
+            rewrite(pkt, false, inside);
+            rewrite(&mut pkt[inner_at..], true, inside);
+            fix_icmp_checksum(pkt);
+            return;
+        }
+        let key = match flow_key(pkt) {
+            Some(key) => key,
+            None => return,
+        };
+        let (protocol, remote, public) = key;
+        let inside = match self.publics.get(&(protocol, public)) {
+            Some(inside) => *inside,
+            None => return,
+        };
+        let outbound = (protocol, inside, remote);
+        let now = Instant::now();
+        if !self.outbound.contains_key(&outbound) {
+            let known = self
+                .endpoints
+                .get(&(protocol, inside))
+                .map_or(false, |endpoint| {
+                    endpoint.remotes.contains_key(&remote.ip())
+                });
+            if !known || !self.open(outbound, now) {
+                return;
+            }
+        }
+        let mapping = self.outbound.get_mut(&outbound).unwrap();
+        mapping.refresh(protocol, tcp_flags(pkt), true, now);
+        rewrite(pkt, false, inside);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        if now < self.next_sweep {
+            return;
+        }
+        self.next_sweep = now + SWEEP_INTERVAL;
+        let (endpoints, publics) = (&mut self.endpoints, &mut self.publics);
+        self.outbound.retain(|key, mapping| {
+            if mapping.expires > now {
+                return true;
+            }
+            if let Some(endpoint) = endpoints.get_mut(&(key.0, key.1)) {
+                if let Some(flows) = endpoint.remotes.get_mut(&key.2.ip()) {
+                    *flows -= 1;
+                    if *flows == 0 {
+                        endpoint.remotes.remove(&key.2.ip());
+                    }
+                }
+                if endpoint.remotes.is_empty() {
+                    endpoints.remove(&(key.0, key.1));
+                    publics.remove(&(key.0, mapping.public));
+                }
+            }
+            false
+        });
+    }
+}
+
+/// The flow of an IPv4 packet, `None` for other protocols, ICMP messages other than
+/// echo, truncated headers and non-first fragments.
+fn flow_key(pkt: &[u8]) -> Option<FlowKey> {
+    let (src, dst, protocol, payload) = util::split_ip_packet(pkt)?;
+    if is_fragment(pkt) {
+        return None;
+    }
+    let port = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+    let (src_port, dst_port) = match protocol {
+        IPPROTO_TCP | IPPROTO_UDP if payload.len() >= 4 => (port(0), port(2)),
+        IPPROTO_ICMP if payload.len() >= 8 => match payload[0] {
+            8 => (port(4), 0),
+            0 => (0, port(4)),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some((
+        protocol,
+        SocketAddr::new(src, src_port),
+        SocketAddr::new(dst, dst_port),
+    ))
+}
+
+fn is_fragment(pkt: &[u8]) -> bool {
+    u16::from_be_bytes([pkt[6], pkt[7]]) & 0x1fff != 0
+}
+
+fn tcp_flags(pkt: &[u8]) -> u8 {
+    let hdr_len = ((pkt[0] & 0x0f) as usize) * 4;
+    match pkt.get(hdr_len + 13) {
+        Some(flags) if pkt[9] == IPPROTO_TCP => *flags,
+        _ => 0,
+    }
+}
+
+/// The offset of the packet quoted by an ICMP destination unreachable, time exceeded or
+/// parameter problem. The total length must cover the ICMP header and at least the IPv4
+/// header of the quoted packet.
+fn icmp_error_inner(pkt: &[u8]) -> Option<usize> {
+    let (_, _, protocol, icmp) = util::split_ip_packet(pkt)?;
+    if protocol != IPPROTO_ICMP || is_fragment(pkt) || icmp.len() < 8 + 20 {
+        return None;
+    }
+    let hdr_len = ((pkt[0] & 0x0f) as usize) * 4;
+    match icmp[0] {
+        3 | 11 | 12 => Some(hdr_len + 8),
+        _ => None,
+    }
+}
+
+fn adjust_checksum(buf: &mut [u8], at: usize, old: &[u8], new: &[u8]) {
+    let checksum = u16::from_be_bytes([buf[at], buf[at + 1]]);
+    let checksum = util::checksum_adjust(checksum, old, new);
+    buf[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Rewrites the source or destination of an IPv4 packet to `to` and updates the
+/// checksums, the port is the ICMP echo identifier. `pkt` may be a quoted packet
+/// truncated after 8 bytes of payload.
+fn rewrite(pkt: &mut [u8], source: bool, to: SocketAddr) {
+    let new_addr = match to.ip() {
+        IpAddr::V4(ip) => ip.octets(),
+        IpAddr::V6(_) => return,
+    };
+    let addr_at = if source { 12 } else { 16 };
+    let old_addr: [u8; 4] = pkt[addr_at..addr_at + 4].try_into().unwrap();
+    pkt[addr_at..addr_at + 4].copy_from_slice(&new_addr);
+    adjust_checksum(pkt, 10, &old_addr, &new_addr);
+    if is_fragment(pkt) {
+        return;
+    }
+    let protocol = pkt[9];
+    let hdr_len = ((pkt[0] & 0x0f) as usize) * 4;
+    let l4 = match pkt.get_mut(hdr_len..) {
+        Some(l4) => l4,
+        None => return,
+    };
+    let new_port = to.port().to_be_bytes();
+    match protocol {
+        IPPROTO_TCP | IPPROTO_UDP if l4.len() >= 4 => {
+            let port_at = if source { 0 } else { 2 };
+            let old_port = [l4[port_at], l4[port_at + 1]];
+            l4[port_at..port_at + 2].copy_from_slice(&new_port);
+            let checksum_at = if protocol == IPPROTO_TCP { 16 } else { 6 };
+            if l4.len() < checksum_at + 2 {
+                return;
+            }
+            if protocol == IPPROTO_UDP && l4[checksum_at..checksum_at + 2] == [0, 0] {
+                // No checksum.
+                return;
+            }
+            let old = [
+                old_addr[0],
+                old_addr[1],
+                old_addr[2],
+                old_addr[3],
+                old_port[0],
+                old_port[1],
+            ];
+            let new = [
+                new_addr[0],
+                new_addr[1],
+                new_addr[2],
+                new_addr[3],
+                new_port[0],
+                new_port[1],
+            ];
+            adjust_checksum(l4, checksum_at, &old, &new);
+            if protocol == IPPROTO_UDP && l4[checksum_at..checksum_at + 2] == [0, 0] {
+                l4[checksum_at..checksum_at + 2].copy_from_slice(&[0xff, 0xff]);
+            }
+        }
+        IPPROTO_ICMP if l4.len() >= 8 && (l4[0] == 8 || l4[0] == 0) => {
+            let old_id = [l4[4], l4[5]];
+            l4[4..6].copy_from_slice(&new_port);
+            adjust_checksum(l4, 2, &old_id, &new_port);
+        }
+        _ => {}
+    }
+}
+
+/// Recomputes the checksum of an ICMP message whose quoted packet was rewritten, `pkt`
+/// has passed `icmp_error_inner`.
+fn fix_icmp_checksum(pkt: &mut [u8]) {
+    let hdr_len = ((pkt[0] & 0x0f) as usize) * 4;
+    let tot_len = (u16::from_be_bytes([pkt[2], pkt[3]]) as usize).min(pkt.len());
+    let icmp = &mut pkt[hdr_len..tot_len];
+    icmp[2..4].copy_from_slice(&[0, 0]);
+    let checksum = util::inet_checksum(icmp, 0);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Installs the forwarding output hook of lwIP, must run before `lwip_init`.
+pub(crate) fn init() {
+    unsafe { lwip_ip4_forward_output_fn = Some(forward_output_cb) };
+}
+
+pub(crate) fn enable(inside: InterfaceId, outside: InterfaceId, pool: IpCidr) -> Result<(), Error> {
+    let base = match pool.addr() {
+        IpAddr::V4(ip) => u32::from(ip),
+        IpAddr::V6(_) => return Err(Error::InvalidCidr(pool.to_string())),
+    };
+    let size = 1u64 << (32 - pool.prefix_len());
+    // The network and broadcast addresses are left out of pools larger than 2.
+    let (pool_base, pool_size) = if size > 2 {
+        (base + 1, (size - 2) as u32)
+    } else {
+        (base, size as u32)
+    };
+    NAT.lock().unwrap_or_else(|e| e.into_inner()).replace(Nat {
+        inside,
+        outside,
+        pool_base,
+        pool_size,
+        next_port: PORT_MIN,
+        next_sweep: Instant::now(),
+        outbound: HashMap::new(),
+        endpoints: HashMap::new(),
+        publics: HashMap::new(),
+    });
+    Ok(())
+}
+
+/// Stops translating and removes the mappings, called when the netstack goes away.
+pub(crate) fn disable() {
+    NAT.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+pub(crate) fn mappings() -> Vec<NatMapping> {
+    let now = Instant::now();
+    match NAT.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(nat) => nat
+            .outbound
+            .iter()
+            .map(|(key, mapping)| NatMapping {
+                protocol: key.0,
+                inside: key.1,
+                public: mapping.public,
+                remote: key.2,
+                expires_in: mapping.expires.saturating_duration_since(now),
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Removes the expired mappings, called from the timer of the stack.
+pub(crate) fn expire() {
+    if let Some(nat) = NAT.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        nat.expire(Instant::now());
+    }
+}
+
+/// Translates a packet from the device back to the inside host if `interface` is the
+/// outside one, before lwIP sees it.
+pub(crate) fn translate_input(interface: &Interface, pkt: &mut [u8]) {
+    let mut nat = NAT.lock().unwrap_or_else(|e| e.into_inner());
+    let nat = match nat.as_mut() {
+        Some(nat) if nat.outside == interface.id() => nat,
+        _ => return,
+    };
+    if let Some(pkt) = interface.ip_packet_mut(pkt) {
+        nat.translate_input(pkt);
+    }
+}
+
+/// Translates a packet lwIP forwards from `inp` to `netif`, `p` starts at the IP header.
+/// Returns 0 to drop it, lwip_mutex is locked.
+extern "C" fn forward_output_cb(p: *mut pbuf, inp: *mut netif, netif: *mut netif) -> raw::c_int {
+    let mut nat = NAT.lock().unwrap_or_else(|e| e.into_inner());
+    let (nat, from, to) = unsafe {
+        match (
+            nat.as_mut(),
+            Interface::from_netif(inp),
+            Interface::from_netif(netif),
+        ) {
+            (Some(nat), Some(from), Some(to)) => (nat, from.id(), to.id()),
+            _ => return 1,
+        }
+    };
+    if from != nat.inside || to != nat.outside {
+        return 1;
+    }
+    unsafe {
+        let tot_len = std::ptr::read_unaligned(p).tot_len;
+        let mut buf = Vec::with_capacity(tot_len as usize);
+        pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, tot_len, 0);
+        buf.set_len(tot_len as usize);
+        if !nat.translate_output(&mut buf) {
+            return 0;
+        }
+        pbuf_take(p, buf.as_ptr() as *const raw::c_void, tot_len);
+    }
+    1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{self, check};
+
+    const INSIDE: [u8; 4] = [10, 0, 0, 2];
+    const REMOTE: [u8; 4] = [198, 51, 100, 1];
+    const POOL: [u8; 4] = [203, 0, 113, 1];
+
+    fn addr(ip: [u8; 4], port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+    }
+
+    fn tcp(src: SocketAddr, dst: SocketAddr, flags: u8) -> Vec<u8> {
+        testing::tcp(src, dst, flags, b"hello")
+    }
+
+    fn udp(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        testing::udp(src, dst, b"hello")
+    }
+
+    /// An echo request of identifier `id` if `request`, a reply otherwise.
+    fn echo(request: bool, src: [u8; 4], dst: [u8; 4], id: u16) -> Vec<u8> {
+        let (src, dst) = (Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into());
+        testing::echo(request, src, dst, id, 1, b"ping")
+    }
+
+    /// A port unreachable from `src` quoting the first 28 bytes of `quoted`.
+    fn unreachable(src: [u8; 4], dst: [u8; 4], quoted: &[u8]) -> Vec<u8> {
+        let mut l4 = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        l4.extend_from_slice(&quoted[..28]);
+        let (src, dst) = (Ipv4Addr::from(src).into(), Ipv4Addr::from(dst).into());
+        testing::ip(IPPROTO_ICMP, src, dst, l4)
+    }
+
+    fn source(pkt: &[u8]) -> SocketAddr {
+        flow_key(pkt).unwrap().1
+    }
+
+    fn destination(pkt: &[u8]) -> SocketAddr {
+        flow_key(pkt).unwrap().2
+    }
+
+    fn nat() -> Nat {
+        Nat {
+            inside: InterfaceId::from_index(1).unwrap(),
+            outside: InterfaceId::from_index(2).unwrap(),
+            pool_base: u32::from(Ipv4Addr::from(POOL)),
+            pool_size: 1,
+            next_port: PORT_MIN,
+            next_sweep: Instant::now(),
+            outbound: HashMap::new(),
+            endpoints: HashMap::new(),
+            publics: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_tcp_round_trip() {
+        let mut nat = nat();
+        let mut pkt = tcp(addr(INSIDE, 40000), addr(REMOTE, 80), 0x02);
+        assert!(nat.translate_output(&mut pkt));
+        assert_eq!(source(&pkt), addr(POOL, 40000));
+        assert_eq!(destination(&pkt), addr(REMOTE, 80));
+        check(&pkt);
+
+        let mut pkt = tcp(addr(REMOTE, 80), addr(POOL, 40000), 0x12);
+        nat.translate_input(&mut pkt);
+        assert_eq!(source(&pkt), addr(REMOTE, 80));
+        assert_eq!(destination(&pkt), addr(INSIDE, 40000));
+        check(&pkt);
+        let mapping = &nat.outbound[&(IPPROTO_TCP, addr(INSIDE, 40000), addr(REMOTE, 80))];
+        assert_eq!(mapping.tcp, TcpState::Established);
+    }
+
+    #[test]
+    fn test_udp_round_trip() {
+        let mut nat = nat();
+        let mut pkt = udp(addr(INSIDE, 5353), addr(REMOTE, 53));
+        assert!(nat.translate_output(&mut pkt));
+        assert_eq!(source(&pkt), addr(POOL, 5353));
+        check(&pkt);
+
+        let mut pkt = udp(addr(REMOTE, 53), addr(POOL, 5353));
+        nat.translate_input(&mut pkt);
+        assert_eq!(destination(&pkt), addr(INSIDE, 5353));
+        check(&pkt);
+
+        // Without a checksum the packet is left without one.
+        let mut pkt = udp(addr(INSIDE, 5353), addr(REMOTE, 53));
+        pkt[26..28].fill(0);
+        assert!(nat.translate_output(&mut pkt));
+        assert_eq!(source(&pkt), addr(POOL, 5353));
+        assert_eq!(pkt[26..28], [0, 0]);
+        assert_eq!(util::inet_checksum(&pkt[..20], 0), 0);
+    }
+
+    #[test]
+    fn test_echo_round_trip() {
+        let mut nat = nat();
+        // The identifier is below PORT_MIN, so another one is allocated.
+        let mut pkt = echo(true, INSIDE, REMOTE, 7);
+        assert!(nat.translate_output(&mut pkt));
+        assert_eq!(source(&pkt), addr(POOL, PORT_MIN));
+        check(&pkt);
+
+        let mut pkt = echo(false, REMOTE, POOL, PORT_MIN);
+        nat.translate_input(&mut pkt);
+        assert_eq!(destination(&pkt), addr(INSIDE, 7));
+        check(&pkt);
+
+        // Only echo requests of the inside hosts are translated.
+        let mut pkt = echo(false, INSIDE, REMOTE, 7);
+        assert!(!nat.translate_output(&mut pkt));
+    }
+
+    #[test]
+    fn test_icmp_error() {
+        let mut nat = nat();
+        let mut sent = udp(addr(INSIDE, 5353), addr(REMOTE, 53));
+        assert!(nat.translate_output(&mut sent));
+
+        // A router on the path can't reach the remote host.
+        let router = [192, 0, 2, 254];
+        let mut pkt = unreachable(router, POOL, &sent);
+        nat.translate_input(&mut pkt);
+        assert_eq!(&pkt[16..20], &INSIDE);
+        assert_eq!(source(&pkt[28..]), addr(INSIDE, 5353));
+        assert_eq!(destination(&pkt[28..]), addr(REMOTE, 53));
+        check(&pkt);
+        check(&pkt[28..]);
+
+        // The inside host has no listener for the reply.
+        let received = udp(addr(REMOTE, 53), addr(INSIDE, 5353));
+        let mut pkt = unreachable(INSIDE, REMOTE, &received);
+        assert!(nat.translate_output(&mut pkt));
+        assert_eq!(&pkt[12..16], &POOL);
+        assert_eq!(destination(&pkt[28..]), addr(POOL, 5353));
+        check(&pkt);
+        check(&pkt[28..]);
+
+        // About a flow of no mapping.
+        let received = udp(addr(REMOTE, 53), addr(INSIDE, 6000));
+        let mut pkt = unreachable(INSIDE, REMOTE, &received);
+        assert!(!nat.translate_output(&mut pkt));
+    }
+
+    #[test]
+    fn test_truncated_icmp_error() {
+        let mut nat = nat();
+        let mut sent = udp(addr(INSIDE, 5353), addr(REMOTE, 53));
+        assert!(nat.translate_output(&mut sent));
+        let router = [192, 0, 2, 254];
+        let pkt = unreachable(router, POOL, &sent);
+
+        // A total length too short for the ICMP header, or for the quoted header.
+        for tot_len in [0u16, 20, 22, 24, 47] {
+            let mut lying = pkt.clone();
+            lying[2..4].copy_from_slice(&tot_len.to_be_bytes());
+            nat.translate_input(&mut lying);
+            assert_eq!(&lying[16..20], &POOL);
+            assert_eq!(lying[4..], pkt[4..]);
+        }
+
+        // A header length below 20 bytes.
+        let mut lying = pkt.clone();
+        lying[0] = 0x44;
+        nat.translate_input(&mut lying);
+        assert_eq!(lying[1..], pkt[1..]);
+
+        // Truncated after the ICMP header.
+        let mut truncated = pkt[..24].to_vec();
+        nat.translate_input(&mut truncated);
+        assert_eq!(truncated, pkt[..24]);
+        assert!(!nat.translate_output(&mut pkt[..24].to_vec()));
+    }
+
+    #[test]
+    fn test_port_collision() {
+        let mut nat = nat();
+        let other = [10, 0, 0, 3];
+        let mut first = udp(addr(INSIDE, 5000), addr(REMOTE, 53));
+        let mut second = udp(addr(other, 5000), addr(REMOTE, 53));
+        assert!(nat.translate_output(&mut first));
+        assert!(nat.translate_output(&mut second));
+        assert_eq!(source(&first), addr(POOL, 5000));
+        assert_eq!(source(&second), addr(POOL, PORT_MIN));
+        check(&second);
+
+        let mut pkt = udp(addr(REMOTE, 53), addr(POOL, PORT_MIN));
+        nat.translate_input(&mut pkt);
+        assert_eq!(destination(&pkt), addr(other, 5000));
+        let mut pkt = udp(addr(REMOTE, 53), addr(POOL, 5000));
+        nat.translate_input(&mut pkt);
+        assert_eq!(destination(&pkt), addr(INSIDE, 5000));
+    }
+
+    #[test]
+    fn test_endpoint_independent_mapping() {
+        let mut nat = nat();
+        let other = [198, 51, 100, 2];
+        let mut first = udp(addr(INSIDE, 5000), addr(REMOTE, 3478));
+        let mut second = udp(addr(INSIDE, 5000), addr(other, 3478));
+        assert!(nat.translate_output(&mut first));
+        assert!(nat.translate_output(&mut second));
+        assert_eq!(source(&first), source(&second));
+
+        // Another port of a remote host the inside one sent to gets through.
+        let mut pkt = udp(addr(REMOTE, 9999), addr(POOL, 5000));
+        nat.translate_input(&mut pkt);
+        assert_eq!(destination(&pkt), addr(INSIDE, 5000));
+        assert_eq!(nat.outbound.len(), 3);
+
+        // An unknown remote host doesn't.
+        let mut pkt = udp(addr([192, 0, 2, 1], 3478), addr(POOL, 5000));
+        nat.translate_input(&mut pkt);
+        assert_eq!(destination(&pkt), addr(POOL, 5000));
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut nat = nat();
+        let mut pkt = tcp(addr(INSIDE, 40000), addr(REMOTE, 80), 0x02);
+        assert!(nat.translate_output(&mut pkt));
+        let mut pkt = tcp(addr(REMOTE, 80), addr(POOL, 40000), 0x12);
+        nat.translate_input(&mut pkt);
+        let mut pkt = udp(addr(INSIDE, 5353), addr(REMOTE, 53));
+        assert!(nat.translate_output(&mut pkt));
+
+        nat.expire(Instant::now() + UDP_TIMEOUT + Duration::from_secs(1));
+        assert_eq!(nat.outbound.len(), 1);
+        assert_eq!(nat.endpoints.len(), 1);
+        assert_eq!(nat.endpoints.values().next().unwrap().remotes.len(), 1);
+        assert_eq!(nat.publics.len(), 1);
+        let mut pkt = udp(addr(REMOTE, 53), addr(POOL, 5353));
+        nat.translate_input(&mut pkt);
+        assert_eq!(destination(&pkt), addr(POOL, 5353));
+
+        // Swept at most once per SWEEP_INTERVAL.
+        let later = Instant::now() + TCP_ESTABLISHED_TIMEOUT + Duration::from_secs(1);
+        nat.next_sweep = later + SWEEP_INTERVAL;
+        nat.expire(later);
+        assert_eq!(nat.outbound.len(), 1);
+        nat.next_sweep = later;
+        nat.expire(later);
+        assert!(nat.outbound.is_empty());
+        assert!(nat.endpoints.is_empty());
+        assert!(nat.publics.is_empty());
+    }
+}
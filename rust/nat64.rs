@@ -0,0 +1,672 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::Instant,
+};
+
+use super::config::Nat64Config;
+use super::util;
+use crate::Error;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_FRAGMENT: u8 = 44;
+const IPPROTO_ICMPV6: u8 = 58;
+
+/// How much larger a translated IPv4 packet gets at most, the IPv6 header and a fragment
+/// header instead of the IPv4 header.
+pub(crate) const NAT64_OVERHEAD: u16 = 28;
+
+/// The translator of an interface, see `Nat64Config`.
+pub(crate) struct Nat64 {
+    prefix: Ipv6Addr,
+    translate: bool,
+    pool_base: u32,
+    pool_size: u32,
+    state: Mutex<Nat64State>,
+}
+
+#[derive(Default)]
+struct Nat64State {
+    clients: HashMap<Ipv6Addr, Ipv4Addr>,
+    /// The IPv6 source of each pool address and when it was last seen.
+    addrs: HashMap<Ipv4Addr, (Ipv6Addr, Instant)>,
+    next_id: u16,
+}
+
+impl Nat64 {
+    pub fn new(config: &Nat64Config) -> Result<Self, Error> {
+        let base = match config.client_pool.addr() {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => return Err(Error::InvalidCidr(config.client_pool.to_string())),
+        };
+        let size = 1u64 << (32 - config.client_pool.prefix_len());
+        // The network and broadcast addresses are left out of pools larger than 2.
+        let (pool_base, pool_size) = if size > 2 {
+            (base + 1, (size - 2) as u32)
+        } else {
+            (base, size as u32)
+        };
+        Ok(Nat64 {
+            prefix: config.prefix,
+            translate: config.translate,
+            pool_base,
+            pool_size,
+            state: Mutex::new(Nat64State::default()),
+        })
+    }
+
+    pub fn translates(&self) -> bool {
+        self.translate
+    }
+
+    /// `addr` with the IPv4 address embedded in the prefix, if any.
+    pub fn unmap(&self, addr: SocketAddr) -> SocketAddr {
+        util::unmap_nat64(addr, &self.prefix)
+    }
+
+    fn in_prefix(&self, ip: &Ipv6Addr) -> bool {
+        ip.octets()[..12] == self.prefix.octets()[..12]
+    }
+
+    /// Translates an IPv6 packet from the device to the prefix into IPv4, other packets
+    /// are left alone. Returns `None` to drop it.
+    pub fn translate_input(&self, pkt: Vec<u8>) -> Option<Vec<u8>> {
+        if !self.translate || pkt.len() < 40 || pkt[0] >> 4 != 6 {
+            return Some(pkt);
+        }
+        let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&pkt[24..40]).unwrap());
+        if !self.in_prefix(&dst) {
+            return Some(pkt);
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let id = state.next_id;
+        state.next_id = id.wrapping_add(1);
+        let now = Instant::now();
+        let mut map = |ip: Ipv6Addr| {
+            if self.in_prefix(&ip) {
+                Some(Ipv4Addr::from(
+                    <[u8; 4]>::try_from(&ip.octets()[12..]).unwrap(),
+                ))
+            } else {
+                Some(self.client_addr(&mut state, ip, now))
+            }
+        };
+        ip6_to_ip4(&pkt, &mut map, id, false)
+    }
+
+    /// Translates an IPv4 packet from lwIP to a pool address back into IPv6, other
+    /// packets are left alone. Returns `None` to drop it.
+    pub fn translate_output(&self, pkt: Vec<u8>) -> Option<Vec<u8>> {
+        if !self.translate || pkt.len() < 20 || pkt[0] >> 4 != 4 {
+            return Some(pkt);
+        }
+        let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&pkt[16..20]).unwrap());
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.addrs.contains_key(&dst) {
+            return Some(pkt);
+        }
+        let mut map = |ip: Ipv4Addr| match state.addrs.get(&ip) {
+            Some((client, _)) => *client,
+            None => util::map_nat64(ip, &self.prefix),
+        };
+        ip4_to_ip6(&pkt, &mut map, false)
+    }
+
+    /// The pool address of the IPv6 source `client`, the least recently used one is
+    /// reassigned when all are taken.
+    fn client_addr(&self, state: &mut Nat64State, client: Ipv6Addr, now: Instant) -> Ipv4Addr {
+        let addr = match state.clients.get(&client) {
+            Some(addr) => *addr,
+            None => {
+                let free = (0..self.pool_size)
+                    .map(|i| Ipv4Addr::from(self.pool_base + i))
+                    .find(|addr| !state.addrs.contains_key(addr));
+                let addr = match free {
+                    Some(addr) => addr,
+                    None => {
+                        let (addr, (old, _)) = state
+                            .addrs
+                            .iter()
+                            .min_by_key(|(_, (_, seen))| *seen)
+                            .map(|(addr, entry)| (*addr, *entry))
+                            .unwrap();
+                        state.clients.remove(&old);
+                        addr
+                    }
+                };
+                state.clients.insert(client, addr);
+                addr
+            }
+        };
+        state.addrs.insert(addr, (client, now));
+        addr
+    }
+}
+
+/// Moves the checksum at `at` of a TCP or UDP header from the pseudo-header addresses
+/// `old` to `new`, the other fields of both pseudo-headers sum up the same.
+fn adjust_pseudo_checksum(l4: &mut [u8], at: usize, old: &[u8], new: &[u8]) {
+    let mut old_addrs = [0u8; 32];
+    let mut new_addrs = [0u8; 32];
+    old_addrs[..old.len()].copy_from_slice(old);
+    new_addrs[..new.len()].copy_from_slice(new);
+    let checksum = u16::from_be_bytes([l4[at], l4[at + 1]]);
+    let checksum = util::checksum_adjust(checksum, &old_addrs, &new_addrs);
+    l4[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// The offset of the checksum of a TCP or UDP header if `l4` holds it.
+fn checksum_at(protocol: u8, l4: &[u8]) -> Option<usize> {
+    let at = match protocol {
+        IPPROTO_TCP => 16,
+        IPPROTO_UDP => 6,
+        _ => return None,
+    };
+    if l4.len() >= at + 2 {
+        Some(at)
+    } else {
+        None
+    }
+}
+
+/// Translates an IPv6 packet into IPv4, see RFC 7915 section 5. `quoted` packets are the
+/// ones of ICMP errors, truncated and whose lengths are left as they were.
+fn ip6_to_ip4(
+    pkt: &[u8],
+    map: &mut dyn FnMut(Ipv6Addr) -> Option<Ipv4Addr>,
+    id: u16,
+    quoted: bool,
+) -> Option<Vec<u8>> {
+    if pkt.len() < 40 {
+        return None;
+    }
+    let src6: [u8; 16] = pkt[8..24].try_into().unwrap();
+    let dst6: [u8; 16] = pkt[24..40].try_into().unwrap();
+    let src = map(src6.into())?;
+    let dst = map(dst6.into())?;
+    let payload_len = u16::from_be_bytes([pkt[4], pkt[5]]) as usize;
+    let mut payload = &pkt[40..(40 + payload_len).min(pkt.len())];
+    let mut next_header = pkt[6];
+    let mut hdr_len = 0;
+    let (mut id, mut frag_off) = (id, 0u16);
+    if next_header == IPPROTO_FRAGMENT {
+        if payload.len() < 8 {
+            return None;
+        }
+        next_header = payload[0];
+        let offset = u16::from_be_bytes([payload[2], payload[3]]);
+        frag_off = (offset >> 3) | if offset & 1 != 0 { 0x2000 } else { 0 };
+        id = u16::from_be_bytes([payload[6], payload[7]]);
+        payload = &payload[8..];
+        hdr_len = 8;
+    }
+    let first_fragment = frag_off & 0x1fff == 0;
+    let (protocol, l4) = match next_header {
+        IPPROTO_ICMPV6 if first_fragment && frag_off == 0 => {
+            (IPPROTO_ICMP, icmp6_to_icmp4(payload, map, quoted)?)
+        }
+        IPPROTO_ICMPV6 => return None,
+        IPPROTO_TCP | IPPROTO_UDP => {
+            let mut l4 = payload.to_vec();
+            if let Some(at) = checksum_at(next_header, &l4).filter(|_| first_fragment) {
+                let mut new = [0u8; 8];
+                new[..4].copy_from_slice(&src.octets());
+                new[4..].copy_from_slice(&dst.octets());
+                let old = [src6, dst6].concat();
+                adjust_pseudo_checksum(&mut l4, at, &old, &new);
+            }
+            (next_header, l4)
+        }
+        // Hop-by-hop, routing and destination options.
+        0 | 43 | 60 => return None,
+        protocol => (protocol, payload.to_vec()),
+    };
+    let tot_len = if quoted {
+        20 + payload_len.saturating_sub(hdr_len)
+    } else {
+        20 + l4.len()
+    };
+    if frag_off == 0 && tot_len > 1260 {
+        // DF, see RFC 7915 section 5.1.
+        frag_off = 0x4000;
+    }
+    let traffic_class = (pkt[0] << 4) | (pkt[1] >> 4);
+    let mut out = Vec::with_capacity(20 + l4.len());
+    out.extend_from_slice(&[0x45, traffic_class]);
+    out.extend_from_slice(&(tot_len as u16).to_be_bytes());
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&frag_off.to_be_bytes());
+    out.extend_from_slice(&[pkt[7], protocol, 0, 0]);
+    out.extend_from_slice(&src.octets());
+    out.extend_from_slice(&dst.octets());
+    let checksum = util::inet_checksum(&out, 0);
+    out[10..12].copy_from_slice(&checksum.to_be_bytes());
+    out.extend_from_slice(&l4);
+    Some(out)
+}
+
+/// Translates an ICMPv6 message into ICMP, see RFC 7915 section 5.2.
+fn icmp6_to_icmp4(
+    msg: &[u8],
+    map: &mut dyn FnMut(Ipv6Addr) -> Option<Ipv4Addr>,
+    quoted: bool,
+) -> Option<Vec<u8>> {
+    if msg.len() < 8 {
+        return None;
+    }
+    let mut rest: [u8; 4] = msg[4..8].try_into().unwrap();
+    let (icmp_type, code, error) = match (msg[0], msg[1]) {
+        (128, 0) => (8, 0, false),
+        (129, 0) => (0, 0, false),
+        // No route, beyond the scope of the source, address unreachable.
+        (1, 0) | (1, 2) | (1, 3) => (3, 1, true),
+        (1, 1) => (3, 10, true),
+        (1, 4) => (3, 3, true),
+        (2, 0) => {
+            let mtu = u32::from_be_bytes(rest)
+                .saturating_sub(20)
+                .min(u16::MAX as u32) as u16;
+            rest = [0, 0, (mtu >> 8) as u8, mtu as u8];
+            (3, 4, true)
+        }
+        (3, code) => (11, code, true),
+        (4, 1) => (3, 2, true),
+        (4, 0) => {
+            let pointer = match u32::from_be_bytes(rest) {
+                0 => 0,
+                1 => 1,
+                4 | 5 => 2,
+                6 => 9,
+                7 => 8,
+                8..=23 => 12,
+                24..=39 => 16,
+                _ => return None,
+            };
+            rest = [pointer, 0, 0, 0];
+            (12, 0, true)
+        }
+        _ => return None,
+    };
+    let mut out = vec![icmp_type, code, 0, 0];
+    out.extend_from_slice(&rest);
+    if error && !quoted {
+        out.extend_from_slice(&ip6_to_ip4(&msg[8..], map, 0, true)?);
+    } else {
+        out.extend_from_slice(&msg[8..]);
+    }
+    let checksum = util::inet_checksum(&out, 0);
+    out[2..4].copy_from_slice(&checksum.to_be_bytes());
+    Some(out)
+}
+
+/// Translates an IPv4 packet into IPv6, see RFC 7915 section 4. `quoted` packets are the
+/// ones of ICMP errors, truncated and whose lengths are left as they were.
+fn ip4_to_ip6(
+    pkt: &[u8],
+    map: &mut dyn FnMut(Ipv4Addr) -> Ipv6Addr,
+    quoted: bool,
+) -> Option<Vec<u8>> {
+    if pkt.len() < 20 {
+        return None;
+    }
+    let hdr_len = ((pkt[0] & 0x0f) as usize) * 4;
+    let tot_len = u16::from_be_bytes([pkt[2], pkt[3]]) as usize;
+    if hdr_len < 20 || pkt.len() < hdr_len || tot_len < hdr_len {
+        return None;
+    }
+    let payload = &pkt[hdr_len..tot_len.min(pkt.len())];
+    let src4: [u8; 4] = pkt[12..16].try_into().unwrap();
+    let dst4: [u8; 4] = pkt[16..20].try_into().unwrap();
+    let src = map(src4.into());
+    let dst = map(dst4.into());
+    let frag_off = u16::from_be_bytes([pkt[6], pkt[7]]);
+    let offset = frag_off & 0x1fff;
+    let fragmented = offset != 0 || frag_off & 0x2000 != 0;
+    let protocol = pkt[9];
+    let (next_header, l4) = match protocol {
+        IPPROTO_ICMP if !fragmented => (
+            IPPROTO_ICMPV6,
+            icmp4_to_icmp6(payload, map, quoted, &src, &dst)?,
+        ),
+        IPPROTO_ICMP => return None,
+        IPPROTO_TCP | IPPROTO_UDP => {
+            let mut l4 = payload.to_vec();
+            if let Some(at) = checksum_at(protocol, &l4).filter(|_| offset == 0) {
+                if protocol == IPPROTO_UDP && l4[at..at + 2] == [0, 0] {
+                    // Mandatory in IPv6, only a whole datagram can be summed up.
+                    if fragmented || quoted {
+                        return None;
+                    }
+                    let sum = util::ip6_pseudo_sum(&src, &dst, l4.len() as u32, protocol);
+                    let checksum = match util::inet_checksum(&l4, sum) {
+                        0 => 0xffff,
+                        checksum => checksum,
+                    };
+                    l4[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+                } else {
+                    let old = [src4, dst4].concat();
+                    let new = [src.octets(), dst.octets()].concat();
+                    adjust_pseudo_checksum(&mut l4, at, &old, &new);
+                }
+            }
+            (protocol, l4)
+        }
+        protocol => (protocol, payload.to_vec()),
+    };
+    let frag_len = if fragmented { 8 } else { 0 };
+    let payload_len = if quoted {
+        tot_len - hdr_len + frag_len
+    } else {
+        l4.len() + frag_len
+    };
+    let tos = pkt[1];
+    let mut out = Vec::with_capacity(40 + frag_len + l4.len());
+    out.extend_from_slice(&[0x60 | (tos >> 4), tos << 4, 0, 0]);
+    out.extend_from_slice(&(payload_len as u16).to_be_bytes());
+    let first_header = if fragmented {
+        IPPROTO_FRAGMENT
+    } else {
+        next_header
+    };
+    out.extend_from_slice(&[first_header, pkt[8]]);
+    out.extend_from_slice(&src.octets());
+    out.extend_from_slice(&dst.octets());
+    if fragmented {
+        let offset = (offset << 3) | if frag_off & 0x2000 != 0 { 1 } else { 0 };
+        out.extend_from_slice(&[next_header, 0]);
+        out.extend_from_slice(&offset.to_be_bytes());
+        out.extend_from_slice(&[0, 0, pkt[4], pkt[5]]);
+    }
+    out.extend_from_slice(&l4);
+    Some(out)
+}
+
+/// Translates an ICMP message into ICMPv6, see RFC 7915 section 4.2. `src` and `dst` are
+/// the translated addresses of the packet, for the checksum.
+fn icmp4_to_icmp6(
+    msg: &[u8],
+    map: &mut dyn FnMut(Ipv4Addr) -> Ipv6Addr,
+    quoted: bool,
+    src: &Ipv6Addr,
+    dst: &Ipv6Addr,
+) -> Option<Vec<u8>> {
+    if msg.len() < 8 {
+        return None;
+    }
+    let mut rest: [u8; 4] = msg[4..8].try_into().unwrap();
+    let (icmp_type, code, error) = match (msg[0], msg[1]) {
+        (8, 0) => (128, 0, false),
+        (0, 0) => (129, 0, false),
+        (3, 0 | 1 | 5 | 6 | 7 | 8 | 11 | 12) => (1, 0, true),
+        (3, 2) => {
+            // The next header field.
+            rest = [0, 0, 0, 6];
+            (4, 1, true)
+        }
+        (3, 3) => (1, 4, true),
+        (3, 4) => {
+            let mtu = u16::from_be_bytes([rest[2], rest[3]]) as u32 + 20;
+            rest = mtu.max(1280).to_be_bytes();
+            (2, 0, true)
+        }
+        (3, 9 | 10 | 13 | 15) => (1, 1, true),
+        (11, code) => (3, code, true),
+        (12, 0 | 2) => {
+            let pointer: u32 = match rest[0] {
+                0 => 0,
+                1 => 1,
+                2 | 3 => 4,
+                8 => 7,
+                9 => 6,
+                12..=15 => 8,
+                16..=19 => 24,
+                _ => return None,
+            };
+            rest = pointer.to_be_bytes();
+            (4, 0, true)
+        }
+        _ => return None,
+    };
+    let mut out = vec![icmp_type, code, 0, 0];
+    out.extend_from_slice(&rest);
+    if error && !quoted {
+        out.extend_from_slice(&ip4_to_ip6(&msg[8..], map, true)?);
+    } else {
+        out.extend_from_slice(&msg[8..]);
+    }
+    let sum = util::ip6_pseudo_sum(src, dst, out.len() as u32, IPPROTO_ICMPV6);
+    let checksum = util::inet_checksum(&out, sum);
+    out[2..4].copy_from_slice(&checksum.to_be_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{self, check, ip4, ip6};
+
+    const CLIENT: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    const SERVER4: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 1);
+    const POOL4: Ipv4Addr = Ipv4Addr::new(192, 0, 0, 1);
+
+    fn server6() -> Ipv6Addr {
+        util::map_nat64(SERVER4, &Nat64Config::default().prefix)
+    }
+
+    /// `l4` with its TCP, UDP or ICMPv6 checksum set for the IPv6 addresses.
+    fn sum6(protocol: u8, src: Ipv6Addr, dst: Ipv6Addr, l4: Vec<u8>) -> Vec<u8> {
+        testing::sum(protocol, src.into(), dst.into(), l4)
+    }
+
+    /// `l4` with its TCP, UDP or ICMP checksum set for the IPv4 addresses.
+    fn sum4(protocol: u8, src: Ipv4Addr, dst: Ipv4Addr, l4: Vec<u8>) -> Vec<u8> {
+        testing::sum(protocol, src.into(), dst.into(), l4)
+    }
+
+    fn tcp() -> Vec<u8> {
+        testing::tcp_segment(12345, 80, 0x02, b"hello")
+    }
+
+    fn udp(payload: &[u8]) -> Vec<u8> {
+        testing::udp_datagram(12345, 53, payload)
+    }
+
+    fn addrs4(pkt: &[u8]) -> (Ipv4Addr, Ipv4Addr) {
+        let src: [u8; 4] = pkt[12..16].try_into().unwrap();
+        let dst: [u8; 4] = pkt[16..20].try_into().unwrap();
+        (src.into(), dst.into())
+    }
+
+    fn addrs6(pkt: &[u8]) -> (Ipv6Addr, Ipv6Addr) {
+        let src: [u8; 16] = pkt[8..24].try_into().unwrap();
+        let dst: [u8; 16] = pkt[24..40].try_into().unwrap();
+        (src.into(), dst.into())
+    }
+
+    fn nat64() -> Nat64 {
+        Nat64::new(&Nat64Config::default()).unwrap()
+    }
+
+    #[test]
+    fn test_tcp_round_trip() {
+        let nat64 = nat64();
+        let l4 = sum6(IPPROTO_TCP, CLIENT, server6(), tcp());
+        let pkt = nat64
+            .translate_input(ip6(IPPROTO_TCP, CLIENT, server6(), &l4))
+            .unwrap();
+        assert_eq!(addrs4(&pkt), (POOL4, SERVER4));
+        assert_eq!((pkt[8], pkt[9]), (64, IPPROTO_TCP));
+        assert_eq!(&pkt[20..24], &l4[..4]);
+        check(&pkt);
+
+        let l4 = sum4(IPPROTO_TCP, SERVER4, POOL4, tcp());
+        let pkt = nat64
+            .translate_output(ip4(IPPROTO_TCP, SERVER4, POOL4, 0, &l4))
+            .unwrap();
+        assert_eq!(addrs6(&pkt), (server6(), CLIENT));
+        assert_eq!(pkt[6], IPPROTO_TCP);
+        check(&pkt);
+    }
+
+    #[test]
+    fn test_udp_round_trip() {
+        let nat64 = nat64();
+        let l4 = sum6(IPPROTO_UDP, CLIENT, server6(), udp(b"query"));
+        let pkt = nat64
+            .translate_input(ip6(IPPROTO_UDP, CLIENT, server6(), &l4))
+            .unwrap();
+        assert_eq!(addrs4(&pkt), (POOL4, SERVER4));
+        check(&pkt);
+
+        let l4 = sum4(IPPROTO_UDP, SERVER4, POOL4, udp(b"answer"));
+        let pkt = nat64
+            .translate_output(ip4(IPPROTO_UDP, SERVER4, POOL4, 0, &l4))
+            .unwrap();
+        assert_eq!(addrs6(&pkt), (server6(), CLIENT));
+        check(&pkt);
+
+        // The optional IPv4 checksum is mandatory in IPv6.
+        let pkt = nat64
+            .translate_output(ip4(IPPROTO_UDP, SERVER4, POOL4, 0, &udp(b"answer")))
+            .unwrap();
+        assert_ne!(&pkt[46..48], &[0, 0]);
+        check(&pkt);
+        // Unless the datagram is fragmented.
+        let pkt = ip4(IPPROTO_UDP, SERVER4, POOL4, 0x2000, &udp(b"answer"));
+        assert!(nat64.translate_output(pkt).is_none());
+    }
+
+    #[test]
+    fn test_echo_round_trip() {
+        let nat64 = nat64();
+        let echo = testing::echo_message(128, 0x4c57, 1, b"ping");
+        let l4 = sum6(IPPROTO_ICMPV6, CLIENT, server6(), echo);
+        let pkt = nat64
+            .translate_input(ip6(IPPROTO_ICMPV6, CLIENT, server6(), &l4))
+            .unwrap();
+        assert_eq!(addrs4(&pkt), (POOL4, SERVER4));
+        assert_eq!(pkt[9], IPPROTO_ICMP);
+        assert_eq!((pkt[20], pkt[21]), (8, 0));
+        assert_eq!(&pkt[24..], &l4[4..]);
+        check(&pkt);
+
+        let echo = testing::echo_message(0, 0x4c57, 1, b"ping");
+        let l4 = sum4(IPPROTO_ICMP, SERVER4, POOL4, echo);
+        let pkt = nat64
+            .translate_output(ip4(IPPROTO_ICMP, SERVER4, POOL4, 0, &l4))
+            .unwrap();
+        assert_eq!(addrs6(&pkt), (server6(), CLIENT));
+        assert_eq!(pkt[6], IPPROTO_ICMPV6);
+        assert_eq!((pkt[40], pkt[41]), (129, 0));
+        check(&pkt);
+    }
+
+    #[test]
+    fn test_fragments() {
+        let nat64 = nat64();
+        // A first fragment, with the more fragments flag, holding the whole datagram.
+        let l4 = sum6(IPPROTO_UDP, CLIENT, server6(), udp(b"first"));
+        let mut payload = vec![IPPROTO_UDP, 0, 0, 1, 0, 0, 0xab, 0xcd];
+        payload.extend_from_slice(&l4);
+        let pkt = nat64
+            .translate_input(ip6(IPPROTO_FRAGMENT, CLIENT, server6(), &payload))
+            .unwrap();
+        assert_eq!(pkt[9], IPPROTO_UDP);
+        assert_eq!(&pkt[4..6], &[0xab, 0xcd]);
+        assert_eq!(u16::from_be_bytes([pkt[6], pkt[7]]), 0x2000);
+        assert_eq!(pkt.len(), 20 + l4.len());
+        check(&pkt);
+
+        // A last fragment at offset 8, left as it is.
+        let data = b"0123456789";
+        let pkt = nat64
+            .translate_output(ip4(IPPROTO_UDP, SERVER4, POOL4, 1, data))
+            .unwrap();
+        assert_eq!(addrs6(&pkt), (server6(), CLIENT));
+        assert_eq!(pkt[6], IPPROTO_FRAGMENT);
+        assert_eq!(
+            u16::from_be_bytes([pkt[4], pkt[5]]) as usize,
+            8 + data.len()
+        );
+        assert_eq!(&pkt[40..48], &[IPPROTO_UDP, 0, 0, 8, 0, 0, 0x12, 0x34]);
+        assert_eq!(&pkt[48..], data);
+
+        // ICMP can't be reassembled by the translator.
+        let pkt = ip4(
+            IPPROTO_ICMP,
+            SERVER4,
+            POOL4,
+            0x2000,
+            &[0, 0, 0, 0, 0, 0, 0, 0],
+        );
+        assert!(nat64.translate_output(pkt).is_none());
+    }
+
+    #[test]
+    fn test_icmp_errors() {
+        let nat64 = nat64();
+        let l4 = sum6(IPPROTO_UDP, CLIENT, server6(), udp(b"query"));
+        let sent = nat64
+            .translate_input(ip6(IPPROTO_UDP, CLIENT, server6(), &l4))
+            .unwrap();
+
+        // A port unreachable from the server quoting the translated datagram.
+        let mut error = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        error.extend_from_slice(&sent);
+        let l4 = sum4(IPPROTO_ICMP, SERVER4, POOL4, error);
+        let pkt = nat64
+            .translate_output(ip4(IPPROTO_ICMP, SERVER4, POOL4, 0, &l4))
+            .unwrap();
+        assert_eq!(addrs6(&pkt), (server6(), CLIENT));
+        assert_eq!((pkt[40], pkt[41]), (1, 4));
+        let quoted = &pkt[48..];
+        assert_eq!(addrs6(quoted), (CLIENT, server6()));
+        assert_eq!(quoted[6], IPPROTO_UDP);
+        check(&pkt);
+
+        // Quotes cut in the transport header are still translated.
+        let mut error = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        error.extend_from_slice(&sent[..24]);
+        let l4 = sum4(IPPROTO_ICMP, SERVER4, POOL4, error);
+        let pkt = nat64
+            .translate_output(ip4(IPPROTO_ICMP, SERVER4, POOL4, 0, &l4))
+            .unwrap();
+        assert_eq!(pkt.len(), 48 + 40 + 4);
+        check(&pkt);
+
+        // Quotes too short for an IP header are dropped.
+        for len in [0, 2, 19] {
+            let mut error = vec![3, 3, 0, 0, 0, 0, 0, 0];
+            error.extend_from_slice(&sent[..len]);
+            let l4 = sum4(IPPROTO_ICMP, SERVER4, POOL4, error);
+            let pkt = ip4(IPPROTO_ICMP, SERVER4, POOL4, 0, &l4);
+            assert!(nat64.translate_output(pkt).is_none());
+        }
+
+        // A packet too big from the client quoting a reply of the server.
+        let reply = ip6(IPPROTO_UDP, server6(), CLIENT, &udp(b"answer"));
+        let mut error = vec![2, 0, 0, 0, 0, 0, 0x05, 0x78];
+        error.extend_from_slice(&reply);
+        let l4 = sum6(IPPROTO_ICMPV6, CLIENT, server6(), error);
+        let pkt = nat64
+            .translate_input(ip6(IPPROTO_ICMPV6, CLIENT, server6(), &l4))
+            .unwrap();
+        assert_eq!(addrs4(&pkt), (POOL4, SERVER4));
+        assert_eq!((pkt[20], pkt[21]), (3, 4));
+        assert_eq!(u16::from_be_bytes([pkt[26], pkt[27]]), 1400 - 20);
+        let quoted = &pkt[28..];
+        assert_eq!(addrs4(quoted), (SERVER4, POOL4));
+        assert_eq!(util::inet_checksum(&quoted[..20], 0), 0);
+        check(&pkt);
+
+        // Quotes too short for an IPv6 header are dropped.
+        let mut error = vec![2, 0, 0, 0, 0, 0, 0x05, 0x78];
+        error.extend_from_slice(&reply[..39]);
+        let l4 = sum6(IPPROTO_ICMPV6, CLIENT, server6(), error);
+        let pkt = ip6(IPPROTO_ICMPV6, CLIENT, server6(), &l4);
+        assert!(nat64.translate_input(pkt).is_none());
+    }
+}
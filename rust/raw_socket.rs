@@ -0,0 +1,303 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::IpAddr,
+    os::raw,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use futures::future::poll_fn;
+use futures::stream::Stream;
+use futures::task::{Context, Poll, Waker};
+use log::{error, warn};
+
+use super::lwip::*;
+use super::route;
+use super::stack::NetStack;
+use super::stack_impl;
+use super::util;
+use super::LWIP_MUTEX;
+use crate::Error;
+
+const RAW_QUEUE_SIZE: usize = 64;
+
+/// `(payload, src_addr, dst_addr)`, the payload follows the IP header.
+pub type RawPkt = (Vec<u8>, IpAddr, IpAddr);
+
+struct RawQueue {
+    pkts: VecDeque<RawPkt>,
+    waker: Option<Waker>,
+}
+
+pub extern "C" fn raw_recv_cb(
+    arg: *mut raw::c_void,
+    _pcb: *mut raw_pcb,
+    p: *mut pbuf,
+    _addr: *const ip_addr_t,
+) -> u8_t {
+    // SAFETY: the callback is removed, with lwip_mutex locked, before the socket is dropped.
+    let socket = unsafe { &*(arg as *const RawSocket) };
+    let tot_len = unsafe { std::ptr::read_unaligned(p).tot_len };
+    let mut buf = vec![0u8; tot_len as usize];
+    unsafe { pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, tot_len, 0) };
+    let pkt = match util::split_ip_packet(&buf) {
+        Some((src_addr, dst_addr, _, payload)) => (payload.to_vec(), src_addr, dst_addr),
+        None => return 0,
+    };
+    let mut queue = socket.queue();
+    if queue.pkts.len() < RAW_QUEUE_SIZE {
+        queue.pkts.push_back(pkt);
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+    } else {
+        warn!("raw socket full, packet dropped");
+    }
+    if socket.copy.load(Ordering::Relaxed) {
+        return 0;
+    }
+    unsafe { pbuf_free(p) };
+    1
+}
+
+/// Receives the packets of one IP protocol, e.g. GRE or ESP, that lwIP would otherwise
+/// drop, and sends packets of that protocol to the device.
+pub struct RawSocket {
+    pcb: usize,
+    protocol: u8,
+    copy: AtomicBool,
+    queue: Mutex<RawQueue>,
+}
+
+impl RawSocket {
+    /// Binds a socket for `protocol` to `addr`, an unspecified address receives the
+    /// packets sent to any address of its family.
+    pub fn bind(_stack: &NetStack, protocol: u8, addr: IpAddr) -> Result<Box<Self>, Error> {
+        let ip = util::to_ip_addr_t(addr)?;
+        let _g = LWIP_MUTEX.lock();
+        unsafe {
+            let ip_type = match addr {
+                IpAddr::V4(_) => lwip_ip_addr_type_IPADDR_TYPE_V4,
+                IpAddr::V6(_) => lwip_ip_addr_type_IPADDR_TYPE_V6,
+            };
+            let pcb = raw_new_ip_type(ip_type as u8_t, protocol);
+            if pcb.is_null() {
+                return Err(Error::LwIP(err_enum_t_ERR_MEM as err_t));
+            }
+            let err = raw_bind(pcb, &ip);
+            if err != err_enum_t_ERR_OK as err_t {
+                error!("bind raw failed: {}", err);
+                raw_remove(pcb);
+                return Err(Error::LwIP(err));
+            }
+            let socket = Box::new(RawSocket {
+                pcb: pcb as usize,
+                protocol,
+                copy: AtomicBool::new(false),
+                queue: Mutex::new(RawQueue {
+                    pkts: VecDeque::new(),
+                    waker: None,
+                }),
+            });
+            let arg = &*socket as *const RawSocket as *mut raw::c_void;
+            raw_recv(pcb, Some(raw_recv_cb), arg);
+            Ok(socket)
+        }
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    /// Receives a copy of the packets and leaves them to lwIP as well, off by default.
+    pub fn set_copy(&self, copy: bool) {
+        self.copy.store(copy, Ordering::Relaxed);
+    }
+
+    fn queue(&self) -> std::sync::MutexGuard<'_, RawQueue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub async fn recv_from(&mut self) -> io::Result<RawPkt> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "raw socket closed"))
+    }
+
+    /// Sends `data` as the payload of an IP packet from `src_addr` to the device at
+    /// `dst_addr`, lwIP adds the IP header.
+    pub async fn send_to(
+        &self,
+        data: &[u8],
+        src_addr: &IpAddr,
+        dst_addr: &IpAddr,
+    ) -> io::Result<()> {
+        poll_fn(|cx| {
+            let _g = LWIP_MUTEX.lock();
+            poll_send_raw(cx, src_addr, dst_addr, self.pcb, data)
+        })
+        .await
+    }
+}
+
+/// Sends `data` over a raw pcb, lwip_mutex must be locked.
+pub(crate) fn poll_send_raw(
+    cx: &mut Context,
+    src_addr: &IpAddr,
+    dst_addr: &IpAddr,
+    pcb: usize,
+    data: &[u8],
+) -> Poll<io::Result<()>> {
+    let (src_ip, dst_ip) = match (util::to_ip_addr_t(*src_addr), util::to_ip_addr_t(*dst_addr)) {
+        (Ok(src_ip), Ok(dst_ip)) => (src_ip, dst_ip),
+        (Err(e), _) | (_, Err(e)) => return Poll::Ready(Err(e.into())),
+    };
+    let netif = route::find(src_addr, dst_addr);
+    if netif.is_null() {
+        return Poll::Ready(Err(Error::LwIP(err_enum_t_ERR_RTE as err_t).into()));
+    }
+    if !stack_impl::egress_has_room(netif) {
+        stack_impl::park_sender(cx.waker());
+        // The stream may have been drained before we were parked.
+        if !stack_impl::egress_has_room(netif) {
+            return Poll::Pending;
+        }
+    }
+    let err = unsafe {
+        let pbuf = pbuf_alloc(pbuf_layer_PBUF_IP, data.len() as u16_t, pbuf_type_PBUF_RAM);
+        if pbuf.is_null() {
+            err_enum_t_ERR_MEM as err_t
+        } else {
+            pbuf_take(pbuf, data.as_ptr() as *const _, data.len() as u16_t);
+            let err = raw_sendto_if_src(pcb as *mut raw_pcb, pbuf, &dst_ip, netif, &src_ip);
+            pbuf_free(pbuf);
+            err
+        }
+    };
+    if err == err_enum_t_ERR_OK as err_t {
+        Poll::Ready(Ok(()))
+    } else if err == err_enum_t_ERR_MEM as err_t {
+        stack_impl::park_sender(cx.waker());
+        Poll::Pending
+    } else {
+        Poll::Ready(Err(Error::LwIP(err).into()))
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        let _g = LWIP_MUTEX.lock();
+        unsafe {
+            raw_recv(self.pcb as *mut raw_pcb, None, std::ptr::null_mut());
+            raw_remove(self.pcb as *mut raw_pcb);
+        }
+    }
+}
+
+impl Stream for RawSocket {
+    type Item = RawPkt;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut queue = self.queue();
+        match queue.pkts.pop_front() {
+            Some(pkt) => Poll::Ready(Some(pkt)),
+            None => {
+                queue.waker.replace(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{self, timeout};
+    use crate::NetStackConfig;
+    use futures::{SinkExt, StreamExt};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const IPPROTO_GRE: u8 = 47;
+
+    /// The next packet of `protocol` out of the stack, skipping the neighbor discovery and
+    /// the like.
+    async fn next_packet(stack: &mut NetStack, protocol: u8) -> Vec<u8> {
+        loop {
+            let pkt = stack.next().await.unwrap().unwrap();
+            if matches!(util::split_ip_packet(&pkt), Some((_, _, proto, _)) if proto == protocol) {
+                return pkt;
+            }
+        }
+    }
+
+    #[test]
+    fn test_raw_socket() {
+        let _stack = testing::lock_stack();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (mut stack, _tcp_listener, _udp_socket) = NetStack::with_config(NetStackConfig {
+                ipv4_addr: [10, 0, 0, 1].into(),
+                ipv4_netmask: [255, 255, 255, 0].into(),
+                ipv6_addr: Some("fd00::1".parse().unwrap()),
+                ..Default::default()
+            })
+            .unwrap();
+            // The device, a remote address and the netif one, for each family built.
+            let mut addrs: Vec<(IpAddr, IpAddr, IpAddr, u8)> = Vec::new();
+            if cfg!(feature = "ipv4") {
+                let (device, remote) = ([10, 0, 0, 2].into(), [192, 0, 2, 1].into());
+                addrs.push((device, remote, [10, 0, 0, 1].into(), testing::IPPROTO_ICMP));
+            }
+            if cfg!(feature = "ipv6") {
+                let (device, remote) = ("fd00::2".parse().unwrap(), "2001:db8::1".parse().unwrap());
+                let netif = "fd00::1".parse().unwrap();
+                addrs.push((device, remote, netif, testing::IPPROTO_ICMPV6));
+            }
+            for (device, remote, netif, icmp) in addrs {
+                let unspecified: IpAddr = match remote {
+                    IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                    IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                };
+                let mut gre = RawSocket::bind(&stack, IPPROTO_GRE, unspecified).unwrap();
+                assert_eq!(gre.protocol(), IPPROTO_GRE);
+                let payload = [0, 0, 0x08, 0, 1, 2, 3, 4];
+                let pkt = testing::ip(IPPROTO_GRE, device, remote, payload.to_vec());
+                timeout(stack.send(pkt)).await.unwrap();
+                let received = timeout(gre.recv_from()).await.unwrap();
+                assert_eq!(received, (payload.to_vec(), device, remote));
+
+                // Sent from the remote address, lwIP adds the header.
+                timeout(gre.send_to(&payload, &remote, &device))
+                    .await
+                    .unwrap();
+                let pkt = timeout(next_packet(&mut stack, IPPROTO_GRE)).await;
+                let sent = util::split_ip_packet(&pkt).unwrap();
+                assert_eq!(sent, (remote, device, IPPROTO_GRE, &payload[..]));
+
+                // lwIP only answers the echo requests the socket copies.
+                let mut socket = RawSocket::bind(&stack, icmp, unspecified).unwrap();
+                for (id, copy) in [(1, false), (2, true)] {
+                    socket.set_copy(copy);
+                    let request = testing::echo(true, device, netif, id, 1, b"abc");
+                    timeout(stack.send(request.clone())).await.unwrap();
+                    let received = timeout(socket.recv_from()).await.unwrap();
+                    let (_, _, _, msg) = util::split_ip_packet(&request).unwrap();
+                    assert_eq!(received, (msg.to_vec(), device, netif));
+                }
+                let reply = timeout(next_packet(&mut stack, icmp)).await;
+                testing::check(&reply);
+                let (src, dst, _, msg) = util::split_ip_packet(&reply).unwrap();
+                assert_eq!((src, dst), (netif, device));
+                assert_eq!(u16::from_be_bytes([msg[4], msg[5]]), 2);
+            }
+        });
+    }
+}
@@ -0,0 +1,107 @@
+#[cfg(feature = "ipv4")]
+use std::net::Ipv4Addr;
+#[cfg(feature = "ipv6")]
+use std::net::Ipv6Addr;
+use std::{net::IpAddr, sync::RwLock};
+
+use super::classify::IpCidr;
+use super::interface::InterfaceId;
+use super::lwip::*;
+use super::util;
+
+/// `(destination, netif index)`, looked up by longest prefix.
+static ROUTES: RwLock<Vec<(IpCidr, u8)>> = RwLock::new(Vec::new());
+
+/// Installs the routing hooks of lwIP, must run before `lwip_init`.
+pub(crate) fn init() {
+    unsafe {
+        #[cfg(feature = "ipv4")]
+        {
+            lwip_ip4_route_fn = Some(ip4_route_cb);
+        }
+        #[cfg(feature = "ipv6")]
+        {
+            lwip_ip6_route_fn = Some(ip6_route_cb);
+        }
+    }
+}
+
+/// Routes `net` to the netif `interface`, replacing the previous route for `net`.
+pub(crate) fn add(net: IpCidr, interface: InterfaceId) {
+    let mut routes = ROUTES.write().unwrap_or_else(|e| e.into_inner());
+    routes.retain(|(route, _)| *route != net);
+    routes.push((net, interface.index()));
+}
+
+pub(crate) fn remove(net: &IpCidr) -> bool {
+    let mut routes = ROUTES.write().unwrap_or_else(|e| e.into_inner());
+    let len = routes.len();
+    routes.retain(|(route, _)| route != net);
+    routes.len() != len
+}
+
+/// Removes the routes to `interface`, called when it goes away.
+pub(crate) fn remove_interface(interface: InterfaceId) {
+    ROUTES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|(_, index)| *index != interface.index());
+}
+
+pub(crate) fn clear() {
+    ROUTES.write().unwrap_or_else(|e| e.into_inner()).clear();
+}
+
+/// The netif lwIP sends from `src_addr` to `dst_addr` on, null if there is none,
+/// lwip_mutex must be locked.
+pub(crate) fn find(src_addr: &IpAddr, dst_addr: &IpAddr) -> *mut netif {
+    let (src_ip, dst_ip) = match (util::to_ip_addr_t(*src_addr), util::to_ip_addr_t(*dst_addr)) {
+        (Ok(src_ip), Ok(dst_ip)) => (src_ip, dst_ip),
+        _ => return std::ptr::null_mut(),
+    };
+    unsafe {
+        #[allow(unreachable_patterns)]
+        match (src_addr, dst_addr) {
+            #[cfg(feature = "ipv4")]
+            (IpAddr::V4(_), IpAddr::V4(_)) => {
+                ip4_route_src(util::ip_2_ip4(&src_ip), util::ip_2_ip4(&dst_ip))
+            }
+            #[cfg(feature = "ipv6")]
+            (IpAddr::V6(_), IpAddr::V6(_)) => {
+                ip6_route(util::ip_2_ip6(&src_ip), util::ip_2_ip6(&dst_ip))
+            }
+            _ => std::ptr::null_mut(),
+        }
+    }
+}
+
+/// The netif of the longest route containing `dest`, null if there is none, lwip_mutex
+/// is locked.
+fn lookup(dest: IpAddr) -> *mut netif {
+    let routes = ROUTES.read().unwrap_or_else(|e| e.into_inner());
+    match routes
+        .iter()
+        .filter(|(net, _)| net.contains(&dest))
+        .max_by_key(|(net, _)| net.prefix_len())
+    {
+        Some((_, index)) => unsafe { netif_get_by_index(*index) },
+        None => std::ptr::null_mut(),
+    }
+}
+
+// Routes only depend on the destination, the source is ignored.
+
+#[cfg(feature = "ipv4")]
+extern "C" fn ip4_route_cb(_src: *const ip4_addr, dest: *const ip4_addr) -> *mut netif {
+    let dest = Ipv4Addr::from(unsafe { (*dest).addr }.to_ne_bytes());
+    lookup(dest.into())
+}
+
+#[cfg(feature = "ipv6")]
+extern "C" fn ip6_route_cb(_src: *const ip6_addr, dest: *const ip6_addr) -> *mut netif {
+    let mut octets = [0u8; 16];
+    for (i, word) in unsafe { (*dest).addr }.iter().enumerate() {
+        octets[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+    }
+    lookup(Ipv6Addr::from(octets).into())
+}
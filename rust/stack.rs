@@ -1,35 +1,222 @@
-use std::{io, pin::Pin};
+use std::{io, net::IpAddr, pin::Pin, sync::Arc, time::Duration};
 
+use futures::future::Future;
 use futures::sink::Sink;
 use futures::stream::Stream;
 use futures::task::{Context, Poll};
 
+use super::classify::{InputHandle, InputOutcome, InputRules, IpCidr};
+use super::config::{InterfaceConfig, NetStackConfig};
+use super::event::{self, EventStream};
+use super::filter::{self, Direction, DivertStream, PacketView, Verdict};
+use super::forward;
+use super::icmp;
+use super::interface::{InterfaceHandle, InterfaceId};
+use super::nat::{self, NatMapping};
+use super::route;
 use super::stack_impl::NetStackImpl;
+#[cfg(feature = "stats")]
+use super::stats::{self, StackStats};
 use super::tcp_listener::TcpListener;
 use super::udp::UdpSocket;
+use super::unclaimed::{self, UnclaimedPolicy};
 use crate::Error;
 
+/// There is one stack per process: lwIP and the routes, filters, NAT and event subscribers
+/// are global and reset when the stack is dropped, so creating a stack fails with
+/// `Error::StackExists` while another one is alive.
 pub struct NetStack(Box<NetStackImpl>);
 
 impl NetStack {
     pub fn new() -> Result<(Self, TcpListener, Box<UdpSocket>), Error> {
-        Ok((
-            NetStack(NetStackImpl::new(512)),
-            TcpListener::new()?,
-            UdpSocket::new(64)?,
-        ))
+        Self::with_config(NetStackConfig::default())
     }
 
     pub fn with_buffer_size(
         stack_buffer_size: usize,
         udp_buffer_size: usize,
+    ) -> Result<(Self, TcpListener, Box<UdpSocket>), Error> {
+        Self::with_config(NetStackConfig {
+            stack_buffer_size,
+            udp_buffer_size,
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(
+        config: NetStackConfig,
     ) -> Result<(Self, TcpListener, Box<UdpSocket>), Error> {
         Ok((
-            NetStack(NetStackImpl::new(stack_buffer_size)),
+            NetStack(NetStackImpl::new(&config)?),
             TcpListener::new()?,
-            UdpSocket::new(udp_buffer_size)?,
+            UdpSocket::new(config.udp_buffer_size)?,
         ))
     }
+
+    /// Adds an interface, e.g. for another TUN device, with its own packet stream and
+    /// sink. Accepted connections and UDP flows are answered on the interface they came
+    /// in on, other traffic follows the routes and falls back to the interface of the
+    /// stack.
+    pub fn add_interface(&self, config: InterfaceConfig) -> Result<InterfaceHandle, Error> {
+        InterfaceHandle::new(&config)
+    }
+
+    /// The size of the queue of the UDP sockets bound in the stack.
+    pub(crate) fn udp_buffer_size(&self) -> usize {
+        self.0.udp_buffer_size()
+    }
+
+    /// The id of the interface the stack stream and sink belong to.
+    pub fn interface_id(&self) -> InterfaceId {
+        self.0.interface().id()
+    }
+
+    /// Routes the traffic to `net` which isn't pinned to an interface, e.g. pings and
+    /// replies to packets lwIP answers itself, to `interface`. The longest matching route
+    /// wins, a route for `net` is replaced. Routes to an interface go away with it.
+    pub fn add_route(&self, net: IpCidr, interface: InterfaceId) {
+        route::add(net, interface)
+    }
+
+    /// Returns whether there was a route for `net`.
+    pub fn remove_route(&self, net: &IpCidr) -> bool {
+        route::remove(net)
+    }
+
+    /// Sets the predicate the packets forwarded in router mode go through, with the
+    /// interfaces they come from and go to, replacing the previous one. Denied packets
+    /// are answered with an ICMP/ICMPv6 administratively prohibited. It runs inside lwIP
+    /// and must not call into the stack.
+    pub fn set_forward_filter<F>(&self, filter: F)
+    where
+        F: Fn(&PacketView, InterfaceId, InterfaceId) -> bool + Send + Sync + 'static,
+    {
+        forward::set_filter(Some(Arc::new(filter)))
+    }
+
+    /// Removes the forward filter, every routed packet is forwarded.
+    pub fn clear_forward_filter(&self) {
+        forward::set_filter(None)
+    }
+
+    /// Masquerades the TCP, UDP and ICMP echo traffic forwarded from `inside` to
+    /// `outside` behind the IPv4 addresses of `pool`, replacing the previous NAT. Each
+    /// inside host is mapped to one pool address, the replies are translated back before
+    /// lwIP forwards them. An inside address and port keeps its public port whatever the
+    /// remote, and any port of the remotes it sent to may answer, as STUN and peer-to-peer
+    /// applications expect, see RFC 4787. Needs `NetStackConfig::ip_forward` and routes both ways, e.g.
+    /// `0.0.0.0/0` to `outside` and the inside network to `inside`. Other forwarded IPv4
+    /// traffic is dropped, IPv6 isn't translated.
+    pub fn enable_nat(
+        &self,
+        inside: InterfaceId,
+        outside: InterfaceId,
+        pool: IpCidr,
+    ) -> Result<(), Error> {
+        nat::enable(inside, outside, pool)
+    }
+
+    /// Stops translating and removes the mappings.
+    pub fn disable_nat(&self) {
+        nat::disable()
+    }
+
+    /// A snapshot of the NAT table.
+    pub fn nat_mappings(&self) -> Vec<NatMapping> {
+        nat::mappings()
+    }
+
+    /// Subscribes to flow lifecycle events, each call returns an independent stream.
+    pub fn events(&self) -> EventStream {
+        event::subscribe()
+    }
+
+    /// Sets the rules `classify_and_input` routes packets by, `None` inputs every packet.
+    pub fn set_input_rules(&self, rules: Option<InputRules>) {
+        self.0.input().set_rules(rules)
+    }
+
+    /// Inputs `pkt` into lwIP like the sink does, unless the input rules bypass it, in
+    /// which case it's handed back untouched.
+    pub fn classify_and_input(&self, pkt: Vec<u8>) -> io::Result<InputOutcome> {
+        self.0.input().classify_and_input(pkt)
+    }
+
+    /// A handle to `classify_and_input` which stays usable once the stack is split.
+    pub fn input_handle(&self) -> InputHandle {
+        self.0.input().clone()
+    }
+
+    /// Sets the filter every packet from the device goes through before lwIP sees it,
+    /// replacing the previous one. Packets which can't be parsed bypass the filter.
+    pub fn set_input_filter<F>(&self, filter: F)
+    where
+        F: Fn(&PacketView) -> Verdict + Send + Sync + 'static,
+    {
+        filter::set_filter(Direction::Ingress, Some(Arc::new(filter)))
+    }
+
+    /// Sets the filter every packet from lwIP goes through before it reaches the stack
+    /// stream. It runs inside lwIP and must not call into the stack, e.g. send on a socket.
+    pub fn set_output_filter<F>(&self, filter: F)
+    where
+        F: Fn(&PacketView) -> Verdict + Send + Sync + 'static,
+    {
+        filter::set_filter(Direction::Egress, Some(Arc::new(filter)))
+    }
+
+    /// Removes the input and output filters.
+    pub fn clear_filters(&self) {
+        filter::set_filter(Direction::Ingress, None);
+        filter::set_filter(Direction::Egress, None);
+    }
+
+    /// Returns the stream of the packets diverted by the filters, replacing the previous
+    /// one. Diverted packets are dropped while there is no stream.
+    pub fn divert_stream(&self) -> DivertStream {
+        filter::divert()
+    }
+
+    /// Pings `dst` through the netif, from the netif address, and resolves to the
+    /// round-trip time. The device must route the reply back into the stack. The future
+    /// doesn't borrow the stack, so that it can be spawned.
+    pub fn ping(
+        &self,
+        dst: IpAddr,
+        payload_len: usize,
+        timeout: Duration,
+    ) -> impl Future<Output = io::Result<Duration>> + Send + 'static {
+        icmp::ping(None, dst, payload_len, timeout)
+    }
+
+    /// Like `ping`, from `src`.
+    pub fn ping_from(
+        &self,
+        src: IpAddr,
+        dst: IpAddr,
+        payload_len: usize,
+        timeout: Duration,
+    ) -> impl Future<Output = io::Result<Duration>> + Send + 'static {
+        icmp::ping(Some(src), dst, payload_len, timeout)
+    }
+
+    /// Sets how TCP segments no listener or connection claims are answered, `Reset` by
+    /// default.
+    pub fn set_tcp_unclaimed_policy(&self, policy: UnclaimedPolicy) {
+        unclaimed::set_tcp(policy)
+    }
+
+    /// Sets how UDP datagrams no socket claims are answered, `Drop` by default. Only
+    /// happens once the catch-all `UdpSocket` has been dropped.
+    pub fn set_udp_unclaimed_policy(&self, policy: UnclaimedPolicy) {
+        unclaimed::set_udp(policy)
+    }
+
+    /// Returns a snapshot of the lwIP protocol, heap and memory pool counters.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> StackStats {
+        stats::snapshot()
+    }
 }
 
 impl Stream for NetStack {
@@ -59,3 +246,27 @@ impl Sink<Vec<u8>> for NetStack {
         Pin::new(&mut self.0).poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_one_stack_per_process() {
+        let _stack = testing::lock_stack();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (stack, _tcp_listener, _udp_socket) = NetStack::new().unwrap();
+            stack.add_route("10.1.0.0/16".parse().unwrap(), stack.interface_id());
+            assert!(matches!(NetStack::new(), Err(Error::StackExists)));
+            // The failed attempt left the routes of the live stack alone.
+            assert!(stack.remove_route(&"10.1.0.0/16".parse().unwrap()));
+            drop(stack);
+            NetStack::new().unwrap();
+        });
+    }
+}
@@ -1,76 +1,152 @@
-use std::{io, os::raw, pin::Pin, sync::Once, time};
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, Once,
+    },
+    time,
+};
 
 use futures::sink::Sink;
 use futures::stream::Stream;
 use futures::task::{Context, Poll, Waker};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
 
+use super::classify::InputHandle;
+use super::config::NetStackConfig;
+use super::event;
+use super::filter;
+use super::forward;
+use super::interface::{Interface, InterfaceHandle};
 use super::lwip::*;
-use super::output::{output_ip4, output_ip6, OUTPUT_CB_PTR};
+use super::nat;
+use super::route;
+use super::udp;
 use super::LWIP_MUTEX;
+use crate::Error;
 
 static LWIP_INIT: Once = Once::new();
+/// Whether a stack is alive, lwIP and the routes, filters, NAT and event subscribers are
+/// global and reset when the stack goes away.
+static STACK_ALIVE: AtomicBool = AtomicBool::new(false);
+
+/// Packets emitted by lwIP and dropped because the stream of their interface was full.
+pub(crate) static OUTPUT_DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Packets written to an interface and dropped because lwIP had no pbuf left for them.
+pub(crate) static INPUT_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Tasks waiting for room in the interface streams or in the lwIP pools.
+static SENDER_WAKERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+/// Parks a sender until packets are drained from an interface stream, or lwIP processed
+/// input or timers, which may free memory.
+pub(crate) fn park_sender(waker: &Waker) {
+    let mut wakers = SENDER_WAKERS.lock().unwrap_or_else(|e| e.into_inner());
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+pub(crate) fn wake_senders() {
+    let wakers = std::mem::take(&mut *SENDER_WAKERS.lock().unwrap_or_else(|e| e.into_inner()));
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// Whether the stream of `netif`, the one a packet leaves on, can take another packet,
+/// lwip_mutex must be locked. A null netif has room, lwIP then fails the send itself.
+pub(crate) fn egress_has_room(netif: *mut netif) -> bool {
+    if netif.is_null() {
+        return true;
+    }
+    match unsafe { Interface::from_netif(netif) } {
+        Some(interface) => interface.has_room(),
+        None => true,
+    }
+}
 
 pub struct NetStackImpl {
-    waker: Option<Waker>,
-    tx: Sender<Vec<u8>>,
-    rx: Receiver<Vec<u8>>,
-    sink_buf: Option<Vec<u8>>, // We're flushing per item, no need large buffer.
+    handle: InterfaceHandle,
+    input: InputHandle,
+    udp_buffer_size: usize,
 }
 
 impl NetStackImpl {
-    pub fn new(buffer_size: usize) -> Box<Self> {
-        LWIP_INIT.call_once(|| unsafe { lwip_init() });
-
-        unsafe {
-            (*netif_list).output = Some(output_ip4);
-            (*netif_list).output_ip6 = Some(output_ip6);
-            (*netif_list).mtu = 1500;
+    pub fn new(config: &NetStackConfig) -> Result<Box<Self>, Error> {
+        if STACK_ALIVE
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(Error::StackExists);
+        }
+        let stack = Self::with_lwip(config);
+        if stack.is_err() {
+            STACK_ALIVE.store(false, Ordering::Release);
         }
+        stack
+    }
 
-        let (tx, rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = channel(buffer_size);
+    fn with_lwip(config: &NetStackConfig) -> Result<Box<Self>, Error> {
+        LWIP_INIT.call_once(|| {
+            #[cfg(feature = "lwip-debug")]
+            super::diag::init();
+            route::init();
+            forward::init();
+            nat::init();
+            unsafe { lwip_init() }
+        });
+
+        let handle = InterfaceHandle::new(&config.interface())?;
+        handle.interface().set_default();
+        forward::set_enabled(config.ip_forward);
 
+        let input = InputHandle::new(handle.downgrade());
         let stack = Box::new(NetStackImpl {
-            waker: None,
-            tx,
-            rx,
-            sink_buf: None,
+            handle,
+            input,
+            udp_buffer_size: config.udp_buffer_size,
         });
 
-        unsafe {
-            OUTPUT_CB_PTR = &*stack as *const NetStackImpl as usize;
-        }
-
         tokio::spawn(async move {
             loop {
                 {
                     let _g = LWIP_MUTEX.lock();
                     unsafe { sys_check_timeouts() };
                 }
+                wake_senders();
+                nat::expire();
+                udp::expire_flows();
                 tokio::time::sleep(time::Duration::from_millis(250)).await;
             }
         });
 
-        stack
+        Ok(stack)
     }
 
-    pub fn output(&mut self, pkt: Vec<u8>) {
-        if self.tx.try_send(pkt).is_err() {
-            // log::trace!("try send stack output pkt failed: {}", e);
-        }
-        if let Some(waker) = self.waker.as_ref() {
-            waker.wake_by_ref();
-        }
+    /// The default interface, the one the stack stream and sink belong to.
+    pub fn interface(&self) -> &Interface {
+        self.handle.interface()
+    }
+
+    pub fn input(&self) -> &InputHandle {
+        &self.input
+    }
+
+    pub fn udp_buffer_size(&self) -> usize {
+        self.udp_buffer_size
     }
 }
 
 impl Drop for NetStackImpl {
     fn drop(&mut self) {
         log::trace!("drop netstack");
-        unsafe {
-            let _g = LWIP_MUTEX.lock();
-            OUTPUT_CB_PTR = 0x0;
-        };
+        event::close();
+        filter::close();
+        forward::close();
+        nat::disable();
+        route::clear();
+        STACK_ALIVE.store(false, Ordering::Release);
     }
 }
 
@@ -78,80 +154,26 @@ impl Stream for NetStackImpl {
     type Item = io::Result<Vec<u8>>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.rx.poll_recv(cx) {
-            Poll::Ready(Some(pkt)) => Poll::Ready(Some(Ok(pkt))),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => {
-                self.waker.replace(cx.waker().clone());
-                Poll::Pending
-            }
-        }
+        Pin::new(&mut self.handle).poll_next(cx)
     }
 }
 
 impl Sink<Vec<u8>> for NetStackImpl {
     type Error = io::Error;
 
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        if self.sink_buf.is_none() {
-            Poll::Ready(Ok(()))
-        } else {
-            self.poll_flush(cx)
-        }
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.handle).poll_ready(cx)
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
-        self.sink_buf.replace(item);
-        Ok(())
+        Pin::new(&mut self.handle).start_send(item)
     }
 
-    fn poll_flush(
-        mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-    ) -> Poll<Result<(), Self::Error>> {
-        if let Some(item) = self.sink_buf.take() {
-            if item.is_empty() {
-                return Poll::Ready(Ok(()));
-            }
-            unsafe {
-                let _g = LWIP_MUTEX.lock();
-
-                let pbuf = pbuf_alloc(pbuf_layer_PBUF_RAW, item.len() as u16_t, pbuf_type_PBUF_RAM);
-                if pbuf.is_null() {
-                    log::trace!("pbuf_alloc null alloc");
-                    return Poll::Pending;
-                }
-                pbuf_take(
-                    pbuf,
-                    item.as_ptr() as *const raw::c_void,
-                    item.len() as u16_t,
-                );
-
-                if let Some(input_fn) = (*netif_list).input {
-                    let err = input_fn(pbuf, netif_list);
-                    if err == err_enum_t_ERR_OK as err_t {
-                        Poll::Ready(Ok(()))
-                    } else {
-                        pbuf_free(pbuf);
-                        Poll::Ready(Err(io::Error::new(
-                            io::ErrorKind::Interrupted,
-                            format!("input error: {}", err),
-                        )))
-                    }
-                } else {
-                    pbuf_free(pbuf);
-                    Poll::Ready(Err(io::Error::new(
-                        io::ErrorKind::Interrupted,
-                        "input fn not set",
-                    )))
-                }
-            }
-        } else {
-            Poll::Ready(Ok(()))
-        }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.handle).poll_flush(cx)
     }
 
-    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.handle).poll_close(cx)
     }
 }
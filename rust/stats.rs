@@ -0,0 +1,195 @@
+use std::{ffi::CStr, sync::atomic::Ordering};
+
+use super::lwip::*;
+use super::stack_impl::{INPUT_DROPPED, OUTPUT_DROPPED};
+use super::udp::{UDP_RECV_DROPPED, UDP_RECV_DROPPED_BYTES};
+use super::LWIP_MUTEX;
+
+/// Per-protocol packet counters, see `struct stats_proto` in lwIP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtoStats {
+    /// Transmitted packets.
+    pub xmit: u64,
+    /// Received packets.
+    pub recv: u64,
+    /// Forwarded packets.
+    pub fw: u64,
+    /// Dropped packets.
+    pub drop: u64,
+    /// Checksum errors.
+    pub chkerr: u64,
+    /// Invalid length errors.
+    pub lenerr: u64,
+    /// Out of memory errors.
+    pub memerr: u64,
+    /// Routing errors.
+    pub rterr: u64,
+    /// Protocol errors.
+    pub proterr: u64,
+    /// Errors in options.
+    pub opterr: u64,
+    /// Misc errors.
+    pub err: u64,
+    pub cachehit: u64,
+}
+
+impl From<&stats_proto> for ProtoStats {
+    fn from(s: &stats_proto) -> Self {
+        ProtoStats {
+            xmit: s.xmit as u64,
+            recv: s.recv as u64,
+            fw: s.fw as u64,
+            drop: s.drop as u64,
+            chkerr: s.chkerr as u64,
+            lenerr: s.lenerr as u64,
+            memerr: s.memerr as u64,
+            rterr: s.rterr as u64,
+            proterr: s.proterr as u64,
+            opterr: s.opterr as u64,
+            err: s.err as u64,
+            cachehit: s.cachehit as u64,
+        }
+    }
+}
+
+/// Usage of the lwIP heap (`MEM_SIZE`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemStats {
+    /// Bytes available.
+    pub avail: usize,
+    /// Bytes in use.
+    pub used: usize,
+    /// High-water mark of `used`.
+    pub max: usize,
+    /// Failed allocations.
+    pub err: u64,
+    /// Illegal frees.
+    pub illegal: u64,
+}
+
+impl From<&stats_mem> for MemStats {
+    fn from(s: &stats_mem) -> Self {
+        MemStats {
+            avail: s.avail as usize,
+            used: s.used as usize,
+            max: s.max as usize,
+            err: s.err as u64,
+            illegal: s.illegal as u64,
+        }
+    }
+}
+
+/// Usage of one lwIP memory pool (`MEMP_NUM_*`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemPoolStats {
+    /// Pool name as declared in `memp_std.h`, e.g. `TCP_PCB`.
+    pub name: &'static str,
+    /// Number of elements in the pool.
+    pub avail: usize,
+    /// Elements in use.
+    pub used: usize,
+    /// High-water mark of `used`.
+    pub max: usize,
+    /// Failed allocations, i.e. the pool was exhausted.
+    pub err: u64,
+    /// Illegal frees.
+    pub illegal: u64,
+}
+
+/// Packets dropped by this crate because a channel towards the application or lwIP was
+/// full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelDropStats {
+    /// Packets emitted by lwIP while the `NetStack` stream was full.
+    pub output: u64,
+    /// Packets written to the `NetStack` sink while lwIP had no pbuf left for them.
+    pub input: u64,
+    /// Datagrams received while the `UdpSocket` buffer was full.
+    pub udp_recv: u64,
+    /// Payload bytes of the datagrams counted in `udp_recv`.
+    pub udp_recv_bytes: u64,
+}
+
+/// Snapshot of the lwIP and netstack counters, returned by `NetStack::stats`. The counters
+/// of an address family the stack is built without, see the `ipv4` and `ipv6` features,
+/// stay zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StackStats {
+    pub link: ProtoStats,
+    /// ARP, only used in Ethernet mode.
+    pub etharp: ProtoStats,
+    pub ip: ProtoStats,
+    pub ip_frag: ProtoStats,
+    pub icmp: ProtoStats,
+    pub ip6: ProtoStats,
+    pub ip6_frag: ProtoStats,
+    pub icmp6: ProtoStats,
+    pub nd6: ProtoStats,
+    pub udp: ProtoStats,
+    pub tcp: ProtoStats,
+    pub mem: MemStats,
+    pub memp: Vec<MemPoolStats>,
+    pub dropped: ChannelDropStats,
+}
+
+fn pool_name(name: *const std::os::raw::c_char) -> &'static str {
+    if name.is_null() {
+        return "unknown";
+    }
+    // SAFETY: pool names are string literals in memp.c.
+    unsafe { CStr::from_ptr(name) }
+        .to_str()
+        .unwrap_or("unknown")
+}
+
+pub(crate) fn snapshot() -> StackStats {
+    let _g = LWIP_MUTEX.lock();
+    // SAFETY: lwip_stats is only updated by lwIP with lwip_mutex locked.
+    let s = unsafe { &*std::ptr::addr_of!(lwip_stats) };
+    let memp = s
+        .memp
+        .iter()
+        .filter(|pool| !pool.is_null())
+        .map(|&pool| {
+            let pool = unsafe { &*pool };
+            MemPoolStats {
+                name: pool_name(pool.name),
+                avail: pool.avail as usize,
+                used: pool.used as usize,
+                max: pool.max as usize,
+                err: pool.err as u64,
+                illegal: pool.illegal as u64,
+            }
+        })
+        .collect();
+    #[allow(unused_mut)]
+    let mut stats = StackStats {
+        link: (&s.link).into(),
+        etharp: (&s.etharp).into(),
+        ip: (&s.ip).into(),
+        icmp: (&s.icmp).into(),
+        udp: (&s.udp).into(),
+        tcp: (&s.tcp).into(),
+        mem: (&s.mem).into(),
+        memp,
+        dropped: ChannelDropStats {
+            output: OUTPUT_DROPPED.load(Ordering::Relaxed) as u64,
+            input: INPUT_DROPPED.load(Ordering::Relaxed) as u64,
+            udp_recv: UDP_RECV_DROPPED.load(Ordering::Relaxed) as u64,
+            udp_recv_bytes: UDP_RECV_DROPPED_BYTES.load(Ordering::Relaxed) as u64,
+        },
+        ..Default::default()
+    };
+    #[cfg(feature = "ipv4")]
+    {
+        stats.ip_frag = (&s.ip_frag).into();
+    }
+    #[cfg(feature = "ipv6")]
+    {
+        stats.ip6 = (&s.ip6).into();
+        stats.ip6_frag = (&s.ip6_frag).into();
+        stats.icmp6 = (&s.icmp6).into();
+        stats.nd6 = (&s.nd6).into();
+    }
+    stats
+}
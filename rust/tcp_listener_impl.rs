@@ -5,9 +5,11 @@ use futures::stream::Stream;
 use futures::task::{Context, Poll, Waker};
 use log::*;
 
+use super::event::{self, StackEvent};
 use super::lwip::*;
 use super::tcp_stream::TcpStream;
 use super::tcp_stream_impl::TcpStreamImpl;
+use super::util;
 use super::LWIP_MUTEX;
 use crate::Error;
 
@@ -28,6 +30,10 @@ pub extern "C" fn tcp_accept_cb(arg: *mut raw::c_void, newpcb: *mut tcp_pcb, err
     }
     let listener = unsafe { &mut *(arg as *mut TcpListenerImpl) };
     let stream = TcpStreamImpl::new(newpcb);
+    event::emit(StackEvent::TcpOpened {
+        local_addr: *stream.local_addr(),
+        remote_addr: *stream.remote_addr(),
+    });
     listener.queue.push_back(stream);
     if let Some(waker) = listener.waker.as_ref() {
         waker.wake_by_ref();
@@ -46,7 +52,7 @@ impl TcpListenerImpl {
         unsafe {
             let _g = LWIP_MUTEX.lock();
             let mut tpcb = tcp_new();
-            let err = tcp_bind(tpcb, &ip_addr_any_type, 0);
+            let err = tcp_bind(tpcb, &util::ip_any_type(), 0);
             if err != err_enum_t_ERR_OK as err_t {
                 error!("bind TCP failed: {}", err);
                 return Err(Error::LwIP(err));
@@ -3,6 +3,7 @@ use std::{io, pin::Pin};
 use futures::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
+use super::interface::InterfaceId;
 use super::tcp_stream_impl::TcpStreamImpl;
 
 pub struct TcpStream {
@@ -13,6 +14,11 @@ impl TcpStream {
     pub(crate) fn new(stream: Box<TcpStreamImpl>) -> Self {
         TcpStream { inner: stream }
     }
+
+    /// The interface the connection came in on and is answered on.
+    pub fn interface(&self) -> Option<InterfaceId> {
+        self.inner.interface()
+    }
 }
 
 impl AsyncRead for TcpStream {
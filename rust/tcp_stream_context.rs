@@ -7,6 +7,7 @@ use std::{
 };
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
+use super::event::ByteCounts;
 use super::LWIPMutexGuard;
 
 pub struct TcpStreamContextInner {
@@ -17,6 +18,9 @@ pub struct TcpStreamContextInner {
     pub errored: bool,
     pub closed: bool,
     pub write_waker: Option<Waker>,
+    pub bytes: ByteCounts,
+    #[cfg(feature = "tracing")]
+    pub span: tracing::Span,
 }
 
 #[repr(transparent)]
@@ -69,6 +73,15 @@ impl TcpStreamContext {
                 errored: false,
                 closed: false,
                 write_waker: None,
+                bytes: ByteCounts::default(),
+                #[cfg(feature = "tracing")]
+                span: tracing::info_span!(
+                    "netstack_tcp",
+                    src_ip = %local_addr.ip(),
+                    src_port = local_addr.port(),
+                    dst_ip = %remote_addr.ip(),
+                    dst_port = remote_addr.port(),
+                ),
             }),
             borrowed: AtomicBool::new(false),
         }
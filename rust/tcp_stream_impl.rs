@@ -8,6 +8,8 @@ use tokio::{
     sync::mpsc::unbounded_channel,
 };
 
+use super::event::{self, StackEvent, TcpCloseReason};
+use super::interface::{Interface, InterfaceId};
 use super::lwip::*;
 use super::tcp_stream_context::TcpStreamContext;
 use super::util;
@@ -32,6 +34,8 @@ pub unsafe extern "C" fn tcp_recv_cb(
 
     if p.is_null() {
         trace!("netstack tcp eof {}", ctx.local_addr);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &ctx.span, "eof");
         ctx.read_tx.as_ref().map(|tx| tx.send(Vec::new()));
         return err_enum_t_ERR_OK as err_t;
     }
@@ -42,6 +46,9 @@ pub unsafe extern "C" fn tcp_recv_cb(
     buf.set_len(pbuflen as usize);
 
     if !buf.is_empty() {
+        ctx.bytes.rx += buf.len() as u64;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(parent: &ctx.span, bytes = buf.len(), "read");
         ctx.read_tx.as_ref().map(|tx| tx.send(buf));
     }
 
@@ -69,11 +76,27 @@ pub extern "C" fn tcp_err_cb(arg: *mut ::std::os::raw::c_void, err: err_t) {
     // Thus lwip_mutex must be locked before calling any of these.
     let ctx = &mut *unsafe { TcpStreamContext::assume_locked(arg as *const TcpStreamContext) };
     trace!("netstack tcp err {} {}", err, ctx.local_addr);
+    #[cfg(feature = "tracing")]
+    tracing::debug!(parent: &ctx.span, err, rx = ctx.bytes.rx, tx = ctx.bytes.tx, "error");
     ctx.errored = true;
     let _ = ctx.read_tx.take();
     if let Some(waker) = ctx.write_waker.as_ref() {
         waker.wake_by_ref();
     }
+    event::emit(if err == err_enum_t_ERR_RST as err_t {
+        StackEvent::TcpReset {
+            local_addr: ctx.local_addr,
+            remote_addr: ctx.remote_addr,
+            bytes: ctx.bytes,
+        }
+    } else {
+        StackEvent::TcpClosed {
+            local_addr: ctx.local_addr,
+            remote_addr: ctx.remote_addr,
+            reason: TcpCloseReason::Error(err),
+            bytes: ctx.bytes,
+        }
+    });
 }
 
 #[allow(unused_variables)]
@@ -89,6 +112,7 @@ pub extern "C" fn tcp_poll_cb(arg: *mut ::std::os::raw::c_void, tpcb: *mut tcp_p
 pub struct TcpStreamImpl {
     src_addr: SocketAddr,
     dest_addr: SocketAddr,
+    interface: Option<InterfaceId>,
     pcb: usize,
     write_buf: BytesMut,
     callback_ctx: TcpStreamContext,
@@ -107,10 +131,18 @@ impl TcpStreamImpl {
             let (read_tx, read_rx) = unbounded_channel();
             let pcb_v = std::ptr::read_unaligned(pcb);
             let src_addr = util::to_socket_addr(&pcb_v.remote_ip, pcb_v.remote_port);
-            let dest_addr = util::to_socket_addr(&pcb_v.local_ip, pcb_v.local_port);
+            let mut dest_addr = util::to_socket_addr(&pcb_v.local_ip, pcb_v.local_port);
+            let interface = InterfaceId::from_index(pcb_v.netif_idx);
+            if let Some(nat64) = interface
+                .and_then(|id| Interface::from_id(id))
+                .and_then(Interface::nat64)
+            {
+                dest_addr = nat64.unmap(dest_addr);
+            }
             let stream = Box::new(TcpStreamImpl {
                 src_addr,
                 dest_addr,
+                interface,
                 pcb: pcb as usize,
                 write_buf: BytesMut::new(),
                 callback_ctx: TcpStreamContext::new(src_addr, dest_addr, read_tx, read_rx),
@@ -147,6 +179,10 @@ impl TcpStreamImpl {
         &self.dest_addr
     }
 
+    pub fn interface(&self) -> Option<InterfaceId> {
+        self.interface
+    }
+
     fn send_buf_size(&self) -> usize {
         unsafe { std::ptr::read_unaligned(self.pcb as *const tcp_pcb).snd_buf as usize }
     }
@@ -209,6 +245,15 @@ impl Drop for TcpStreamImpl {
         let guard = LWIP_MUTEX.lock();
         let ctx = &*self.callback_ctx.with_lock(&guard);
         trace!("netstack tcp drop {}", &ctx.local_addr);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            parent: &ctx.span,
+            errored = ctx.errored,
+            closed = ctx.closed,
+            rx = ctx.bytes.rx,
+            tx = ctx.bytes.tx,
+            "drop"
+        );
         if !ctx.errored {
             unsafe {
                 tcp_arg(self.pcb as *mut tcp_pcb, std::ptr::null_mut());
@@ -220,6 +265,16 @@ impl Drop for TcpStreamImpl {
                     tcp_abort(self.pcb as *mut tcp_pcb);
                 }
             }
+            event::emit(StackEvent::TcpClosed {
+                local_addr: ctx.local_addr,
+                remote_addr: ctx.remote_addr,
+                reason: if ctx.closed {
+                    TcpCloseReason::Closed
+                } else {
+                    TcpCloseReason::Aborted
+                },
+                bytes: ctx.bytes,
+            });
         }
     }
 }
@@ -245,6 +300,9 @@ impl AsyncWrite for TcpStreamImpl {
             )
         };
         if err == err_enum_t_ERR_OK as err_t {
+            ctx.bytes.tx += to_write as u64;
+            #[cfg(feature = "tracing")]
+            tracing::trace!(parent: &ctx.span, bytes = to_write, "write");
             // Call output in case of mem err?
             let err = unsafe { tcp_output(self.pcb as *mut tcp_pcb) };
             if err == err_enum_t_ERR_OK as err_t {
@@ -260,6 +318,8 @@ impl AsyncWrite for TcpStreamImpl {
             ctx.write_waker.replace(cx.waker().clone());
             Poll::Pending
         } else {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(parent: &ctx.span, err, "tcp_write error");
             Poll::Ready(Err(io::Error::new(
                 io::ErrorKind::Interrupted,
                 format!("netstack tcp_write error {}", err),
@@ -290,6 +350,8 @@ impl AsyncWrite for TcpStreamImpl {
             return Poll::Ready(Err(broken_pipe()));
         }
         trace!("netstack tcp shutdown {}", &ctx.local_addr);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &ctx.span, "shutdown");
         let err = unsafe { tcp_shutdown(self.pcb as *mut tcp_pcb, 0, 1) };
         if err != err_enum_t_ERR_OK as err_t {
             Poll::Ready(Err(io::Error::new(
@@ -0,0 +1,169 @@
+//! Packets for the unit tests, with their checksums set, and the serialization of the
+//! tests running a stack.
+
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use super::util;
+
+static STACK: Mutex<()> = Mutex::new(());
+
+/// Held by the tests creating a `NetStack` for their whole run. lwIP and the state of
+/// the stack are global, another stack would take the unclaimed datagrams with its
+/// catch-all socket, and clear the routes, filters and NAT when dropped.
+pub fn lock_stack() -> MutexGuard<'static, ()> {
+    STACK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Awaits `future`, failing the test rather than hanging it if that takes long.
+pub async fn timeout<F: Future>(future: F) -> F::Output {
+    tokio::time::timeout(Duration::from_secs(5), future)
+        .await
+        .expect("timed out")
+}
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+/// An IPv4 header followed by `payload`, which is left as it is.
+pub fn ip4(protocol: u8, src: Ipv4Addr, dst: Ipv4Addr, frag_off: u16, payload: &[u8]) -> Vec<u8> {
+    let mut pkt = vec![0x45, 0];
+    pkt.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    pkt.extend_from_slice(&[0x12, 0x34]);
+    pkt.extend_from_slice(&frag_off.to_be_bytes());
+    pkt.extend_from_slice(&[64, protocol, 0, 0]);
+    pkt.extend_from_slice(&src.octets());
+    pkt.extend_from_slice(&dst.octets());
+    let checksum = util::inet_checksum(&pkt, 0);
+    pkt[10..12].copy_from_slice(&checksum.to_be_bytes());
+    pkt.extend_from_slice(payload);
+    pkt
+}
+
+/// An IPv6 header followed by `payload`, which is left as it is.
+pub fn ip6(next_header: u8, src: Ipv6Addr, dst: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+    let mut pkt = vec![0x60, 0, 0, 0];
+    pkt.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    pkt.extend_from_slice(&[next_header, 64]);
+    pkt.extend_from_slice(&src.octets());
+    pkt.extend_from_slice(&dst.octets());
+    pkt.extend_from_slice(payload);
+    pkt
+}
+
+/// The partial sum of the pseudo-header of `l4`, none for ICMP.
+fn pseudo_sum(protocol: u8, src: IpAddr, dst: IpAddr, len: usize) -> u32 {
+    match (src, dst) {
+        (IpAddr::V4(_), IpAddr::V4(_)) if protocol == IPPROTO_ICMP => 0,
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            util::ip4_pseudo_sum(&src, &dst, len as u32, protocol)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            util::ip6_pseudo_sum(&src, &dst, len as u32, protocol)
+        }
+        _ => unreachable!("mixed address families"),
+    }
+}
+
+/// `l4` with its TCP, UDP, ICMP or ICMPv6 checksum set for the addresses, other
+/// protocols are left as they are.
+pub fn sum(protocol: u8, src: IpAddr, dst: IpAddr, mut l4: Vec<u8>) -> Vec<u8> {
+    let at = match protocol {
+        IPPROTO_TCP => 16,
+        IPPROTO_UDP => 6,
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => 2,
+        _ => return l4,
+    };
+    l4[at..at + 2].fill(0);
+    let checksum = util::inet_checksum(&l4, pseudo_sum(protocol, src, dst, l4.len()));
+    l4[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    l4
+}
+
+/// An IPv4 or IPv6 packet of `l4`, with all the checksums set.
+pub fn ip(protocol: u8, src: IpAddr, dst: IpAddr, l4: Vec<u8>) -> Vec<u8> {
+    let l4 = sum(protocol, src, dst, l4);
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => ip4(protocol, src, dst, 0, &l4),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => ip6(protocol, src, dst, &l4),
+        _ => unreachable!("mixed address families"),
+    }
+}
+
+/// A TCP header without options, its checksum unset, followed by `payload`.
+pub fn tcp_segment(src_port: u16, dst_port: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut l4 = Vec::new();
+    l4.extend_from_slice(&src_port.to_be_bytes());
+    l4.extend_from_slice(&dst_port.to_be_bytes());
+    l4.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    l4.extend_from_slice(payload);
+    l4
+}
+
+/// A UDP header, its checksum unset, followed by `payload`.
+pub fn udp_datagram(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut l4 = Vec::new();
+    l4.extend_from_slice(&src_port.to_be_bytes());
+    l4.extend_from_slice(&dst_port.to_be_bytes());
+    l4.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    l4.extend_from_slice(&[0, 0]);
+    l4.extend_from_slice(payload);
+    l4
+}
+
+/// An ICMP or ICMPv6 echo message of `icmp_type`, its checksum unset.
+pub fn echo_message(icmp_type: u8, id: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+    let mut l4 = vec![icmp_type, 0, 0, 0];
+    l4.extend_from_slice(&id.to_be_bytes());
+    l4.extend_from_slice(&seq.to_be_bytes());
+    l4.extend_from_slice(payload);
+    l4
+}
+
+pub fn tcp(src: SocketAddr, dst: SocketAddr, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let l4 = tcp_segment(src.port(), dst.port(), flags, payload);
+    ip(IPPROTO_TCP, src.ip(), dst.ip(), l4)
+}
+
+pub fn udp(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let l4 = udp_datagram(src.port(), dst.port(), payload);
+    ip(IPPROTO_UDP, src.ip(), dst.ip(), l4)
+}
+
+/// An echo request if `request`, a reply otherwise, over ICMP or ICMPv6 according to
+/// the addresses.
+pub fn echo(request: bool, src: IpAddr, dst: IpAddr, id: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+    let (protocol, icmp_type) = match (src.is_ipv4(), request) {
+        (true, true) => (IPPROTO_ICMP, 8),
+        (true, false) => (IPPROTO_ICMP, 0),
+        (false, true) => (IPPROTO_ICMPV6, 128),
+        (false, false) => (IPPROTO_ICMPV6, 129),
+    };
+    ip(
+        protocol,
+        src,
+        dst,
+        echo_message(icmp_type, id, seq, payload),
+    )
+}
+
+/// Asserts the checksums of an unfragmented packet. `pkt` may be a quoted IPv4 packet
+/// cut short, its payload is then left unchecked.
+pub fn check(pkt: &[u8]) {
+    let (src, dst, protocol, payload) = util::split_ip_packet(pkt).unwrap();
+    if src.is_ipv4() {
+        let hdr_len = ((pkt[0] & 0x0f) as usize) * 4;
+        assert_eq!(util::inet_checksum(&pkt[..hdr_len], 0), 0);
+        if (u16::from_be_bytes([pkt[2], pkt[3]]) as usize) > pkt.len() {
+            return;
+        }
+    }
+    let sum = pseudo_sum(protocol, src, dst, payload.len());
+    assert_eq!(util::inet_checksum(payload, sum), 0);
+}
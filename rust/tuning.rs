@@ -0,0 +1,9 @@
+//! The lwIP sizes the crate is built with, fixed at build time by the `profile-mobile` and
+//! `profile-server` features and the `LWIP_<NAME>` environment variables, e.g.
+//! `LWIP_TCP_WND=65535`, so that applications can check they got what they asked for.
+//!
+//! ```
+//! assert!(lwip::tuning::TCP_WND >= 2 * lwip::tuning::TCP_MSS as u32);
+//! ```
+
+include!(concat!(env!("OUT_DIR"), "/tuning.rs"));
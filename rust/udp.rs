@@ -1,15 +1,148 @@
-use std::{io, net::SocketAddr, os::raw, pin::Pin};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    os::raw,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
 
+use futures::future::poll_fn;
+use futures::sink::Sink;
 use futures::stream::Stream;
 use futures::task::{Context, Poll, Waker};
 use futures::StreamExt;
 use log::{error, warn};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
 
+use super::event::{self, StackEvent};
+use super::interface::InterfaceId;
 use super::lwip::*;
+use super::route;
+use super::stack::NetStack;
+use super::stack_impl;
 use super::util;
 use crate::Error;
 
+const UDP_FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The live sockets, whose flows are expired by `expire_flows`.
+static UDP_SOCKETS: Mutex<Vec<Weak<UdpSocketInner>>> = Mutex::new(Vec::new());
+
+/// Expires the idle flows of all the sockets, run periodically by the stack.
+pub(crate) fn expire_flows() {
+    let now = Instant::now();
+    let sockets: Vec<_> = {
+        let mut sockets = UDP_SOCKETS.lock().unwrap_or_else(|e| e.into_inner());
+        sockets.retain(|socket| socket.strong_count() > 0);
+        sockets.iter().filter_map(Weak::upgrade).collect()
+    };
+    for socket in sockets {
+        socket.expire_flows(now);
+    }
+}
+
+/// A flow on one interface, the same addresses on another one are another flow.
+struct UdpFlowState {
+    last_seen: Instant,
+    interface: Option<InterfaceId>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Datagrams dropped because the `UdpSocket` buffer was full.
+pub(crate) static UDP_RECV_DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Payload bytes of the datagrams counted in `UDP_RECV_DROPPED`.
+pub(crate) static UDP_RECV_DROPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// What a `UdpSocket` does with a datagram received while its buffer is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UdpOverflowPolicy {
+    /// Drop the datagram just received.
+    #[default]
+    DropNewest,
+    /// Drop the oldest buffered datagram to make room.
+    DropOldest,
+    /// Keep buffering past the buffer size as long as the buffered payloads fit in
+    /// `max_bytes`, then drop the datagram just received.
+    Grow { max_bytes: usize },
+}
+
+/// Datagrams dropped by a `UdpSocket` because its buffer was full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpDropStats {
+    /// Datagrams.
+    pub packets: u64,
+    /// Payload bytes.
+    pub bytes: u64,
+}
+
+/// Datagrams received by lwIP and not yet read from the socket, with the interface they
+/// came in on.
+struct UdpRecvQueue {
+    pkts: VecDeque<(UdpPkt, Option<InterfaceId>)>,
+    bytes: usize,
+    buffer_size: usize,
+    policy: UdpOverflowPolicy,
+    dropped: UdpDropStats,
+    waker: Option<Waker>,
+}
+
+impl UdpRecvQueue {
+    fn push(&mut self, pkt: UdpPkt, interface: Option<InterfaceId>) {
+        if self.pkts.len() >= self.buffer_size {
+            let dropped = match self.policy {
+                UdpOverflowPolicy::DropNewest => Some(pkt.0.len()),
+                UdpOverflowPolicy::DropOldest => {
+                    let oldest = self.pkts.pop_front().map(|((data, _, _), _)| data.len());
+                    if let Some(len) = oldest {
+                        self.bytes -= len;
+                    }
+                    self.bytes += pkt.0.len();
+                    self.pkts.push_back((pkt, interface));
+                    oldest
+                }
+                UdpOverflowPolicy::Grow { max_bytes } if self.bytes + pkt.0.len() > max_bytes => {
+                    Some(pkt.0.len())
+                }
+                UdpOverflowPolicy::Grow { .. } => {
+                    self.bytes += pkt.0.len();
+                    self.pkts.push_back((pkt, interface));
+                    None
+                }
+            };
+            if let Some(len) = dropped {
+                self.dropped.packets += 1;
+                self.dropped.bytes += len as u64;
+                UDP_RECV_DROPPED.fetch_add(1, Ordering::Relaxed);
+                UDP_RECV_DROPPED_BYTES.fetch_add(len, Ordering::Relaxed);
+            }
+        } else {
+            self.bytes += pkt.0.len();
+            self.pkts.push_back((pkt, interface));
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn poll_pop(&mut self, cx: &mut Context) -> Poll<(UdpPkt, Option<InterfaceId>)> {
+        match self.pkts.pop_front() {
+            Some(received) => {
+                self.bytes -= received.0 .0.len();
+                Poll::Ready(received)
+            }
+            None => {
+                self.waker.replace(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
 pub unsafe extern "C" fn udp_recv_cb(
     arg: *mut raw::c_void,
     _pcb: *mut udp_pcb,
@@ -23,134 +156,463 @@ pub unsafe extern "C" fn udp_recv_cb(
         warn!("udp socket has been closed");
         return;
     }
-    let socket = &mut *(arg as *mut UdpSocket);
+    // SAFETY: the callback is removed, with lwip_mutex locked, before the socket state
+    // is dropped.
+    let socket = &*(arg as *const UdpSocketInner);
     let src_addr = util::to_socket_addr(&*addr, port);
     let dst_addr = util::to_socket_addr(&*dst_addr, dst_port);
     let tot_len = std::ptr::read_unaligned(p).tot_len;
+    let interface = InterfaceId::current_input();
+    socket.track_flow(interface, src_addr, dst_addr, tot_len as usize);
     let mut buf = Vec::with_capacity(tot_len as usize);
     pbuf_copy_partial(p, buf.as_mut_ptr() as *mut _, tot_len, 0);
     buf.set_len(tot_len as usize);
     pbuf_free(p);
-    if socket.tx.try_send((buf, src_addr, dst_addr)).is_err() {
-        // log::trace!("try send udp pkt failed (netstack): {}", e);
-    }
-    if let Some(waker) = socket.waker.as_ref() {
-        waker.wake_by_ref();
-    }
+    socket
+        .recv_queue()
+        .push((buf, src_addr, dst_addr), interface);
 }
 
-fn send_udp(
+/// Sends a datagram with lwip_mutex locked, `Pending` while lwIP or the stack stream
+/// has no room for it. The datagram goes out on `interface` if set, otherwise it's routed.
+fn poll_send_udp(
+    cx: &mut Context,
     src_addr: &SocketAddr,
     dst_addr: &SocketAddr,
     pcb: usize,
+    interface: Option<InterfaceId>,
     data: &[u8],
-) -> io::Result<()> {
-    unsafe {
-        let _g = super::LWIP_MUTEX.lock();
+) -> Poll<io::Result<()>> {
+    let (src_ip, dst_ip) = match (
+        util::from_socket_addr(src_addr),
+        util::from_socket_addr(dst_addr),
+    ) {
+        (Ok((src_ip, _)), Ok((dst_ip, _))) => (src_ip, dst_ip),
+        (Err(e), _) | (_, Err(e)) => return Poll::Ready(Err(e.into())),
+    };
+    let netif = match interface {
+        Some(id) => unsafe { netif_get_by_index(id.index()) },
+        None => route::find(&src_addr.ip(), &dst_addr.ip()),
+    };
+    if !stack_impl::egress_has_room(netif) {
+        stack_impl::park_sender(cx.waker());
+        // The stream may have been drained before we were parked.
+        if !stack_impl::egress_has_room(netif) {
+            return Poll::Pending;
+        }
+    }
+    let err = unsafe {
         let pbuf =
             pbuf_alloc_reference(data.as_ptr() as *mut _, data.len() as _, pbuf_type_PBUF_REF);
-        let src_ip = util::to_ip_addr_t(src_addr.ip());
-        let dst_ip = util::to_ip_addr_t(dst_addr.ip());
-        let err = udp_sendto(
-            pcb as *mut udp_pcb,
-            pbuf,
-            &dst_ip as *const _,
-            dst_addr.port(),
-            &src_ip as *const _,
-            src_addr.port(),
-        );
-        pbuf_free(pbuf);
-        if err != err_enum_t_ERR_OK as err_t {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("udp_sendto error: {}", err),
-            ));
+        if pbuf.is_null() {
+            err_enum_t_ERR_MEM as err_t
+        } else {
+            let pcb = pcb as *mut udp_pcb;
+            (*pcb).netif_idx = interface.map_or(NETIF_NO_INDEX as u8_t, |id| id.index());
+            let err = udp_sendto(
+                pcb,
+                pbuf,
+                &dst_ip as *const _,
+                dst_addr.port(),
+                &src_ip as *const _,
+                src_addr.port(),
+            );
+            // The pcb would otherwise only receive from that interface.
+            (*pcb).netif_idx = NETIF_NO_INDEX as u8_t;
+            pbuf_free(pbuf);
+            err
         }
-        Ok(())
+    };
+    if err == err_enum_t_ERR_OK as err_t {
+        Poll::Ready(Ok(()))
+    } else if err == err_enum_t_ERR_MEM as err_t {
+        stack_impl::park_sender(cx.waker());
+        Poll::Pending
+    } else {
+        Poll::Ready(Err(Error::LwIP(err).into()))
     }
 }
 
 pub type UdpPkt = (Vec<u8>, SocketAddr, SocketAddr);
 
-pub struct UdpSocket {
+/// State shared by the halves of a `UdpSocket` and the lwIP callbacks. The pcb is
+/// removed when the last half goes away.
+struct UdpSocketInner {
     pcb: usize,
-    waker: Option<Waker>,
-    tx: Sender<UdpPkt>,
-    rx: Receiver<UdpPkt>,
+    /// The address of `UdpSocket::bind`, the source of the datagrams sent from an
+    /// unspecified address. Kept apart from the pcb as the sends may hold lwip_mutex.
+    bound: Option<SocketAddr>,
+    recv_queue: Mutex<UdpRecvQueue>,
+    /// The flows of each (source, destination) pair, one per interface.
+    flows: Mutex<HashMap<(SocketAddr, SocketAddr), Vec<UdpFlowState>>>,
+}
+
+impl UdpSocketInner {
+    fn local_addr(&self) -> SocketAddr {
+        let _g = super::LWIP_MUTEX.lock();
+        let pcb = unsafe { &*(self.pcb as *const udp_pcb) };
+        util::to_socket_addr(&pcb.local_ip, pcb.local_port)
+    }
+
+    /// `src_addr`, or the bound address if its IP is unspecified.
+    fn source(&self, src_addr: &SocketAddr) -> io::Result<SocketAddr> {
+        if !src_addr.ip().is_unspecified() {
+            return Ok(*src_addr);
+        }
+        self.bound.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp socket is not bound to an address",
+            )
+        })
+    }
+
+    fn recv_queue(&self) -> std::sync::MutexGuard<'_, UdpRecvQueue> {
+        self.recv_queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn flows(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<(SocketAddr, SocketAddr), Vec<UdpFlowState>>> {
+        self.flows.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The interface the flow from `src_addr` to `dst_addr` was last seen on, if it's
+    /// seen on several of them.
+    fn flow_interface(&self, src_addr: &SocketAddr, dst_addr: &SocketAddr) -> Option<InterfaceId> {
+        self.flows()
+            .get(&(*src_addr, *dst_addr))
+            .and_then(|flows| flows.iter().max_by_key(|flow| flow.last_seen))
+            .and_then(|flow| flow.interface)
+    }
+
+    /// Records a datagram of the flow on `interface`, lwip_mutex must be locked.
+    fn track_flow(
+        &self,
+        interface: Option<InterfaceId>,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        len: usize,
+    ) {
+        let now = Instant::now();
+        let mut flows = self.flows();
+        let flows = flows.entry((src_addr, dst_addr)).or_default();
+        let at = match flows.iter().position(|flow| flow.interface == interface) {
+            Some(at) => at,
+            None => {
+                event::emit(StackEvent::UdpFlowStarted {
+                    src_addr,
+                    dst_addr,
+                    interface,
+                });
+                flows.push(UdpFlowState {
+                    last_seen: now,
+                    interface,
+                    #[cfg(feature = "tracing")]
+                    span: tracing::info_span!(
+                        "netstack_udp",
+                        src_ip = %src_addr.ip(),
+                        src_port = src_addr.port(),
+                        dst_ip = %dst_addr.ip(),
+                        dst_port = dst_addr.port(),
+                        interface = ?interface,
+                    ),
+                });
+                flows.len() - 1
+            }
+        };
+        let flow = &mut flows[at];
+        flow.last_seen = now;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(parent: &flow.span, bytes = len, "read");
+        #[cfg(not(feature = "tracing"))]
+        let _ = (flow, len);
+    }
+
+    fn expire_flows(&self, now: Instant) {
+        self.flows().retain(|&(src_addr, dst_addr), flows| {
+            flows.retain(|flow| {
+                if now.duration_since(flow.last_seen) < UDP_FLOW_IDLE_TIMEOUT {
+                    return true;
+                }
+                #[cfg(feature = "tracing")]
+                tracing::debug!(parent: &flow.span, "expired");
+                event::emit(StackEvent::UdpFlowExpired {
+                    src_addr,
+                    dst_addr,
+                    interface: flow.interface,
+                });
+                false
+            });
+            !flows.is_empty()
+        });
+    }
+}
+
+impl Drop for UdpSocketInner {
+    fn drop(&mut self) {
+        unsafe {
+            let _g = super::LWIP_MUTEX.lock();
+            udp_recv(self.pcb as *mut udp_pcb, None, std::ptr::null_mut());
+            udp_remove(self.pcb as *mut udp_pcb);
+        }
+        for ((src_addr, dst_addr), flows) in self.flows().drain() {
+            for flow in flows {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(parent: &flow.span, "drop");
+                event::emit(StackEvent::UdpFlowExpired {
+                    src_addr,
+                    dst_addr,
+                    interface: flow.interface,
+                });
+            }
+        }
+    }
+}
+
+pub struct UdpSocket {
+    inner: Arc<UdpSocketInner>,
 }
 
 impl UdpSocket {
+    /// Creates the catch-all socket receiving the datagrams no bound socket matches.
     pub(crate) fn new(buffer_size: usize) -> Result<Box<Self>, Error> {
+        let _g = super::LWIP_MUTEX.lock();
+        let socket = Self::new_locked(util::ip_any_type(), 0, buffer_size)?;
+        unsafe { (*(socket.inner.pcb as *mut udp_pcb)).flags |= UDP_FLAGS_TUN2SOCKS_ANY as u8_t };
+        Ok(socket)
+    }
+
+    /// Binds a socket to `addr` in the netstack, datagrams sent to it are no longer
+    /// delivered to the catch-all socket returned by `NetStack::new`. Its queue holds
+    /// `NetStackConfig::udp_buffer_size` datagrams, and datagrams sent from an unspecified
+    /// address, e.g. `0.0.0.0:0`, go out from `addr`.
+    pub fn bind(stack: &NetStack, addr: SocketAddr) -> Result<Box<Self>, Error> {
+        let (ip, port) = util::from_socket_addr(&addr)?;
+        let _g = super::LWIP_MUTEX.lock();
+        Self::new_locked(ip, port, stack.udp_buffer_size())
+    }
+
+    fn new_locked(ip: ip_addr_t, port: u16, buffer_size: usize) -> Result<Box<Self>, Error> {
         unsafe {
             let pcb = udp_new();
-            let (tx, rx): (Sender<UdpPkt>, Receiver<UdpPkt>) = channel(buffer_size);
-            let socket = Box::new(Self {
-                pcb: pcb as usize,
-                waker: None,
-                tx,
-                rx,
-            });
-            let err = udp_bind(pcb, &ip_addr_any_type, 0);
+            if pcb.is_null() {
+                return Err(Error::LwIP(err_enum_t_ERR_MEM as err_t));
+            }
+            let err = udp_bind(pcb, &ip, port);
             if err != err_enum_t_ERR_OK as err_t {
                 error!("bind UDP failed: {}", err);
+                udp_remove(pcb);
                 return Err(Error::LwIP(err));
             }
-            let arg = &*socket as *const UdpSocket as *mut raw::c_void;
+            let local = util::to_socket_addr(&(*pcb).local_ip, (*pcb).local_port);
+            let inner = Arc::new(UdpSocketInner {
+                pcb: pcb as usize,
+                bound: Some(local).filter(|addr| !addr.ip().is_unspecified()),
+                recv_queue: Mutex::new(UdpRecvQueue {
+                    pkts: VecDeque::new(),
+                    bytes: 0,
+                    buffer_size,
+                    policy: UdpOverflowPolicy::default(),
+                    dropped: UdpDropStats::default(),
+                    waker: None,
+                }),
+                flows: Mutex::new(HashMap::new()),
+            });
+            let arg = Arc::as_ptr(&inner) as *mut raw::c_void;
             udp_recv(pcb, Some(udp_recv_cb), arg);
-            Ok(socket)
+            UDP_SOCKETS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(Arc::downgrade(&inner));
+            Ok(Box::new(UdpSocket { inner }))
         }
     }
 
+    /// Splits the socket, it stays bound until both halves are dropped.
     pub fn split(self: Box<Self>) -> (SendHalf, RecvHalf) {
-        (SendHalf { pcb: self.pcb }, RecvHalf { socket: self })
+        (
+            SendHalf {
+                inner: self.inner.clone(),
+                sink_buf: None,
+            },
+            RecvHalf { socket: self },
+        )
     }
 
     pub fn local_addr(&self) -> SocketAddr {
-        unsafe {
-            let pcb = self.pcb as *mut udp_pcb;
-            let ip = (*pcb).local_ip;
-            let port = (*pcb).local_port;
-            util::to_socket_addr(&ip, port)
-        }
+        self.inner.local_addr()
     }
-}
 
-impl Drop for UdpSocket {
-    fn drop(&mut self) {
-        unsafe {
-            udp_recv(self.pcb as *mut udp_pcb, None, std::ptr::null_mut());
-            udp_remove(self.pcb as *mut udp_pcb);
-        }
+    /// Sets what happens to datagrams received while the buffer is full, `DropNewest`
+    /// by default.
+    pub fn set_overflow_policy(&self, policy: UdpOverflowPolicy) {
+        self.inner.recv_queue().policy = policy;
+    }
+
+    /// Datagrams dropped by this socket so far.
+    pub fn dropped(&self) -> UdpDropStats {
+        self.inner.recv_queue().dropped
+    }
+
+    /// The interface the datagrams from `src_addr` to `dst_addr` last came in on, `None`
+    /// once the flow has expired. Replies to them go out on it.
+    pub fn flow_interface(
+        &self,
+        src_addr: &SocketAddr,
+        dst_addr: &SocketAddr,
+    ) -> Option<InterfaceId> {
+        self.inner.flow_interface(src_addr, dst_addr)
     }
 }
 
 impl Stream for UdpSocket {
     type Item = UdpPkt;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        match self.rx.poll_recv(cx) {
-            Poll::Ready(Some(pkt)) => Poll::Ready(Some(pkt)),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => {
-                self.waker.replace(cx.waker().clone());
-                Poll::Pending
-            }
-        }
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.inner
+            .recv_queue()
+            .poll_pop(cx)
+            .map(|(pkt, _)| Some(pkt))
     }
 }
 
 pub struct SendHalf {
-    pub(crate) pcb: usize,
+    inner: Arc<UdpSocketInner>,
+    sink_buf: Option<UdpPkt>,
 }
 
 impl SendHalf {
-    pub fn send_to(
+    /// Sends `data` from `src_addr` to `dst_addr`, waiting while lwIP or the stack stream
+    /// has no room for it. Replies go out on the interface of their flow. An unspecified
+    /// `src_addr` stands for the address of `UdpSocket::bind`, it's an error on the
+    /// catch-all socket.
+    pub async fn send_to(
         &self,
         data: &[u8],
         src_addr: &SocketAddr,
         dst_addr: &SocketAddr,
     ) -> io::Result<()> {
-        send_udp(src_addr, dst_addr, self.pcb, data)
+        let src_addr = self.inner.source(src_addr)?;
+        let interface = self.inner.flow_interface(dst_addr, &src_addr);
+        self.send_on(data, &src_addr, dst_addr, interface).await
+    }
+
+    /// Sends `data` from `src_addr` to `dst_addr` on `interface`, routed if `None`.
+    pub(crate) async fn send_on(
+        &self,
+        data: &[u8],
+        src_addr: &SocketAddr,
+        dst_addr: &SocketAddr,
+        interface: Option<InterfaceId>,
+    ) -> io::Result<()> {
+        poll_fn(|cx| {
+            let _g = super::LWIP_MUTEX.lock();
+            let res = poll_send_udp(cx, src_addr, dst_addr, self.inner.pcb, interface, data);
+            if let Poll::Ready(res) = &res {
+                self.record_send(data, src_addr, dst_addr, interface, res);
+            }
+            res
+        })
+        .await
+    }
+
+    /// Sends the datagrams in order, taking the lock once for as many of them as lwIP
+    /// and the stack stream have room for. Stops at the first error.
+    pub async fn send_batch(&self, pkts: &[UdpPkt]) -> io::Result<()> {
+        let mut sent = 0;
+        poll_fn(|cx| {
+            let _g = super::LWIP_MUTEX.lock();
+            while let Some((data, src_addr, dst_addr)) = pkts.get(sent) {
+                let src_addr = &self.inner.source(src_addr)?;
+                let interface = self.inner.flow_interface(dst_addr, src_addr);
+                let res =
+                    match poll_send_udp(cx, src_addr, dst_addr, self.inner.pcb, interface, data) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                self.record_send(data, src_addr, dst_addr, interface, &res);
+                res?;
+                sent += 1;
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    fn record_send(
+        &self,
+        data: &[u8],
+        src_addr: &SocketAddr,
+        dst_addr: &SocketAddr,
+        interface: Option<InterfaceId>,
+        res: &io::Result<()>,
+    ) {
+        #[cfg(feature = "tracing")]
+        {
+            // Replies go back from the flow destination to its source.
+            let flows = self.inner.flows();
+            let flow = flows
+                .get(&(*dst_addr, *src_addr))
+                .and_then(|flows| flows.iter().find(|flow| flow.interface == interface));
+            if let Some(flow) = flow {
+                match res {
+                    Ok(()) => tracing::trace!(parent: &flow.span, bytes = data.len(), "write"),
+                    Err(e) => tracing::debug!(parent: &flow.span, error = %e, "write error"),
+                }
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = (data, src_addr, dst_addr, interface, res);
+    }
+}
+
+impl Sink<UdpPkt> for SendHalf {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.sink_buf.is_none() {
+            Poll::Ready(Ok(()))
+        } else {
+            self.poll_flush(cx)
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: UdpPkt) -> Result<(), Self::Error> {
+        self.sink_buf.replace(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let res = match &self.sink_buf {
+            Some((data, src_addr, dst_addr)) => match self.inner.source(src_addr) {
+                Ok(src_addr) => {
+                    let _g = super::LWIP_MUTEX.lock();
+                    let interface = self.inner.flow_interface(dst_addr, &src_addr);
+                    let res = match poll_send_udp(
+                        cx,
+                        &src_addr,
+                        dst_addr,
+                        self.inner.pcb,
+                        interface,
+                        data,
+                    ) {
+                        Poll::Ready(res) => res,
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    self.record_send(data, &src_addr, dst_addr, interface, &res);
+                    res
+                }
+                Err(e) => Err(e),
+            },
+            None => return Poll::Ready(Ok(())),
+        };
+        self.sink_buf = None;
+        Poll::Ready(res)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
     }
 }
 
@@ -168,6 +630,29 @@ impl RecvHalf {
             )),
         }
     }
+
+    pub fn set_overflow_policy(&self, policy: UdpOverflowPolicy) {
+        self.socket.set_overflow_policy(policy)
+    }
+
+    pub fn dropped(&self) -> UdpDropStats {
+        self.socket.dropped()
+    }
+
+    pub fn flow_interface(
+        &self,
+        src_addr: &SocketAddr,
+        dst_addr: &SocketAddr,
+    ) -> Option<InterfaceId> {
+        self.socket.flow_interface(src_addr, dst_addr)
+    }
+}
+
+impl RecvHalf {
+    /// Receives the next datagram with the interface it came in on.
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context) -> Poll<(UdpPkt, Option<InterfaceId>)> {
+        self.socket.inner.recv_queue().poll_pop(cx)
+    }
 }
 
 impl Stream for RecvHalf {
@@ -177,3 +662,270 @@ impl Stream for RecvHalf {
         Pin::new(&mut self.socket).poll_next(cx)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{self, timeout};
+    use std::net::IpAddr;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_halves_send_sync() {
+        assert_send_sync::<UdpSocket>();
+        assert_send_sync::<SendHalf>();
+        assert_send_sync::<RecvHalf>();
+    }
+
+    fn recv_queue(buffer_size: usize, policy: UdpOverflowPolicy) -> UdpRecvQueue {
+        UdpRecvQueue {
+            pkts: VecDeque::new(),
+            bytes: 0,
+            buffer_size,
+            policy,
+            dropped: UdpDropStats::default(),
+            waker: None,
+        }
+    }
+
+    /// Pushes `payloads` into `queue`, returns the payloads left and the datagrams the
+    /// stack counted as dropped.
+    fn overflow(queue: &mut UdpRecvQueue, payloads: &[&[u8]]) -> (Vec<Vec<u8>>, usize) {
+        let addr: SocketAddr = ([10, 0, 0, 2], 1000).into();
+        // The stack tests could overflow their sockets meanwhile.
+        let _stack = testing::lock_stack();
+        let dropped = UDP_RECV_DROPPED.load(Ordering::Relaxed);
+        let dropped_bytes = UDP_RECV_DROPPED_BYTES.load(Ordering::Relaxed);
+        for payload in payloads {
+            queue.push((payload.to_vec(), addr, addr), None);
+        }
+        let dropped = UDP_RECV_DROPPED.load(Ordering::Relaxed) - dropped;
+        let dropped_bytes = UDP_RECV_DROPPED_BYTES.load(Ordering::Relaxed) - dropped_bytes;
+        assert_eq!(dropped_bytes as u64, queue.dropped.bytes);
+        let left = queue.pkts.iter().map(|((data, _, _), _)| data.clone());
+        (left.collect(), dropped)
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let mut queue = recv_queue(2, UdpOverflowPolicy::DropNewest);
+        let (left, dropped) = overflow(&mut queue, &[b"aaa", b"bbbb", b"ccccc"]);
+        assert_eq!(left, [&b"aaa"[..], b"bbbb"]);
+        assert_eq!(queue.bytes, 7);
+        assert_eq!(
+            queue.dropped,
+            UdpDropStats {
+                packets: 1,
+                bytes: 5
+            }
+        );
+        assert_eq!(dropped, 1);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut queue = recv_queue(2, UdpOverflowPolicy::DropOldest);
+        let (left, dropped) = overflow(&mut queue, &[b"aaa", b"bbbb", b"ccccc"]);
+        assert_eq!(left, [&b"bbbb"[..], b"ccccc"]);
+        assert_eq!(queue.bytes, 9);
+        assert_eq!(
+            queue.dropped,
+            UdpDropStats {
+                packets: 1,
+                bytes: 3
+            }
+        );
+        assert_eq!(dropped, 1);
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut queue = recv_queue(2, UdpOverflowPolicy::Grow { max_bytes: 10 });
+        let payloads: [&[u8]; 5] = [b"aaa", b"bbbb", b"cc", b"ddd", b"e"];
+        let (left, dropped) = overflow(&mut queue, &payloads);
+        // Past 2 datagrams as long as the payloads fit in 10 bytes.
+        assert_eq!(left, [&b"aaa"[..], b"bbbb", b"cc", b"e"]);
+        assert_eq!(queue.bytes, 10);
+        assert_eq!(
+            queue.dropped,
+            UdpDropStats {
+                packets: 1,
+                bytes: 3
+            }
+        );
+        assert_eq!(dropped, 1);
+    }
+
+    #[test]
+    fn test_concurrent_lifecycle() {
+        // Each task holds one socket at a time, far below MEMP_NUM_UDP_PCB.
+        const TASKS: u16 = 8;
+        let _stack = testing::lock_stack();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (stack, _tcp_listener, _udp_socket) = NetStack::new().unwrap();
+            let stack = Arc::new(stack);
+            // Of a family the stack is built with.
+            let (local_ip, remote_ip): (IpAddr, IpAddr) = if cfg!(feature = "ipv4") {
+                ([10, 0, 0, 1].into(), [10, 0, 0, 2].into())
+            } else {
+                ("fd00::1".parse().unwrap(), "fd00::2".parse().unwrap())
+            };
+            let mut tasks = Vec::new();
+            for task in 0..TASKS {
+                let stack = stack.clone();
+                tasks.push(tokio::spawn(async move {
+                    for i in 0..32u16 {
+                        let port = 10000 + task * 32 + i;
+                        let addr = SocketAddr::new(local_ip, port);
+                        let socket = UdpSocket::bind(&stack, addr).unwrap();
+                        assert_eq!(socket.local_addr(), addr);
+                        let (send_half, recv_half) = socket.split();
+                        let dst = SocketAddr::new(remote_ip, 53);
+                        if i % 2 == 0 {
+                            drop(recv_half);
+                            // The pcb must outlive the receive half.
+                            let send = tokio::spawn(async move {
+                                let res = send_half.send_to(b"ping", &addr, &dst);
+                                timeout(res).await.unwrap();
+                            });
+                            timeout(send).await.unwrap();
+                        } else {
+                            timeout(send_half.send_to(b"ping", &addr, &dst))
+                                .await
+                                .unwrap();
+                            drop(send_half);
+                            drop(recv_half);
+                        }
+                    }
+                }));
+            }
+            for task in tasks {
+                timeout(task).await.unwrap();
+            }
+        });
+    }
+
+    #[cfg(feature = "ipv4")]
+    #[test]
+    fn test_same_flow_on_two_interfaces() {
+        use super::super::config::InterfaceConfig;
+        use super::super::udp_listener::UdpListener;
+        use futures::SinkExt;
+
+        let _stack = testing::lock_stack();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (mut stack, _tcp_listener, udp_socket) = NetStack::new().unwrap();
+            let mut second = stack
+                .add_interface(InterfaceConfig {
+                    ipv4_addr: [192, 168, 43, 254].into(),
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut listener = UdpListener::new(udp_socket);
+            let src: SocketAddr = ([10, 0, 0, 2], 1000).into();
+            let dst: SocketAddr = ([8, 8, 8, 8], 53).into();
+            timeout(stack.send(testing::udp(src, dst, b"q")))
+                .await
+                .unwrap();
+            timeout(second.send(testing::udp(src, dst, b"q")))
+                .await
+                .unwrap();
+            let first = timeout(listener.next()).await.unwrap();
+            let other = timeout(listener.next()).await.unwrap();
+            assert_eq!(first.interface(), Some(stack.interface_id()));
+            assert_eq!(other.interface(), Some(second.id()));
+
+            // Each flow is answered on its own interface.
+            timeout(other.send(b"a")).await.unwrap();
+            let out = timeout(second.next()).await.unwrap().unwrap();
+            assert_eq!(&out[28..], b"a");
+            timeout(first.send(b"b")).await.unwrap();
+            let out = timeout(stack.next()).await.unwrap().unwrap();
+            assert_eq!(&out[28..], b"b");
+        });
+    }
+
+    #[cfg(feature = "ipv4")]
+    #[test]
+    fn test_unpolled_interface() {
+        use super::super::config::InterfaceConfig;
+        use futures::FutureExt;
+
+        let _stack = testing::lock_stack();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (mut stack, _tcp_listener, udp_socket) = NetStack::new().unwrap();
+            let second = stack
+                .add_interface(InterfaceConfig {
+                    stack_buffer_size: 1,
+                    ipv4_addr: [192, 168, 43, 254].into(),
+                    ..Default::default()
+                })
+                .unwrap();
+            stack.add_route("192.168.43.0/24".parse().unwrap(), second.id());
+            let (send_half, _recv_half) = udp_socket.split();
+            let remote: SocketAddr = ([8, 8, 8, 8], 53).into();
+            let behind_second: SocketAddr = ([192, 168, 43, 2], 1000).into();
+            timeout(send_half.send_to(b"a", &remote, &behind_second))
+                .await
+                .unwrap();
+            let send = send_half.send_to(b"b", &remote, &behind_second);
+            assert!(send.now_or_never().is_none());
+
+            // The full stream of the second interface doesn't hold back the first one.
+            let behind_first: SocketAddr = ([10, 0, 0, 2], 1000).into();
+            timeout(send_half.send_to(b"c", &remote, &behind_first))
+                .await
+                .unwrap();
+            let out = timeout(stack.next()).await.unwrap().unwrap();
+            assert_eq!(&out[28..], b"c");
+        });
+    }
+
+    #[test]
+    fn test_many_bound_sockets() {
+        let _stack = testing::lock_stack();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (stack, _tcp_listener, _udp_socket) = NetStack::new().unwrap();
+            let local_ip: IpAddr = if cfg!(feature = "ipv4") {
+                [10, 0, 1, 1].into()
+            } else {
+                "fd00:1::1".parse().unwrap()
+            };
+            // Far more than lwIP has timeouts for, sockets must not take one each.
+            let sockets: Vec<_> = (0..32u16)
+                .map(|i| UdpSocket::bind(&stack, SocketAddr::new(local_ip, 20000 + i)).unwrap())
+                .collect();
+            // Lets the stack run its timers and flow sweeps with the sockets alive.
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            for socket in sockets {
+                let addr = socket.local_addr();
+                let (send_half, _recv_half) = socket.split();
+                let dst = SocketAddr::new(local_ip, 53);
+                timeout(send_half.send_to(b"ping", &addr, &dst))
+                    .await
+                    .unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::stream::Stream;
+use futures::task::{Context, Poll};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::{interval, Instant, Interval};
+
+use super::interface::InterfaceId;
+use super::udp::{RecvHalf, SendHalf, UdpSocket};
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Flows idle for a zero timeout are swept this often, `interval` can't tick every 0s.
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_millis(1);
+const FLOW_BUFFER_SIZE: usize = 64;
+
+/// State shared by a `UdpFlow` and its entry in the listener.
+struct UdpFlowShared {
+    last_active: Mutex<Instant>,
+    expired: AtomicBool,
+    closed: AtomicBool,
+}
+
+impl UdpFlowShared {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    fn idle_since(&self, now: Instant) -> Duration {
+        let last_active = *self.last_active.lock().unwrap_or_else(|e| e.into_inner());
+        now.saturating_duration_since(last_active)
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.expired.load(Ordering::Acquire) {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "udp flow expired"))
+        } else if self.closed.load(Ordering::Acquire) {
+            Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "udp listener closed",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+struct UdpFlowEntry {
+    tx: Sender<Vec<u8>>,
+    shared: Arc<UdpFlowShared>,
+}
+
+/// Demultiplexes the datagrams of a `UdpSocket` into a `UdpFlow` per (src, dst) pair and
+/// interface.
+///
+/// Flows are expired after being idle, in both directions, for the idle timeout. Datagrams
+/// are only dispatched while the listener is polled.
+pub struct UdpListener {
+    send_half: Arc<SendHalf>,
+    recv_half: RecvHalf,
+    flows: HashMap<(Option<InterfaceId>, SocketAddr, SocketAddr), UdpFlowEntry>,
+    idle_timeout: Duration,
+    sweep: Interval,
+}
+
+impl UdpListener {
+    pub fn new(socket: Box<UdpSocket>) -> Self {
+        Self::with_idle_timeout(socket, DEFAULT_IDLE_TIMEOUT)
+    }
+
+    pub fn with_idle_timeout(socket: Box<UdpSocket>, idle_timeout: Duration) -> Self {
+        let (send_half, recv_half) = socket.split();
+        UdpListener {
+            send_half: Arc::new(send_half),
+            recv_half,
+            flows: HashMap::new(),
+            idle_timeout,
+            sweep: interval(SWEEP_INTERVAL.min(idle_timeout).max(MIN_SWEEP_INTERVAL)),
+        }
+    }
+
+    /// Hands the datagram to its flow, returns the flow if it's a new one.
+    fn dispatch(
+        &mut self,
+        data: Vec<u8>,
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        interface: Option<InterfaceId>,
+    ) -> Option<UdpFlow> {
+        if let Some(entry) = self.flows.get(&(interface, src_addr, dst_addr)) {
+            if !entry.tx.is_closed() {
+                entry.shared.touch();
+                // Drop the datagram if the flow isn't keeping up, as a socket would.
+                let _ = entry.tx.try_send(data);
+                return None;
+            }
+        }
+        let (tx, rx) = channel(FLOW_BUFFER_SIZE);
+        let _ = tx.try_send(data);
+        let shared = Arc::new(UdpFlowShared {
+            last_active: Mutex::new(Instant::now()),
+            expired: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        });
+        self.flows.insert(
+            (interface, src_addr, dst_addr),
+            UdpFlowEntry {
+                tx,
+                shared: shared.clone(),
+            },
+        );
+        Some(UdpFlow {
+            local_addr: src_addr,
+            remote_addr: dst_addr,
+            interface,
+            rx,
+            send_half: self.send_half.clone(),
+            shared,
+        })
+    }
+
+    fn expire_flows(&mut self, now: Instant) {
+        let idle_timeout = self.idle_timeout;
+        self.flows.retain(|_, entry| {
+            if entry.tx.is_closed() {
+                return false;
+            }
+            if entry.shared.idle_since(now) < idle_timeout {
+                return true;
+            }
+            entry.shared.expired.store(true, Ordering::Release);
+            false
+        });
+    }
+}
+
+impl Drop for UdpListener {
+    fn drop(&mut self) {
+        for entry in self.flows.values() {
+            entry.shared.closed.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Stream for UdpListener {
+    type Item = UdpFlow;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        while self.sweep.poll_tick(cx).is_ready() {
+            self.expire_flows(Instant::now());
+        }
+        loop {
+            match self.recv_half.poll_recv(cx) {
+                Poll::Ready(((data, src_addr, dst_addr), interface)) => {
+                    if let Some(flow) = self.dispatch(data, src_addr, dst_addr, interface) {
+                        return Poll::Ready(Some(flow));
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Datagrams exchanged between one source on the device and one destination, on one
+/// interface.
+pub struct UdpFlow {
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    interface: Option<InterfaceId>,
+    rx: Receiver<Vec<u8>>,
+    send_half: Arc<SendHalf>,
+    shared: Arc<UdpFlowShared>,
+}
+
+impl UdpFlow {
+    /// The device side of the flow.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The destination the device is talking to.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// The interface the flow came in on and is answered on.
+    pub fn interface(&self) -> Option<InterfaceId> {
+        self.interface
+    }
+
+    /// Receives the next datagram sent by the device, fails once the flow has expired.
+    pub async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        match self.rx.recv().await {
+            Some(data) => Ok(data),
+            None => {
+                Err(self.shared.check().err().unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "udp flow closed")
+                }))
+            }
+        }
+    }
+
+    /// Sends a datagram to the device, from the remote address of the flow.
+    pub async fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.shared.check()?;
+        self.shared.touch();
+        self.send_half
+            .send_on(data, &self.remote_addr, &self.local_addr, self.interface)
+            .await
+    }
+}
+
+impl Stream for UdpFlow {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{self, timeout};
+    use crate::NetStack;
+    use futures::{FutureExt, SinkExt, StreamExt};
+
+    /// A source on the device and a destination, of a family the stack is built with.
+    fn addrs() -> (SocketAddr, SocketAddr) {
+        if cfg!(feature = "ipv4") {
+            (([10, 0, 0, 2], 1000).into(), ([8, 8, 8, 8], 53).into())
+        } else {
+            (
+                "[fd00::2]:1000".parse().unwrap(),
+                "[2001:db8::1]:53".parse().unwrap(),
+            )
+        }
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_flows() {
+        let _stack = testing::lock_stack();
+        runtime().block_on(async {
+            let (mut stack, _tcp_listener, udp_socket) = NetStack::new().unwrap();
+            let idle_timeout = Duration::from_secs(10);
+            let mut listener = UdpListener::with_idle_timeout(udp_socket, idle_timeout);
+            let (src, dst) = addrs();
+            let other = SocketAddr::new(src.ip(), src.port() + 1);
+
+            // The first datagram of a pair opens a flow.
+            timeout(stack.send(testing::udp(src, dst, b"a")))
+                .await
+                .unwrap();
+            let mut flow = timeout(listener.next()).await.unwrap();
+            assert_eq!((flow.local_addr(), flow.remote_addr()), (src, dst));
+            assert_eq!(flow.interface(), Some(stack.interface_id()));
+            assert_eq!(timeout(flow.recv()).await.unwrap(), b"a");
+
+            // The next ones go to the same flow, another pair gets its own.
+            timeout(stack.send(testing::udp(src, dst, b"b")))
+                .await
+                .unwrap();
+            timeout(stack.send(testing::udp(other, dst, b"c")))
+                .await
+                .unwrap();
+            let mut second = timeout(listener.next()).await.unwrap();
+            assert_eq!((second.local_addr(), second.remote_addr()), (other, dst));
+            assert_eq!(timeout(flow.recv()).await.unwrap(), b"b");
+            assert_eq!(timeout(second.recv()).await.unwrap(), b"c");
+
+            // Sending keeps a flow alive, the idle one expires.
+            tokio::time::advance(idle_timeout / 2 + Duration::from_secs(1)).await;
+            timeout(flow.send(b"d")).await.unwrap();
+            tokio::time::advance(idle_timeout / 2 + Duration::from_secs(1)).await;
+            assert!(listener.next().now_or_never().is_none());
+            let err = timeout(second.recv()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            let err = timeout(second.send(b"e")).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            timeout(flow.send(b"f")).await.unwrap();
+
+            tokio::time::advance(idle_timeout + Duration::from_secs(1)).await;
+            assert!(listener.next().now_or_never().is_none());
+            let err = timeout(flow.recv()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        });
+    }
+
+    #[test]
+    fn test_zero_idle_timeout() {
+        let _stack = testing::lock_stack();
+        runtime().block_on(async {
+            let (mut stack, _tcp_listener, udp_socket) = NetStack::new().unwrap();
+            let mut listener = UdpListener::with_idle_timeout(udp_socket, Duration::ZERO);
+            let (src, dst) = addrs();
+            timeout(stack.send(testing::udp(src, dst, b"a")))
+                .await
+                .unwrap();
+            let mut flow = timeout(listener.next()).await.unwrap();
+
+            // Expired at the next sweep, after its datagrams.
+            tokio::time::advance(MIN_SWEEP_INTERVAL).await;
+            assert!(listener.next().now_or_never().is_none());
+            assert_eq!(timeout(flow.recv()).await.unwrap(), b"a");
+            let err = timeout(flow.recv()).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        });
+    }
+}
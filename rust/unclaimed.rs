@@ -0,0 +1,197 @@
+use super::lwip::*;
+use super::LWIP_MUTEX;
+
+/// Destination unreachable code answered to unclaimed traffic, sent as the matching
+/// ICMP code for IPv4 and ICMPv6 code for IPv6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnreachableCode {
+    /// Network unreachable, no route to destination for ICMPv6.
+    Network,
+    /// Host unreachable, address unreachable for ICMPv6.
+    Host,
+    /// Port unreachable.
+    Port,
+    /// Communication administratively prohibited.
+    AdminProhibited,
+}
+
+impl UnreachableCode {
+    /// `(icmp_code, icmp6_code)`, see RFC 792/1812 and RFC 4443.
+    fn codes(self) -> (u8, u8) {
+        match self {
+            UnreachableCode::Network => (0, 0),
+            UnreachableCode::Host => (1, 3),
+            UnreachableCode::Port => (3, 4),
+            UnreachableCode::AdminProhibited => (13, 1),
+        }
+    }
+}
+
+/// How the stack answers traffic that no `TcpListener`, `UdpSocket` or connection claims,
+/// e.g. once the listener or the socket has been dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnclaimedPolicy {
+    /// Drop the packet, the client times out.
+    Drop,
+    /// Answer with a TCP RST. UDP has no reset, datagrams get an ICMP port unreachable.
+    Reset,
+    /// Answer with an ICMP or ICMPv6 destination unreachable.
+    Unreachable(UnreachableCode),
+}
+
+impl UnclaimedPolicy {
+    fn to_reply(self, tcp: bool) -> unclaimed_reply {
+        let (action, code) = match self {
+            UnclaimedPolicy::Drop => (UNCLAIMED_REPLY_DROP, UnreachableCode::Port),
+            UnclaimedPolicy::Reset if tcp => (UNCLAIMED_REPLY_RST, UnreachableCode::Port),
+            UnclaimedPolicy::Reset => (UNCLAIMED_REPLY_ICMP, UnreachableCode::Port),
+            UnclaimedPolicy::Unreachable(code) => (UNCLAIMED_REPLY_ICMP, code),
+        };
+        let (icmp_code, icmp6_code) = code.codes();
+        unclaimed_reply {
+            action: action as u8_t,
+            icmp_code,
+            icmp6_code,
+        }
+    }
+}
+
+pub(crate) fn set_tcp(policy: UnclaimedPolicy) {
+    let _g = LWIP_MUTEX.lock();
+    unsafe { tcp_unclaimed_reply = policy.to_reply(true) };
+}
+
+pub(crate) fn set_udp(policy: UnclaimedPolicy) {
+    let _g = LWIP_MUTEX.lock();
+    unsafe { udp_unclaimed_reply = policy.to_reply(false) };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{self, timeout};
+    use crate::{util, NetStack};
+    use futures::{SinkExt, StreamExt};
+    use std::net::SocketAddr;
+    use UnclaimedPolicy::{Drop, Reset, Unreachable};
+    use UnreachableCode::*;
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(Network.codes(), (0, 0));
+        assert_eq!(Host.codes(), (1, 3));
+        assert_eq!(Port.codes(), (3, 4));
+        assert_eq!(AdminProhibited.codes(), (13, 1));
+    }
+
+    #[test]
+    fn test_to_reply() {
+        let reply = |policy: UnclaimedPolicy, tcp| {
+            let reply = policy.to_reply(tcp);
+            (reply.action as u32, reply.icmp_code, reply.icmp6_code)
+        };
+        assert_eq!(reply(Drop, true), (UNCLAIMED_REPLY_DROP, 3, 4));
+        assert_eq!(reply(Drop, false), (UNCLAIMED_REPLY_DROP, 3, 4));
+        assert_eq!(reply(Reset, true), (UNCLAIMED_REPLY_RST, 3, 4));
+        // UDP has no reset.
+        assert_eq!(reply(Reset, false), (UNCLAIMED_REPLY_ICMP, 3, 4));
+        for tcp in [true, false] {
+            let policy = Unreachable(AdminProhibited);
+            assert_eq!(reply(policy, tcp), (UNCLAIMED_REPLY_ICMP, 13, 1));
+        }
+    }
+
+    /// The next TCP segment or destination unreachable out of the stack, skipping the
+    /// neighbor discovery and the like.
+    async fn next_reply(stack: &mut NetStack) -> Vec<u8> {
+        loop {
+            let pkt = stack.next().await.unwrap().unwrap();
+            let (_, _, protocol, l4) = util::split_ip_packet(&pkt).unwrap();
+            match (protocol, l4.first()) {
+                (testing::IPPROTO_TCP, _)
+                | (testing::IPPROTO_ICMP, Some(3))
+                | (testing::IPPROTO_ICMPV6, Some(1)) => return pkt,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_replies() {
+        let _stack = testing::lock_stack();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (mut stack, tcp_listener, udp_socket) = NetStack::new().unwrap();
+            drop(tcp_listener);
+            drop(udp_socket);
+            let mut pairs: Vec<(SocketAddr, SocketAddr)> = Vec::new();
+            if cfg!(feature = "ipv4") {
+                pairs.push((([10, 0, 0, 2], 0).into(), ([8, 8, 8, 8], 80).into()));
+            }
+            if cfg!(feature = "ipv6") {
+                let dst = "[2001:db8::1]:80".parse().unwrap();
+                pairs.push(("[fd00::2]:0".parse().unwrap(), dst));
+            }
+            // Each dropped probe is followed by an answered one, which must be the next
+            // reply.
+            let cases = [
+                (Drop, true),
+                (Reset, true),
+                (Drop, false),
+                (Reset, false),
+                (Unreachable(Host), true),
+                (Unreachable(AdminProhibited), false),
+            ];
+            for (src, dst) in pairs {
+                for (port, &(policy, tcp)) in (1000..).zip(cases.iter()) {
+                    let src = SocketAddr::new(src.ip(), port);
+                    let probe = if tcp {
+                        stack.set_tcp_unclaimed_policy(policy);
+                        testing::tcp(src, dst, 0x02, b"")
+                    } else {
+                        stack.set_udp_unclaimed_policy(policy);
+                        testing::udp(src, dst, b"a")
+                    };
+                    timeout(stack.send(probe)).await.unwrap();
+                    if policy == Drop {
+                        continue;
+                    }
+                    let reply = timeout(next_reply(&mut stack)).await;
+                    testing::check(&reply);
+                    let (from, to, protocol, l4) = util::split_ip_packet(&reply).unwrap();
+                    // From the address the probe was sent to.
+                    assert_eq!((from, to), (dst.ip(), src.ip()));
+                    if policy == Reset && tcp {
+                        assert_eq!(protocol, testing::IPPROTO_TCP);
+                        assert_eq!(
+                            &l4[..4],
+                            [&dst.port().to_be_bytes()[..], &port.to_be_bytes()].concat()
+                        );
+                        assert_ne!(l4[13] & 0x04, 0, "RST");
+                        continue;
+                    }
+                    let code = match policy {
+                        Unreachable(code) => code,
+                        _ => Port,
+                    };
+                    let (icmp_code, icmp6_code) = code.codes();
+                    let quoted = if src.is_ipv4() {
+                        assert_eq!((l4[0], l4[1]), (3, icmp_code));
+                        &l4[8 + 20..]
+                    } else {
+                        assert_eq!((l4[0], l4[1]), (1, icmp6_code));
+                        &l4[8 + 40..]
+                    };
+                    assert_eq!(&quoted[..2], port.to_be_bytes());
+                }
+            }
+            // The policies are global, back to the defaults for the other tests.
+            stack.set_tcp_unclaimed_policy(Reset);
+            stack.set_udp_unclaimed_policy(Drop);
+        });
+    }
+}
@@ -1,74 +1,355 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(feature = "ipv6")]
+use std::net::SocketAddrV6;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
 use super::lwip::*;
+use crate::Error;
 
-// FIXME Need to verify the byte order of Ipv6 address in lwIP.
-
+/// The address of `addr` and the zone of scoped IPv6 addresses as the scope id. The
+/// dual-stack `IPADDR_TYPE_ANY` is `[::]`, unknown types are logged and unspecified.
+///
+/// The fallback is never taken: the callers only pass addresses lwIP filled in, the
+/// endpoints of its pcbs, the addresses of its netifs and those of the packets it
+/// received, which are all typed IPv4, IPv6 or any. Addresses from elsewhere must go
+/// through `IpAddr::try_from` instead.
 pub fn to_socket_addr(addr: &ip_addr_t, port: u16_t) -> SocketAddr {
+    match IpAddr::try_from(addr) {
+        #[cfg(feature = "ipv6")]
+        Ok(IpAddr::V6(ip)) => {
+            let zone = ip_2_ip6(addr).zone;
+            SocketAddr::V6(SocketAddrV6::new(ip, port, 0, zone as u32))
+        }
+        Ok(ip) => SocketAddr::new(ip, port),
+        Err(e) => {
+            log::warn!("{}", e);
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)
+        }
+    }
+}
+
+/// The inverse of `to_socket_addr`, the scope id is kept as the zone of scoped IPv6
+/// addresses if it's a netif index.
+pub fn from_socket_addr(addr: &SocketAddr) -> Result<(ip_addr_t, u16_t), Error> {
+    #[allow(unused_mut)]
+    let mut ip = to_ip_addr_t(addr.ip())?;
+    #[cfg(feature = "ipv6")]
+    if let (SocketAddr::V6(addr), Ok(IpAddr::V6(ip6))) = (addr, IpAddr::try_from(&ip)) {
+        if ip6_has_scope(&ip6) {
+            ip_2_ip6_mut(&mut ip).zone = u8_t::try_from(addr.scope_id()).unwrap_or(0);
+        }
+    }
+    Ok((ip, addr.port()))
+}
+
+/// Whether lwIP keeps a zone with `ip`, i.e. link-local unicast and interface- or
+/// link-local multicast, see `ip6_addr_has_scope`.
+#[cfg(feature = "ipv6")]
+fn ip6_has_scope(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    first & 0xffc0 == 0xfe80 || (first & 0xff00 == 0xff00 && matches!(first & 0x000f, 1 | 2))
+}
+
+/// The IPv4 address embedded in `addr` if it's in the /96 `prefix`, see RFC 6052.
+pub fn unmap_nat64(addr: SocketAddr, prefix: &Ipv6Addr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) if ip.octets()[..12] == prefix.octets()[..12] => {
+            let embedded: [u8; 4] = ip.octets()[12..].try_into().unwrap();
+            SocketAddr::new(Ipv4Addr::from(embedded).into(), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+/// `ip` embedded in the /96 `prefix`, see RFC 6052.
+pub fn map_nat64(ip: Ipv4Addr, prefix: &Ipv6Addr) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[12..].copy_from_slice(&ip.octets());
+    octets.into()
+}
+
+/// The lwIP address of `ip`, IPv4-mapped IPv6 addresses become IPv4 since lwIP doesn't
+/// route them, IPv6 addresses have no zone. Fails for the family the stack is built
+/// without, see the `ipv4` and `ipv6` features.
+pub fn to_ip_addr_t(ip: IpAddr) -> Result<ip_addr_t, Error> {
+    ip.try_into()
+}
+
+/// lwIP's `IP_ANY_TYPE`, the unspecified address of every enabled family.
+pub fn ip_any_type() -> ip_addr_t {
     unsafe {
-        match addr.type_ {
-            // Ipv4
-            0 => SocketAddr::new(IpAddr::V4(addr.u_addr.ip4.addr.to_ne_bytes().into()), port),
-            // Ipv6
-            6 => {
-                let addr = addr.u_addr.ip6.addr;
-                let p0 = addr[0].to_ne_bytes();
-                let p1 = addr[1].to_ne_bytes();
-                let p2 = addr[2].to_ne_bytes();
-                let p3 = addr[3].to_ne_bytes();
-                let mut p = [0u8; 16];
-                p[0..4].copy_from_slice(&p0);
-                p[4..8].copy_from_slice(&p1);
-                p[8..12].copy_from_slice(&p2);
-                p[12..16].copy_from_slice(&p3);
-                let addr = Ipv6Addr::from(p);
-                SocketAddr::new(IpAddr::V6(addr), port)
-            }
-            // FIXME Ipv4+Ipv6 (dual-stack)
-            _ => {
-                log::warn!("Unsupported IP address type");
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)
+        #[cfg(all(feature = "ipv4", feature = "ipv6"))]
+        {
+            ip_addr_any_type
+        }
+        #[cfg(not(feature = "ipv6"))]
+        {
+            ip_addr_any
+        }
+        #[cfg(not(feature = "ipv4"))]
+        {
+            ip6_addr_any
+        }
+    }
+}
+
+/// lwIP's `ip_2_ip4`, the IPv4 address held by `addr`.
+#[cfg(all(feature = "ipv4", feature = "ipv6"))]
+pub fn ip_2_ip4(addr: &ip_addr_t) -> &ip4_addr_t {
+    unsafe { &addr.u_addr.ip4 }
+}
+
+#[cfg(not(feature = "ipv6"))]
+pub fn ip_2_ip4(addr: &ip_addr_t) -> &ip4_addr_t {
+    addr
+}
+
+/// lwIP's `ip_2_ip6`, the IPv6 address held by `addr`.
+#[cfg(all(feature = "ipv4", feature = "ipv6"))]
+pub fn ip_2_ip6(addr: &ip_addr_t) -> &ip6_addr_t {
+    unsafe { &addr.u_addr.ip6 }
+}
+
+#[cfg(not(feature = "ipv4"))]
+pub fn ip_2_ip6(addr: &ip_addr_t) -> &ip6_addr_t {
+    addr
+}
+
+#[cfg(all(feature = "ipv4", feature = "ipv6"))]
+fn ip_2_ip6_mut(addr: &mut ip_addr_t) -> &mut ip6_addr_t {
+    unsafe { &mut addr.u_addr.ip6 }
+}
+
+#[cfg(not(feature = "ipv4"))]
+fn ip_2_ip6_mut(addr: &mut ip_addr_t) -> &mut ip6_addr_t {
+    addr
+}
+
+#[cfg(feature = "ipv4")]
+impl From<Ipv4Addr> for ip4_addr_t {
+    fn from(ip: Ipv4Addr) -> Self {
+        // Network byte order, the octets as they are in memory.
+        ip4_addr {
+            addr: u32::from_ne_bytes(ip.octets()),
+        }
+    }
+}
+
+#[cfg(feature = "ipv6")]
+impl From<Ipv6Addr> for ip6_addr_t {
+    fn from(ip: Ipv6Addr) -> Self {
+        // Each word in network byte order like `ip4_addr_t`.
+        let octets = ip.octets();
+        let mut addr = [0; 4];
+        for (word, chunk) in addr.iter_mut().zip(octets.chunks(4)) {
+            *word = u32::from_ne_bytes(chunk.try_into().unwrap());
+        }
+        ip6_addr {
+            addr,
+            zone: IP6_NO_ZONE as u8_t,
+        }
+    }
+}
+
+impl TryFrom<IpAddr> for ip_addr_t {
+    type Error = Error;
+
+    fn try_from(ip: IpAddr) -> Result<Self, Error> {
+        #[cfg(feature = "ipv4")]
+        let ip = match ip {
+            IpAddr::V6(ip6) => ip6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match ip {
+            #[cfg(feature = "ipv4")]
+            IpAddr::V4(ip4) => Ok(ip4.into()),
+            #[cfg(feature = "ipv6")]
+            IpAddr::V6(ip6) => Ok(ip6.into()),
+            #[allow(unreachable_patterns)]
+            ip => Err(Error::UnsupportedFamily(ip)),
+        }
+    }
+}
+
+// Single-stack builds have `ip_addr_t` be the address of their family instead.
+
+#[cfg(all(feature = "ipv4", feature = "ipv6"))]
+impl From<Ipv4Addr> for ip_addr_t {
+    fn from(ip: Ipv4Addr) -> Self {
+        ip_addr_t {
+            u_addr: ip_addr__bindgen_ty_1 { ip4: ip.into() },
+            type_: lwip_ip_addr_type_IPADDR_TYPE_V4 as u8_t,
+        }
+    }
+}
+
+#[cfg(all(feature = "ipv4", feature = "ipv6"))]
+impl From<Ipv6Addr> for ip_addr_t {
+    fn from(ip: Ipv6Addr) -> Self {
+        ip_addr_t {
+            u_addr: ip_addr__bindgen_ty_1 { ip6: ip.into() },
+            type_: lwip_ip_addr_type_IPADDR_TYPE_V6 as u8_t,
+        }
+    }
+}
+
+#[cfg(feature = "ipv4")]
+impl From<&ip4_addr_t> for Ipv4Addr {
+    fn from(addr: &ip4_addr_t) -> Self {
+        addr.addr.to_ne_bytes().into()
+    }
+}
+
+#[cfg(feature = "ipv6")]
+impl From<&ip6_addr_t> for Ipv6Addr {
+    fn from(addr: &ip6_addr_t) -> Self {
+        let mut octets = [0u8; 16];
+        for (chunk, word) in octets.chunks_mut(4).zip(addr.addr) {
+            chunk.copy_from_slice(&word.to_ne_bytes());
+        }
+        octets.into()
+    }
+}
+
+impl TryFrom<&ip_addr_t> for IpAddr {
+    type Error = Error;
+
+    /// Fails for types other than IPv4, IPv6 and the dual-stack any type, which is `[::]`.
+    #[cfg(all(feature = "ipv4", feature = "ipv6"))]
+    #[allow(non_upper_case_globals)]
+    fn try_from(addr: &ip_addr_t) -> Result<Self, Error> {
+        match addr.type_ as lwip_ip_addr_type {
+            lwip_ip_addr_type_IPADDR_TYPE_V4 => Ok(IpAddr::V4(ip_2_ip4(addr).into())),
+            lwip_ip_addr_type_IPADDR_TYPE_V6 | lwip_ip_addr_type_IPADDR_TYPE_ANY => {
+                Ok(IpAddr::V6(ip_2_ip6(addr).into()))
             }
+            type_ => Err(Error::InvalidAddrType(type_ as u8)),
         }
     }
+
+    #[cfg(not(feature = "ipv6"))]
+    fn try_from(addr: &ip_addr_t) -> Result<Self, Error> {
+        Ok(IpAddr::V4(addr.into()))
+    }
+
+    #[cfg(not(feature = "ipv4"))]
+    fn try_from(addr: &ip_addr_t) -> Result<Self, Error> {
+        Ok(IpAddr::V6(addr.into()))
+    }
+}
+
+#[allow(non_upper_case_globals)]
+pub fn to_io_error_kind(err: err_t) -> io::ErrorKind {
+    match err as err_enum_t {
+        err_enum_t_ERR_MEM | err_enum_t_ERR_BUF => io::ErrorKind::OutOfMemory,
+        err_enum_t_ERR_TIMEOUT => io::ErrorKind::TimedOut,
+        err_enum_t_ERR_INPROGRESS | err_enum_t_ERR_WOULDBLOCK => io::ErrorKind::WouldBlock,
+        err_enum_t_ERR_VAL | err_enum_t_ERR_ARG => io::ErrorKind::InvalidInput,
+        err_enum_t_ERR_USE => io::ErrorKind::AddrInUse,
+        err_enum_t_ERR_CONN => io::ErrorKind::NotConnected,
+        err_enum_t_ERR_ABRT => io::ErrorKind::ConnectionAborted,
+        err_enum_t_ERR_RST => io::ErrorKind::ConnectionReset,
+        err_enum_t_ERR_CLSD => io::ErrorKind::BrokenPipe,
+        _ => io::ErrorKind::Other,
+    }
 }
 
-pub fn to_ip_addr_t(ip: IpAddr) -> ip_addr_t {
-    match ip {
-        IpAddr::V4(ip4) => {
-            ip_addr_t {
-                u_addr: ip_addr__bindgen_ty_1 {
-                    ip4: ip4_addr {
-                        addr: u32::from_ne_bytes(ip4.octets()),
-                    },
-                },
-                type_: 0, // Ipv4
+/// Splits an IPv4 or IPv6 packet into `(src, dst, protocol, payload)`, the payload
+/// is bounded by the length in the header. IPv6 extension headers aren't walked, the
+/// protocol is the next header of the fixed header.
+pub fn split_ip_packet(pkt: &[u8]) -> Option<(IpAddr, IpAddr, u8, &[u8])> {
+    match pkt.first()? >> 4 {
+        4 => {
+            let hdr_len = ((pkt[0] & 0x0f) as usize) * 4;
+            if hdr_len < 20 || pkt.len() < hdr_len {
+                return None;
             }
+            let tot_len = u16::from_be_bytes([pkt[2], pkt[3]]) as usize;
+            if tot_len < hdr_len {
+                return None;
+            }
+            let src: [u8; 4] = pkt[12..16].try_into().ok()?;
+            let dst: [u8; 4] = pkt[16..20].try_into().ok()?;
+            let payload = &pkt[hdr_len..tot_len.min(pkt.len())];
+            Some((
+                Ipv4Addr::from(src).into(),
+                Ipv4Addr::from(dst).into(),
+                pkt[9],
+                payload,
+            ))
         }
-        IpAddr::V6(ip6) => {
-            let bytes = ip6.octets();
-            let mut p0 = [0u8; 4];
-            let mut p1 = [0u8; 4];
-            let mut p2 = [0u8; 4];
-            let mut p3 = [0u8; 4];
-            p0.copy_from_slice(&bytes[0..4]);
-            p1.copy_from_slice(&bytes[4..8]);
-            p2.copy_from_slice(&bytes[8..12]);
-            p3.copy_from_slice(&bytes[12..16]);
-            let addr = [
-                u32::from_ne_bytes(p0),
-                u32::from_ne_bytes(p1),
-                u32::from_ne_bytes(p2),
-                u32::from_ne_bytes(p3),
-            ];
-            ip_addr_t {
-                u_addr: ip_addr__bindgen_ty_1 {
-                    ip6: ip6_addr { addr, zone: 0 },
-                },
-                type_: 6, // Ipv6
+        6 => {
+            if pkt.len() < 40 {
+                return None;
             }
+            let payload_len = u16::from_be_bytes([pkt[4], pkt[5]]) as usize;
+            let src: [u8; 16] = pkt[8..24].try_into().ok()?;
+            let dst: [u8; 16] = pkt[24..40].try_into().ok()?;
+            let payload = &pkt[40..(40 + payload_len).min(pkt.len())];
+            Some((
+                Ipv6Addr::from(src).into(),
+                Ipv6Addr::from(dst).into(),
+                pkt[6],
+                payload,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Internet checksum (RFC 1071) of `data`, `sum` carries the partial sum of a pseudo-header.
+pub fn inet_checksum(data: &[u8], mut sum: u32) -> u16 {
+    for chunk in data.chunks(2) {
+        let word = match *chunk {
+            [hi, lo] => u16::from_be_bytes([hi, lo]),
+            [hi] => u16::from_be_bytes([hi, 0]),
+            _ => unreachable!(),
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Updates `checksum` for the data `old` replaced by `new`, both of the same even length,
+/// see RFC 1624.
+pub fn checksum_adjust(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = !checksum as u32;
+    for (old, new) in old.chunks(2).zip(new.chunks(2)) {
+        sum += !u16::from_be_bytes([old[0], old[1]]) as u32;
+        sum += u16::from_be_bytes([new[0], new[1]]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Partial sum of the IPv4 pseudo-header, see RFC 793 section 3.1.
+#[cfg(test)]
+pub fn ip4_pseudo_sum(src: &Ipv4Addr, dst: &Ipv4Addr, len: u32, protocol: u8) -> u32 {
+    let mut sum = 0u32;
+    for addr in [src, dst] {
+        let octets = addr.octets();
+        sum += u16::from_be_bytes([octets[0], octets[1]]) as u32;
+        sum += u16::from_be_bytes([octets[2], octets[3]]) as u32;
+    }
+    sum + len + protocol as u32
+}
+
+/// Partial sum of the IPv6 pseudo-header, see RFC 8200 section 8.1.
+pub fn ip6_pseudo_sum(src: &Ipv6Addr, dst: &Ipv6Addr, len: u32, next_header: u8) -> u32 {
+    let mut sum = 0u32;
+    for addr in [src, dst] {
+        for segment in addr.segments() {
+            sum += segment as u32;
         }
     }
+    sum + (len >> 16) + (len & 0xffff) + next_header as u32
 }
 
 #[cfg(test)]
@@ -76,22 +357,176 @@ mod test {
     use super::*;
 
     #[test]
+    #[cfg(all(feature = "ipv4", feature = "ipv6"))]
     fn test_to_socket_addr() {
         unsafe {
             let addr = to_socket_addr(&ip_addr_any_type, 80);
-            assert_eq!(addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 80));
+            assert_eq!(addr, SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 80));
             let mut v6_addr = ip_addr_any_type;
             v6_addr.type_ = 6;
             let addr = to_socket_addr(&v6_addr, 80);
             assert_eq!(addr, SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 80));
+            // Not a type lwIP produces, see to_socket_addr.
+            let mut bad_addr = ip_addr_any_type;
+            bad_addr.type_ = 1;
+            let addr = to_socket_addr(&bad_addr, 80);
+            assert_eq!(addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 80));
         }
     }
 
     #[test]
+    #[cfg(all(feature = "ipv4", feature = "ipv6"))]
     fn test_to_ip_addr_t() {
         let addr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
-        assert_eq!(to_ip_addr_t(addr).type_, 0);
+        assert_eq!(to_ip_addr_t(addr).unwrap().type_, 0);
         let addr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
-        assert_eq!(to_ip_addr_t(addr).type_, 6);
+        assert_eq!(to_ip_addr_t(addr).unwrap().type_, 6);
+        let addr: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        let ip = to_ip_addr_t(addr).unwrap();
+        assert_eq!(ip.type_, 0);
+        assert_eq!(IpAddr::try_from(&ip).unwrap(), Ipv4Addr::new(192, 0, 2, 1));
+    }
+
+    #[test]
+    fn test_unsupported_family() {
+        let ip4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let ip6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert_eq!(to_ip_addr_t(ip4).is_ok(), cfg!(feature = "ipv4"));
+        assert_eq!(to_ip_addr_t(ip6).is_ok(), cfg!(feature = "ipv6"));
+        for ip in [ip4, ip6] {
+            if let Ok(addr) = to_ip_addr_t(ip) {
+                assert_eq!(to_socket_addr(&addr, 1).ip(), ip);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "ipv6")]
+    fn test_scope_id() {
+        let addr: SocketAddr = "[fe80::1%3]:53".parse().unwrap();
+        let (ip, port) = from_socket_addr(&addr).unwrap();
+        assert_eq!(ip_2_ip6(&ip).zone, 3);
+        assert_eq!(to_socket_addr(&ip, port), addr);
+        // Unscoped addresses never have a zone.
+        let addr: SocketAddr = "[2001:db8::1%3]:53".parse().unwrap();
+        let (ip, port) = from_socket_addr(&addr).unwrap();
+        assert_eq!(ip_2_ip6(&ip).zone, 0);
+        assert_eq!(
+            to_socket_addr(&ip, port),
+            "[2001:db8::1]:53".parse().unwrap()
+        );
+    }
+
+    /// `ip` as lwIP keeps it, see `to_ip_addr_t`.
+    fn canonical(ip: IpAddr) -> IpAddr {
+        match ip {
+            #[cfg(feature = "ipv4")]
+            IpAddr::V6(ip6) => ip6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        }
+    }
+
+    // `ipaddr_ntoa_r` and `ipaddr_aton` are macros in single-stack builds.
+
+    #[cfg(all(feature = "ipv4", feature = "ipv6"))]
+    fn ntoa(addr: &ip_addr_t) -> String {
+        let mut buf = [0 as std::os::raw::c_char; 48];
+        unsafe {
+            let s = ipaddr_ntoa_r(addr, buf.as_mut_ptr(), buf.len() as _);
+            assert!(!s.is_null());
+            std::ffi::CStr::from_ptr(s).to_str().unwrap().to_owned()
+        }
+    }
+
+    #[cfg(all(feature = "ipv4", feature = "ipv6"))]
+    fn aton(s: &str) -> Option<ip_addr_t> {
+        let s = std::ffi::CString::new(s).unwrap();
+        let mut addr = ip_any_type();
+        (unsafe { ipaddr_aton(s.as_ptr(), &mut addr) } == 1).then_some(addr)
+    }
+
+    #[cfg(all(feature = "ipv4", feature = "ipv6"))]
+    proptest::proptest! {
+        #[test]
+        fn prop_ntoa_round_trip(ip: IpAddr) {
+            let text = ntoa(&to_ip_addr_t(ip).unwrap());
+            let parsed = text.parse::<IpAddr>().unwrap();
+            proptest::prop_assert_eq!(canonical(parsed), canonical(ip));
+        }
+
+        #[test]
+        fn prop_aton_round_trip(ip: IpAddr) {
+            let ip = canonical(ip);
+            let addr = aton(&ip.to_string()).unwrap();
+            proptest::prop_assert_eq!(IpAddr::try_from(&addr).unwrap(), ip);
+            proptest::prop_assert_eq!(to_ip_addr_t(ip).unwrap().type_, addr.type_);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_socket_addr_round_trip(addr: SocketAddr) {
+            let (ip, port) = match from_socket_addr(&addr) {
+                Ok(ip) => ip,
+                // Of the family the stack is built without.
+                Err(_) => return Ok(()),
+            };
+            let expected = match addr {
+                #[cfg(feature = "ipv6")]
+                SocketAddr::V6(v6) if matches!(IpAddr::try_from(&ip), Ok(IpAddr::V6(_))) => {
+                    let scope_id = if ip6_has_scope(v6.ip()) && v6.scope_id() <= 0xff {
+                        v6.scope_id()
+                    } else {
+                        0
+                    };
+                    SocketAddrV6::new(*v6.ip(), v6.port(), 0, scope_id).into()
+                }
+                addr => SocketAddr::new(canonical(addr.ip()), addr.port()),
+            };
+            proptest::prop_assert_eq!(to_socket_addr(&ip, port), expected);
+        }
+    }
+
+    #[test]
+    fn test_nat64() {
+        let prefix: Ipv6Addr = "64:ff9b::".parse().unwrap();
+        let mapped = map_nat64(Ipv4Addr::new(192, 0, 2, 33), &prefix);
+        assert_eq!(mapped, "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap());
+        let addr = SocketAddr::new(mapped.into(), 443);
+        assert_eq!(
+            unmap_nat64(addr, &prefix),
+            "192.0.2.33:443".parse().unwrap()
+        );
+        let addr: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        assert_eq!(unmap_nat64(addr, &prefix), addr);
+    }
+
+    #[test]
+    fn test_inet_checksum() {
+        // RFC 1071 section 3 example.
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(inet_checksum(&data, 0), !0xddf2);
+        assert_eq!(inet_checksum(&data[..7], 0), !0xdcfb);
+        let sum = ip6_pseudo_sum(&Ipv6Addr::LOCALHOST, &Ipv6Addr::LOCALHOST, 8, 58);
+        assert_eq!(inet_checksum(&[], sum), !(2 + 8 + 58));
+    }
+
+    #[test]
+    fn test_checksum_adjust() {
+        let mut data = [0x45, 0x00, 0x00, 0x1c, 0xc0, 0xa8, 0x2b, 0x02, 0x08, 0x08];
+        let checksum = inet_checksum(&data, 0);
+        data[4..8].copy_from_slice(&[0x64, 0x40, 0x00, 0x01]);
+        let adjusted = checksum_adjust(checksum, &[0xc0, 0xa8, 0x2b, 0x02], &data[4..8]);
+        assert_eq!(adjusted, inet_checksum(&data, 0));
+    }
+
+    #[test]
+    fn test_to_io_error() {
+        let err = io::Error::from(crate::Error::LwIP(err_enum_t_ERR_MEM as err_t));
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+        let inner = err.get_ref().and_then(|e| e.downcast_ref::<crate::Error>());
+        assert!(matches!(inner, Some(crate::Error::LwIP(e)) if *e == err_enum_t_ERR_MEM as err_t));
+        let err = io::Error::from(crate::Error::LwIP(err_enum_t_ERR_RTE as err_t));
+        assert_eq!(err.kind(), io::ErrorKind::Other);
     }
 }